@@ -0,0 +1,82 @@
+use std::sync::Arc;
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use kvs::{FileSystem, KvStore, KvStoreOptions, KvsEngine, StdFs, UringFs};
+use rand::prelude::*;
+use tempfile::TempDir;
+
+const KEYS_PER_THREAD: usize = 100;
+
+fn backends() -> Vec<(&'static str, Arc<dyn FileSystem>)> {
+    vec![
+        ("std", Arc::new(StdFs)),
+        ("uring", Arc::new(UringFs::new(256).unwrap())),
+    ]
+}
+
+fn open(temp_dir: &TempDir, fs: &Arc<dyn FileSystem>) -> KvStore {
+    let options = KvStoreOptions {
+        fs: fs.clone(),
+        max_segment_size: 1 << 20,
+        ..Default::default()
+    };
+    KvStore::open_with_options(temp_dir.path(), options).unwrap()
+}
+
+fn concurrent_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_set");
+    group.sample_size(10);
+    for (name, fs) in backends() {
+        for threads in &[1, 4, 8] {
+            group.bench_with_input(format!("{}_{}", name, threads), threads, |b, &threads| {
+                b.iter_batched(
+                    || {
+                        let temp_dir = TempDir::new().unwrap();
+                        (open(&temp_dir, &fs), temp_dir)
+                    },
+                    |(store, _temp_dir)| {
+                        let handles: Vec<_> = (0..threads)
+                            .map(|t| {
+                                let store = store.clone();
+                                thread::spawn(move || {
+                                    for i in 0..KEYS_PER_THREAD {
+                                        store
+                                            .set(format!("key{}_{}", t, i), "value".to_string())
+                                            .unwrap();
+                                    }
+                                })
+                            })
+                            .collect();
+                        for handle in handles {
+                            handle.join().unwrap();
+                        }
+                    },
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+fn random_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("random_get");
+    for (name, fs) in backends() {
+        group.bench_function(name, |b| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = open(&temp_dir, &fs);
+            for i in 0..1000 {
+                store.set(format!("key{}", i), "value".to_string()).unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store.get(format!("key{}", rng.gen_range(0, 1000))).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_set, random_get);
+criterion_main!(benches);
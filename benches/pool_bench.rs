@@ -12,10 +12,21 @@ use kvs::{KvStore, KvsEngine, SharedQueueThreadPool, SledKvsEngine, ThreadPool};
 use rand::prelude::*;
 use tempfile::TempDir;
 
+/// Both servers at each thread count, the thread-pool one for comparison.
+fn servers() -> Vec<(&'static str, &'static u32)> {
+    let mut servers = Vec::new();
+    for server in &["thread-pool", "async"] {
+        for threads in &[1, 2, 4, 8] {
+            servers.push((*server, threads));
+        }
+    }
+    servers
+}
+
 fn write_queued_kvstore(c: &mut Criterion) {
     let mut group = c.benchmark_group("write_queued_kvstore");
-    for i in &vec![1, 2, 4, 8] {
-        group.bench_with_input(format!("write_queued_kvs_{}", i), i, |b, i| {
+    for (kind, i) in servers() {
+        group.bench_with_input(format!("write_queued_kvs_{}_{}", kind, i), i, |b, i| {
             let (sender, receiver) = mpsc::sync_channel::<()>(0);
             let addr = "127.0.0.1:4004";
             let temp_dir = TempDir::new().unwrap();
@@ -28,6 +39,8 @@ fn write_queued_kvstore(c: &mut Criterion) {
                     addr,
                     "--threads",
                     i.to_string().as_str(),
+                    "--server",
+                    kind,
                 ])
                 .current_dir(&temp_dir)
                 .spawn()
@@ -73,8 +86,8 @@ fn write_queued_kvstore(c: &mut Criterion) {
 
 fn read_queued_kvstore(c: &mut Criterion) {
     let mut group = c.benchmark_group("read_queued_kvstore");
-    for i in &vec![1, 2, 4, 8] {
-        group.bench_with_input(format!("read_queued_kvstore_{}", i), i, |b, i| {
+    for (kind, i) in servers() {
+        group.bench_with_input(format!("read_queued_kvstore_{}_{}", kind, i), i, |b, i| {
             let (sender, receiver) = mpsc::sync_channel::<()>(0);
             let addr = "127.0.0.1:4004";
             let temp_dir = TempDir::new().unwrap();
@@ -87,6 +100,8 @@ fn read_queued_kvstore(c: &mut Criterion) {
                     addr,
                     "--threads",
                     i.to_string().as_str(),
+                    "--server",
+                    kind,
                 ])
                 .current_dir(&temp_dir)
                 .spawn()
@@ -131,8 +146,8 @@ fn read_queued_kvstore(c: &mut Criterion) {
 
 fn write_queued_sled(c: &mut Criterion) {
     let mut group = c.benchmark_group("write_queued_sled");
-    for i in &vec![1, 2, 4, 8] {
-        group.bench_with_input(format!("write_queued_sled_{}", i), i, |b, i| {
+    for (kind, i) in servers() {
+        group.bench_with_input(format!("write_queued_sled_{}_{}", kind, i), i, |b, i| {
             let (sender, receiver) = mpsc::sync_channel::<()>(0);
             let addr = "127.0.0.1:4004";
             let temp_dir = TempDir::new().unwrap();
@@ -145,6 +160,8 @@ fn write_queued_sled(c: &mut Criterion) {
                     addr,
                     "--threads",
                     i.to_string().as_str(),
+                    "--server",
+                    kind,
                 ])
                 .current_dir(&temp_dir)
                 .spawn()
@@ -190,8 +207,8 @@ fn write_queued_sled(c: &mut Criterion) {
 
 fn read_queued_sled(c: &mut Criterion) {
     let mut group = c.benchmark_group("read_queued_sled");
-    for i in &vec![1, 2, 4, 8] {
-        group.bench_with_input(format!("read_queued_sled_{}", i), i, |b, i| {
+    for (kind, i) in servers() {
+        group.bench_with_input(format!("read_queued_sled_{}_{}", kind, i), i, |b, i| {
             let (sender, receiver) = mpsc::sync_channel::<()>(0);
             let addr = "127.0.0.1:4004";
             let temp_dir = TempDir::new().unwrap();
@@ -204,6 +221,8 @@ fn read_queued_sled(c: &mut Criterion) {
                     addr,
                     "--threads",
                     i.to_string().as_str(),
+                    "--server",
+                    kind,
                 ])
                 .current_dir(&temp_dir)
                 .spawn()
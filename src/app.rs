@@ -1,13 +1,13 @@
 use std::sync::Arc;
 
-use crate::KvsEngine;
+use crate::AsyncKvsEngine;
 use crate::NodeId;
 use crate::Raft;
 use crate::Store;
 
 // Representation of an application state. This struct can be shared around to share
 // instances of raft, store and more.
-pub struct App<K: KvsEngine + Sync> {
+pub struct App<K: AsyncKvsEngine> {
     pub id: NodeId,
     pub addr: String,
     pub raft: Raft,
@@ -0,0 +1,288 @@
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+use slog::{info, warn, Logger};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+use crate::auth::Session;
+use crate::common::{self, *};
+use crate::layers::SizeLimitLayer;
+use crate::server::answer;
+use crate::shutdown::{Connections, DEFAULT_DRAIN_TIMEOUT};
+use crate::{Acl, KvsEngine, Result, ServerTls, ShutdownHandle};
+
+/// Serves the `KvsServer` protocol from a tokio runtime.
+///
+/// Connections are tasks rather than threads, so idle ones cost next to
+/// nothing. Engine calls run on the runtime's blocking pool, bounded by
+/// `Builder::max_blocking_threads`.
+pub struct AsyncKvsServer<E: KvsEngine> {
+    addr: SocketAddr,
+    engine: E,
+    logger: Logger,
+    limits: SizeLimitLayer,
+    tls: Option<ServerTls>,
+    acl: Option<Acl>,
+    shutdown: ShutdownHandle,
+    drain_timeout: Duration,
+}
+
+impl<E: KvsEngine> AsyncKvsServer<E> {
+    pub fn new(addr: SocketAddr, engine: E, logger: Logger) -> Self {
+        AsyncKvsServer {
+            addr,
+            engine,
+            logger,
+            limits: SizeLimitLayer::default(),
+            tls: None,
+            acl: None,
+            shutdown: ShutdownHandle::new(None),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+        }
+    }
+
+    /// Rejects requests with keys or values over these sizes before reading
+    /// them off the socket, like `KvsServer::with_limits`.
+    pub fn with_limits(mut self, limits: SizeLimitLayer) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Speaks TLS on every connection, like `KvsServer::with_tls`.
+    pub fn with_tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Only runs requests the ACL grants the client, like
+    /// `KvsServer::with_acl`.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// How long shutting down waits for requests in flight, like
+    /// `KvsServer::with_drain_timeout`.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// A handle that makes `run` return once the server has drained.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Binds the address and serves connections until shut down through a
+    /// `ShutdownHandle`, or until accepting fails.
+    ///
+    /// Shutting down drains connections as `KvsServer::start` does, except
+    /// that connections still busy after the drain timeout are left to
+    /// the runtime to drop.
+    pub async fn run(self) -> Result<()> {
+        let listener = TcpListener::bind(self.addr).await?;
+        let acceptor = self.tls.map(|tls| TlsAcceptor::from(tls.config));
+        let connections = Connections::new();
+        info!(self.logger, "Started listening on {}", self.addr);
+
+        loop {
+            let (stream, peer) = tokio::select! {
+                biased;
+                _ = self.shutdown.wait() => break,
+                accepted = listener.accept() => accepted?,
+            };
+            let engine = self.engine.clone();
+            let limits = self.limits;
+            let logger = self.logger.clone();
+            let acceptor = acceptor.clone();
+            let session = Session::new(self.acl.clone());
+            let shutdown = self.shutdown.clone();
+            let guard = connections.open(None);
+            tokio::spawn(async move {
+                let served = handle_client(engine, stream, limits, acceptor, session, shutdown);
+                if let Err(e) = served.await {
+                    warn!(logger, "Error serving connection"; "peer" => %peer, "error" => %e);
+                }
+                drop(guard);
+            });
+        }
+        drop(listener);
+
+        info!(self.logger, "Shutting down"; "connections" => connections.len());
+        // waiting on a plain thread needs neither the runtime's timer nor
+        // one of its blocking threads, which the draining connections use
+        let (sender, drained) = tokio::sync::oneshot::channel();
+        let timeout = self.drain_timeout;
+        let waiting = connections.clone();
+        thread::spawn(move || sender.send(waiting.wait(timeout)));
+        if !drained.await? {
+            warn!(self.logger, "Leaving connections that didn't drain in time";
+                  "connections" => connections.len());
+        }
+        let engine = self.engine.clone();
+        tokio::task::spawn_blocking(move || engine.flush()).await??;
+        info!(self.logger, "Shut down");
+        Ok(())
+    }
+}
+
+async fn handle_client<E: KvsEngine>(
+    engine: E,
+    stream: TcpStream,
+    limits: SizeLimitLayer,
+    acceptor: Option<TlsAcceptor>,
+    session: Session,
+    shutdown: ShutdownHandle,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    match acceptor {
+        Some(acceptor) => {
+            let stream = acceptor.accept(stream).await?;
+            serve_connection(engine, stream, limits, session, shutdown).await
+        }
+        None => serve_connection(engine, stream, limits, session, shutdown).await,
+    }
+}
+
+/// Answers requests in the order they arrive until the client hangs up, as
+/// `KvsServer` does, or until the server shuts down while the connection
+/// has nothing left to answer.
+async fn serve_connection<E, S>(
+    engine: E,
+    stream: S,
+    limits: SizeLimitLayer,
+    mut session: Session,
+    shutdown: ShutdownHandle,
+) -> Result<()>
+where
+    E: KvsEngine,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    let accepted = tokio::select! {
+        accepted = handshake(&mut reader, &mut writer) => accepted?,
+        _ = shutdown.wait() => false,
+    };
+    if !accepted {
+        return Ok(());
+    }
+
+    loop {
+        // pipelined requests already read get answered
+        let idle = reader.buffer().is_empty();
+        let len = tokio::select! {
+            len = read_frame_len(&mut reader) => len?,
+            _ = shutdown.wait(), if idle => None,
+        };
+        let len = match len {
+            Some(len) => len,
+            None => break,
+        };
+        let mut frame = (&mut reader).take(u64::from(len));
+        let id = frame.read_u64_le().await?;
+        let request = read_request(&mut frame, limits).await?;
+
+        // checking a password is as slow as an engine call
+        let engine = engine.clone();
+        let (body, returned) = tokio::task::spawn_blocking(move || {
+            let body = answer(&engine, &mut session, request);
+            (body, session)
+        })
+        .await?;
+        session = returned;
+        write_frame(&mut writer, &Envelope { id, body }).await?;
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers the client's `Hello`, returning whether they settled on a
+/// version.
+async fn handshake<R, W>(reader: &mut R, writer: &mut W) -> Result<bool>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let ours = Hello::current();
+    let welcome = match read_frame_len(reader).await? {
+        None => return Ok(false),
+        Some(len) if len <= MAX_HELLO_LEN => {
+            let mut buf = vec![0; len as usize];
+            reader.read_exact(&mut buf).await?;
+            match bincode::deserialize(&buf) {
+                Ok(hello) => ours.negotiate(&hello),
+                Err(_) => ours.reject(),
+            }
+        }
+        Some(_) => ours.reject(),
+    };
+
+    write_frame(writer, &welcome).await?;
+    writer.flush().await?;
+    Ok(matches!(welcome, Welcome::Accepted { .. }))
+}
+
+async fn read_frame_len<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<u32>> {
+    let mut buf = [0; 4];
+    match reader.read(&mut buf[..1]).await {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        // a TLS peer that hangs up without a close_notify
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    reader.read_exact(&mut buf[1..]).await?;
+    Ok(Some(u32::from_le_bytes(buf)))
+}
+
+async fn write_frame<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let mut buf = Vec::new();
+    common::write_frame(&mut buf, message)?;
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Reads the request in the rest of a frame with `common::read_request`.
+///
+/// Only as much of the frame as a request within the limits can take is
+/// buffered, and the rest is skipped. Past that point there can only be
+/// the bytes of a field over its limit, which the decoder skips without
+/// looking at, so zeros stand in for them.
+async fn read_request<R: AsyncRead + Unpin>(
+    frame: &mut R,
+    limits: SizeLimitLayer,
+) -> io::Result<Result<Request>> {
+    let max_key_size = limits.max_key_size();
+    let max_value_size = limits.max_value_size();
+    // tags and lengths, a password request's credentials, and a key and a
+    // value no longer than `common::read_request` reads
+    let bound = 64
+        + 3 * MAX_CREDENTIAL_LEN as u64
+        + max_key_size.unwrap_or(MAX_FIELD_LEN) as u64
+        + max_value_size.unwrap_or(MAX_FIELD_LEN) as u64;
+    let mut prefix = Vec::new();
+    (&mut *frame).take(bound).read_to_end(&mut prefix).await?;
+    let skipped = tokio::io::copy(frame, &mut tokio::io::sink()).await?;
+
+    let mut buffered = Read::chain(io::Cursor::new(prefix), io::repeat(0).take(skipped));
+    Ok(common::read_request(
+        &mut buffered,
+        max_key_size,
+        max_value_size,
+    ))
+}
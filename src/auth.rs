@@ -0,0 +1,299 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+
+use crate::common::{Credentials, Request, Response};
+use crate::{KvsError, Result};
+
+/// Who may use a `KvsServer` and which keys they may touch, read from a
+/// TOML file like:
+///
+/// ```toml
+/// # what clients get before they authenticate, nothing if left out
+/// [anonymous]
+/// read = ["public/"]
+///
+/// [[tokens]]
+/// name = "backup"
+/// token = "f1d4c0ffee"
+/// read = [""]
+///
+/// [[users]]
+/// name = "alice"
+/// # an argon2 PHC string
+/// password = "$argon2id$v=19$m=19456,t=2,p=1$..."
+/// read = ["alice/", "public/"]
+/// write = ["alice/"]
+/// ```
+///
+/// `read` and `write` list key prefixes, `""` matching every key. Write
+/// doesn't imply read.
+#[derive(Clone)]
+pub struct Acl {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    anonymous: Arc<Grants>,
+    tokens: Vec<(String, Arc<Grants>)>,
+    /// Password hashes and grants by user name.
+    users: HashMap<String, (String, Arc<Grants>)>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclFile {
+    #[serde(default)]
+    anonymous: Grants,
+    #[serde(default)]
+    tokens: Vec<TokenEntry>,
+    #[serde(default)]
+    users: Vec<UserEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenEntry {
+    name: String,
+    token: String,
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserEntry {
+    name: String,
+    password: String,
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
+}
+
+impl Acl {
+    /// Reads an ACL file, checking every password is a hash argon2 can
+    /// verify against.
+    pub fn from_file(path: &Path) -> Result<Acl> {
+        let text = fs::read_to_string(path)?;
+        let file: AclFile = toml::from_str(&text)
+            .map_err(|e| failure::err_msg(format!("Invalid ACL file {}: {}", path.display(), e)))?;
+
+        let mut names = HashSet::new();
+        let mut tokens = Vec::new();
+        for entry in file.tokens {
+            if !names.insert(entry.name.clone()) {
+                return Err(failure::err_msg(format!(
+                    "Token {} is listed twice",
+                    entry.name
+                )));
+            }
+            if entry.token.is_empty() {
+                return Err(failure::err_msg(format!("Token {} is empty", entry.name)));
+            }
+            let grants = Grants {
+                read: entry.read,
+                write: entry.write,
+            };
+            tokens.push((entry.token, Arc::new(grants)));
+        }
+
+        let mut users = HashMap::new();
+        for entry in file.users {
+            PasswordHash::new(&entry.password).map_err(|e| {
+                failure::err_msg(format!("Invalid password hash for {}: {}", entry.name, e))
+            })?;
+            let grants = Grants {
+                read: entry.read,
+                write: entry.write,
+            };
+            if users
+                .insert(entry.name.clone(), (entry.password, Arc::new(grants)))
+                .is_some()
+            {
+                return Err(failure::err_msg(format!(
+                    "User {} is listed twice",
+                    entry.name
+                )));
+            }
+        }
+
+        Ok(Acl {
+            inner: Arc::new(Inner {
+                anonymous: Arc::new(file.anonymous),
+                tokens,
+                users,
+            }),
+        })
+    }
+
+    /// What `credentials` are granted, if they're valid.
+    fn authenticate(&self, credentials: &Credentials) -> Option<Arc<Grants>> {
+        match credentials {
+            Credentials::Token(token) => {
+                // compare against every token, so the time taken says
+                // nothing about which one came close
+                let mut found = None;
+                for (known, grants) in &self.inner.tokens {
+                    if constant_time_eq(known.as_bytes(), token.as_bytes()) {
+                        found = Some(grants.clone());
+                    }
+                }
+                found
+            }
+            Credentials::Password { user, password } => {
+                // an unknown user takes as long to turn away as a wrong
+                // password, so the time taken doesn't say who exists
+                let (hash, grants) = match self.inner.users.get(user) {
+                    Some((hash, grants)) => (hash.as_str(), Some(grants)),
+                    None => (DUMMY_HASH, None),
+                };
+                let hash = PasswordHash::new(hash).ok()?;
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .ok()?;
+                grants.cloned()
+            }
+        }
+    }
+}
+
+/// What passwords of unknown users are checked against, hashed with the
+/// default parameters like the ones in ACL files.
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$a3Zycy1kdW1teS1zYWx0IQ$G35nyPhVTsPsupQfKbIXUaLEuo2Nk4XrY6xyO3XFYyA";
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Key prefixes a client may read and write.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Grants {
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
+}
+
+impl Grants {
+    fn can_read(&self, key: &[u8]) -> bool {
+        self.read.iter().any(|p| key.starts_with(p.as_bytes()))
+    }
+
+    fn can_write(&self, key: &[u8]) -> bool {
+        self.write.iter().any(|p| key.starts_with(p.as_bytes()))
+    }
+
+    /// Where a scan from `start` should begin so it doesn't open on keys
+    /// the client can't read: `start` itself if it's readable, else the
+    /// first readable prefix after it. `None` if nothing after `start` is
+    /// readable.
+    fn scan_start(&self, start: Bound<Vec<u8>>) -> Option<Bound<Vec<u8>>> {
+        let after = match &start {
+            Bound::Included(key) | Bound::Excluded(key) if self.can_read(key) => {
+                return Some(start)
+            }
+            Bound::Included(key) | Bound::Excluded(key) => key.as_slice(),
+            Bound::Unbounded if self.can_read(b"") => return Some(start),
+            Bound::Unbounded => b"",
+        };
+        // a prefix before `after` that `after` doesn't start with only
+        // covers keys before it too
+        self.read
+            .iter()
+            .map(|p| p.as_bytes())
+            .filter(|p| *p > after)
+            .min()
+            .map(|p| Bound::Included(p.to_vec()))
+    }
+}
+
+/// What one connection may do, which an `Auth` request can change.
+pub(crate) struct Session {
+    /// The ACL and what it grants the client so far, `None` when there's
+    /// no ACL and everything is allowed.
+    access: Option<(Acl, Arc<Grants>)>,
+}
+
+impl Session {
+    pub(crate) fn new(acl: Option<Acl>) -> Session {
+        Session {
+            access: acl.map(|acl| {
+                let anonymous = acl.inner.anonymous.clone();
+                (acl, anonymous)
+            }),
+        }
+    }
+
+    /// Answers `request` itself if it's an `Auth`, or if the client may not
+    /// make it. Otherwise returns the request to run, which for a scan may
+    /// start later than asked.
+    ///
+    /// Failed credentials leave the client anonymous.
+    pub(crate) fn admit(&mut self, request: Request) -> std::result::Result<Request, Response> {
+        let (acl, grants) = match &mut self.access {
+            Some(access) => access,
+            // with no ACL, any credentials will do
+            None => {
+                return match request {
+                    Request::Auth(_) => Err(Response::Ok),
+                    request => Ok(request),
+                }
+            }
+        };
+
+        match request {
+            Request::Auth(credentials) => match acl.authenticate(&credentials) {
+                Some(granted) => {
+                    *grants = granted;
+                    Err(Response::Ok)
+                }
+                None => Err(self.unauthenticated()),
+            },
+            Request::Get(get) if grants.can_read(&get.key) => Ok(Request::Get(get)),
+            Request::Set(set) if grants.can_write(&set.key) => Ok(Request::Set(set)),
+            Request::Remove(remove) if grants.can_write(&remove.key) => Ok(Request::Remove(remove)),
+            Request::Scan(mut scan) if !grants.read.is_empty() => {
+                match grants.scan_start(scan.start) {
+                    Some(start) => {
+                        scan.start = start;
+                        Ok(Request::Scan(scan))
+                    }
+                    None => Err(Response::Pairs(Vec::new())),
+                }
+            }
+            _ => Err(Response::Denied(KvsError::PermissionDenied)),
+        }
+    }
+
+    /// Leaves the client anonymous after it failed to authenticate.
+    pub(crate) fn unauthenticated(&mut self) -> Response {
+        if let Some((acl, grants)) = &mut self.access {
+            *grants = acl.inner.anonymous.clone();
+        }
+        Response::Denied(KvsError::Unauthenticated)
+    }
+
+    /// Cuts a scan's pairs short at the first key the client can't read,
+    /// so a scan covers the one readable range it starts in.
+    pub(crate) fn filter(&self, response: Response) -> Response {
+        match (response, &self.access) {
+            (Response::Pairs(pairs), Some((_, grants))) => Response::Pairs(
+                pairs
+                    .into_iter()
+                    .take_while(|(key, _)| grants.can_read(key))
+                    .collect(),
+            ),
+            (response, _) => response,
+        }
+    }
+}
@@ -1,9 +1,18 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use ::clap::{Args, Parser, Subcommand};
+use kvs::common::Credentials;
+use kvs::ClientTls;
+use kvs::EncryptionKey;
 use kvs::KvStore;
+use kvs::KvStoreOptions;
 use kvs::KvsClient;
+use kvs::KvsEngine;
 use kvs::Result;
+use kvs::SledKvsEngine;
 use slog::info;
 use slog::o;
 use slog::Drain;
@@ -21,6 +30,46 @@ enum Commands {
     Get(Get),
     Set(Set),
     Rm(Rm),
+    Export(Export),
+    Import(Import),
+}
+
+/// How to reach a server that speaks TLS.
+#[derive(Args)]
+struct TlsArgs {
+    /// Connects over TLS, trusting servers with a certificate signed by one
+    /// of the PEM CA certificates in this file.
+    #[arg(long = "tls-ca")]
+    tls_ca: Option<PathBuf>,
+    /// Name the server's certificate must carry, the IP of `--addr` if
+    /// omitted.
+    #[arg(long = "tls-server-name", requires = "tls_ca")]
+    tls_server_name: Option<String>,
+    /// PEM certificate chain to show servers that ask for one.
+    #[arg(long = "tls-cert", requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for `--tls-cert`.
+    #[arg(long = "tls-key", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+/// Who to authenticate to the server as.
+#[derive(Args)]
+struct AuthArgs {
+    /// One of the server's static tokens.
+    #[arg(
+        long = "token",
+        env = "KVS_TOKEN",
+        hide_env_values = true,
+        conflicts_with = "user"
+    )]
+    token: Option<String>,
+    /// User to log in as, with `--password`.
+    #[arg(long = "user", requires = "password")]
+    user: Option<String>,
+    /// Password for `--user`.
+    #[arg(long = "password", env = "KVS_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
 }
 
 #[derive(Args)]
@@ -28,6 +77,10 @@ struct Get {
     key: String,
     #[arg(short, long, default_value = "127.0.0.1:4000")]
     addr: SocketAddr,
+    #[command(flatten)]
+    tls: TlsArgs,
+    #[command(flatten)]
+    auth: AuthArgs,
 }
 
 #[derive(Args)]
@@ -36,6 +89,10 @@ struct Set {
     value: String,
     #[arg(short, long, default_value = "127.0.0.1:4000")]
     addr: SocketAddr,
+    #[command(flatten)]
+    tls: TlsArgs,
+    #[command(flatten)]
+    auth: AuthArgs,
 }
 
 #[derive(Args)]
@@ -43,9 +100,58 @@ struct Rm {
     key: String,
     #[arg(short, long, default_value = "127.0.0.1:4000")]
     addr: SocketAddr,
+    #[command(flatten)]
+    tls: TlsArgs,
+    #[command(flatten)]
+    auth: AuthArgs,
+}
+
+/// Where `export` and `import` find the store: a running server, or an
+/// engine directory opened directly while no server is using it.
+#[derive(Args)]
+struct Target {
+    #[arg(short, long, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+    #[arg(short = 'e', long = "engine", requires = "dir")]
+    engine: Option<String>,
+    #[arg(short, long)]
+    dir: Option<PathBuf>,
+    #[arg(short = 'b', long = "batch-size", default_value = "1000")]
+    batch_size: usize,
+    #[command(flatten)]
+    tls: TlsArgs,
+    #[command(flatten)]
+    auth: AuthArgs,
+}
+
+#[derive(Args)]
+struct Export {
+    /// File to write the JSON Lines dump to, stdout if omitted.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    target: Target,
+}
+
+#[derive(Args)]
+struct Import {
+    /// JSON Lines dump to load, stdin if omitted.
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+    #[command(flatten)]
+    target: Target,
+}
+
+/// Prints errors with their `Display`, as typed errors like
+/// `KvsError::KeyNotFound` only name their variant in `Debug`.
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
 }
 
-fn main() -> Result<()> {
+fn run() -> Result<()> {
     let cli = Cli::parse();
 
     let decorator = slog_term::TermDecorator::new().build();
@@ -60,24 +166,147 @@ fn main() -> Result<()> {
 
     match &cli.command {
         Some(Commands::Get(args)) => {
-            let client = KvsClient::new(args.addr, logger);
-            let val = client.get(args.key.clone())?;
-            println!("{}", val.unwrap());
+            let client = client(args.addr, &args.tls, &args.auth, logger)?;
+            match client.get(args.key.clone())? {
+                Some(value) => println!("{}", value),
+                None => println!("Key not found"),
+            }
             Ok(())
         }
         Some(Commands::Set(args)) => {
-            let client = KvsClient::new(args.addr, logger);
+            let client = client(args.addr, &args.tls, &args.auth, logger)?;
             client.set(args.key.clone(), args.value.clone())?;
             Ok(())
         }
         Some(Commands::Rm(args)) => {
-            let client = KvsClient::new(args.addr, logger);
+            let client = client(args.addr, &args.tls, &args.auth, logger)?;
             client.remove(args.key.clone())?;
             Ok(())
         }
+        Some(Commands::Export(args)) => {
+            let exported = match &args.target.engine {
+                None => export(
+                    client(
+                        args.target.addr,
+                        &args.target.tls,
+                        &args.target.auth,
+                        logger.clone(),
+                    )?,
+                    args,
+                )?,
+                Some(engine) => match engine.as_str() {
+                    "kvs" => export(
+                        KvStore::open_read_only_with_options(
+                            target_dir(&args.target),
+                            kvs_options()?,
+                        )?,
+                        args,
+                    )?,
+                    "sled" => export(SledKvsEngine::open(target_dir(&args.target)), args)?,
+                    other => return Err(unsupported(other)),
+                },
+            };
+            info!(logger, "Exported {} keys", exported);
+            Ok(())
+        }
+        Some(Commands::Import(args)) => {
+            let imported = match &args.target.engine {
+                None => import(
+                    client(
+                        args.target.addr,
+                        &args.target.tls,
+                        &args.target.auth,
+                        logger.clone(),
+                    )?,
+                    args,
+                )?,
+                Some(engine) => match engine.as_str() {
+                    "kvs" => import(
+                        KvStore::open_with_options(target_dir(&args.target), kvs_options()?)?,
+                        args,
+                    )?,
+                    "sled" => import(SledKvsEngine::open(target_dir(&args.target)), args)?,
+                    other => return Err(unsupported(other)),
+                },
+            };
+            info!(logger, "Imported {} keys", imported);
+            Ok(())
+        }
         _ => {
             println!("Unknown method");
             std::process::exit(1);
         }
     }
 }
+
+/// A client for the server at `addr`, over TLS and authenticated if the
+/// flags ask for it.
+fn client(
+    addr: SocketAddr,
+    tls: &TlsArgs,
+    auth: &AuthArgs,
+    logger: slog::Logger,
+) -> Result<KvsClient> {
+    let mut client = KvsClient::new(addr, logger);
+    match (&auth.token, &auth.user, &auth.password) {
+        (Some(token), _, _) => client = client.with_credentials(Credentials::Token(token.clone())),
+        (None, Some(user), Some(password)) => {
+            client = client.with_credentials(Credentials::Password {
+                user: user.clone(),
+                password: password.clone(),
+            })
+        }
+        _ => {}
+    }
+    let ca = match &tls.tls_ca {
+        Some(ca) => ca,
+        None => return Ok(client),
+    };
+    let server_name = match &tls.tls_server_name {
+        Some(name) => name.clone(),
+        None => addr.ip().to_string(),
+    };
+    let identity = match (&tls.tls_cert, &tls.tls_key) {
+        (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+        _ => None,
+    };
+    Ok(client.with_tls(ClientTls::from_pem_files(ca, &server_name, identity)?))
+}
+
+fn export<E: KvsEngine>(engine: E, args: &Export) -> Result<u64> {
+    let output: Box<dyn io::Write> = match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    kvs::dump::export(&engine, BufWriter::new(output), args.target.batch_size)
+}
+
+fn import<E: KvsEngine>(engine: E, args: &Import) -> Result<u64> {
+    let input: Box<dyn io::Read> = match &args.input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin()),
+    };
+    kvs::dump::import(&engine, BufReader::new(input), args.target.batch_size)
+}
+
+fn target_dir(target: &Target) -> PathBuf {
+    // clap makes `--engine` require `--dir`
+    target.dir.clone().unwrap()
+}
+
+/// How to open a kvs directory: encrypted with the key in
+/// `KVS_ENCRYPTION_KEY` if set. Exports open it read-only, so they neither
+/// disturb a server writing to it nor create a store where there's none.
+fn kvs_options() -> Result<KvStoreOptions> {
+    Ok(KvStoreOptions {
+        encryption_key: EncryptionKey::from_env()?,
+        ..Default::default()
+    })
+}
+
+fn unsupported(engine: &str) -> failure::Error {
+    match engine {
+        "inmem" => failure::err_msg("The inmem engine keeps nothing on disk"),
+        _ => failure::err_msg(format!("Unknown engine {}", engine)),
+    }
+}
@@ -0,0 +1,154 @@
+extern crate slog;
+extern crate slog_async;
+extern crate slog_term;
+
+use std::path::{Path, PathBuf};
+
+use ::clap::Parser;
+use kvs::{EncryptionKey, KvStore, KvStoreOptions, KvsEngine, Migration, Result, SledKvsEngine};
+
+use slog::{info, o, Drain, Logger};
+
+#[derive(Parser)]
+#[clap(author, version)]
+#[clap(about = "Copies every key of a kvs or sled directory into another engine")]
+struct Cli {
+    #[arg(long = "from")]
+    from: String,
+    #[arg(long = "from-dir")]
+    from_dir: PathBuf,
+    #[arg(long = "to")]
+    to: String,
+    #[arg(long = "to-dir")]
+    to_dir: PathBuf,
+    /// Progress file used to resume an interrupted migration. Defaults to
+    /// `<to-dir>.migrate` next to the destination directory.
+    #[arg(long = "checkpoint")]
+    checkpoint: Option<PathBuf>,
+    #[arg(short = 'b', long = "batch-size", default_value = "1000")]
+    batch_size: usize,
+    /// Environment variable holding the base64 key a kvs source is
+    /// encrypted with. The source is read as plaintext if omitted.
+    #[arg(long = "source-key-env")]
+    source_key_env: Option<String>,
+    /// Environment variable holding the base64 key to encrypt a kvs
+    /// destination with. The destination is written as plaintext if
+    /// omitted.
+    #[arg(long = "dest-key-env")]
+    dest_key_env: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let decorator = slog_term::TermDecorator::new().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+
+    let logger = slog::Logger::root(
+        drain,
+        o!("from" => cli.from.clone(), "to" => cli.to.clone()),
+    );
+
+    if cli.from_dir == cli.to_dir {
+        return Err(failure::err_msg(
+            "Source and destination directories must differ",
+        ));
+    }
+
+    match cli.from.as_str() {
+        "kvs" => {
+            // never write to the store being copied
+            let options = kvs_options(cli.source_key_env.as_deref())?;
+            let source = KvStore::open_read_only_with_options(cli.from_dir.clone(), options)?;
+            with_source(source, &cli, logger)
+        }
+        "sled" => with_source(SledKvsEngine::open(cli.from_dir.clone()), &cli, logger),
+        other => Err(unsupported(other)),
+    }
+}
+
+fn with_source<S: KvsEngine>(source: S, cli: &Cli, logger: Logger) -> Result<()> {
+    match cli.to.as_str() {
+        "kvs" => {
+            let options = kvs_options(cli.dest_key_env.as_deref())?;
+            let dest = KvStore::open_with_options(cli.to_dir.clone(), options)?;
+            migrate(source, dest, cli, logger)
+        }
+        "sled" => migrate(source, SledKvsEngine::open(cli.to_dir.clone()), cli, logger),
+        other => Err(unsupported(other)),
+    }
+}
+
+fn migrate<S: KvsEngine, D: KvsEngine>(
+    source: S,
+    dest: D,
+    cli: &Cli,
+    logger: Logger,
+) -> Result<()> {
+    let checkpoint = match &cli.checkpoint {
+        Some(path) => path.clone(),
+        None => default_checkpoint(&cli.to_dir)?,
+    };
+    let resuming = checkpoint.exists();
+
+    let mut migration = Migration::new(source, dest, checkpoint)?.batch_size(cli.batch_size);
+    if resuming {
+        info!(logger, "Resuming migration"; "copied" => migration.copied());
+    } else {
+        info!(logger, "Starting migration");
+    }
+
+    while migration.step()? {
+        info!(logger, "Copied batch"; "copied" => migration.copied());
+    }
+
+    let summary = migration.finish()?;
+    info!(logger, "Migration verified";
+          "keys" => summary.keys,
+          "checksum" => format!("{:016x}", summary.checksum));
+
+    Ok(())
+}
+
+/// `<to_dir>.migrate` next to the destination, which exists by now. The
+/// directory is resolved first, so `.` and `..` get a name of their own,
+/// and `.migrate` is added to the name rather than replacing an extension.
+fn default_checkpoint(to_dir: &Path) -> Result<PathBuf> {
+    let dir = to_dir.canonicalize()?;
+    let mut name = dir
+        .file_name()
+        .ok_or_else(|| {
+            failure::err_msg(format!(
+                "No place for a checkpoint next to {}, pass --checkpoint",
+                dir.display()
+            ))
+        })?
+        .to_os_string();
+    name.push(".migrate");
+    Ok(dir.with_file_name(name))
+}
+
+/// Options for a kvs directory encrypted with the key in the environment
+/// variable `key_env`, if one is named.
+fn kvs_options(key_env: Option<&str>) -> Result<KvStoreOptions> {
+    let encryption_key = match key_env {
+        Some(name) => Some(EncryptionKey::from_env_var(name)?.ok_or_else(|| {
+            failure::err_msg(format!("Environment variable {} is not set", name))
+        })?),
+        None => None,
+    };
+    Ok(KvStoreOptions {
+        encryption_key,
+        ..Default::default()
+    })
+}
+
+fn unsupported(engine: &str) -> failure::Error {
+    match engine {
+        "inmem" => {
+            failure::err_msg("The inmem engine keeps nothing on disk, so it can't be migrated")
+        }
+        _ => failure::err_msg(format!("Unknown engine {}", engine)),
+    }
+}
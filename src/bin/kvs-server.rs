@@ -5,15 +5,22 @@ extern crate slog_term;
 use std::{
     net::{IpAddr, SocketAddr},
     path::PathBuf,
+    sync::Arc,
+    thread,
+    time::Duration,
 };
 
 use ::clap::{Args, Parser, Subcommand};
+use kvs::layers::{EngineMetrics, MetricsLayer, ReadCacheLayer, SizeLimitLayer, TracingLayer};
 use kvs::{
-    KvsEngine, KvsServer, NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool,
-    ThreadPool,
+    Acl, AsyncKvsServer, EncryptionKey, EngineBuilder, FileSystem, HttpGateway, KvStoreOptions,
+    KvsEngine, KvsServer, MemcachedServer, NaiveThreadPool, RayonThreadPool, RespServer, Result,
+    ServerTls, SharedQueueThreadPool, ShutdownHandle, StdFs, ThreadPool,
 };
 
-use slog::{info, o, Drain, Logger};
+use slog::{error, info, o, warn, Drain, Logger};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
 #[derive(Parser)]
 #[clap(author, version)]
@@ -25,10 +32,83 @@ struct Cli {
     engine: String,
     #[arg(short = 'p', long = "pool", default_value = "naive")]
     pool: String,
+    /// Threads for engine calls, and for connections too with the
+    /// thread-pool server.
     #[arg(short = 't', long = "threads", default_value = "4")]
     threads: u32,
+    /// `async` serves connections as tokio tasks, `thread-pool` gives each
+    /// one a pool thread.
+    #[arg(long = "server", default_value = "async")]
+    server: String,
+    /// What `--addr` speaks: `bincode` for `kvs-client`, or `memcached` for
+    /// memcached clients, which the async server alone serves.
+    #[arg(long = "protocol", default_value = "bincode")]
+    protocol: String,
+    /// Also serves the engine to Redis clients on this address.
+    #[arg(long = "resp-addr")]
+    resp_addr: Option<SocketAddr>,
+    /// Also serves the engine as a REST resource under `/kv` on this
+    /// address.
+    #[arg(long = "http-addr")]
+    http_addr: Option<SocketAddr>,
+    /// Serves the bincode protocol over TLS with the PEM certificate chain
+    /// in this file.
+    #[arg(long = "tls-cert", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for `--tls-cert`.
+    #[arg(long = "tls-key", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Only lets in clients with a certificate signed by one of the PEM CA
+    /// certificates in this file.
+    #[arg(long = "client-ca", requires = "tls_cert")]
+    client_ca: Option<PathBuf>,
+    /// Makes bincode clients authenticate, and limits them to the key
+    /// prefixes this TOML file grants them.
+    #[arg(long = "acl")]
+    acl: Option<PathBuf>,
+    /// Seconds a bincode server waits on SIGTERM or Ctrl-C for requests in
+    /// flight before closing their connections and flushing the engine.
+    #[arg(long = "drain-timeout", default_value = "10")]
+    drain_timeout: u64,
     #[arg(short, long, default_value = ".")]
     dir: String,
+    /// Encrypts the kvs engine's records with the key in this file, which
+    /// holds 32 raw bytes or their base64. `KVS_ENCRYPTION_KEY` is read when
+    /// it's not given.
+    #[arg(long = "encryption-key-file")]
+    encryption_key_file: Option<PathBuf>,
+    /// Previous key still sealing some records. The next compaction rewrites
+    /// them with the current key. Can be repeated.
+    #[arg(long = "old-encryption-key-file")]
+    old_encryption_key_files: Vec<PathBuf>,
+    /// LZ4 compresses kvs engine records of at least this many bytes.
+    #[arg(long = "compress-min-size")]
+    compress_min_size: Option<usize>,
+    /// Number of shards a new kvs engine store spreads its keys over.
+    #[arg(long = "shards", default_value = "4")]
+    shards: usize,
+    /// Does kvs engine segment I/O through io_uring, falling back to std::fs
+    /// where the kernel doesn't support it.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[arg(long = "io-uring")]
+    io_uring: bool,
+    /// Logs every engine call at debug level.
+    #[arg(long = "trace")]
+    trace: bool,
+    /// Logs engine call counts and timings every this many seconds.
+    #[arg(long = "metrics-interval")]
+    metrics_interval: Option<u64>,
+    /// Caches the results of this many recent gets.
+    #[arg(long = "cache-entries")]
+    cache_entries: Option<usize>,
+    /// Refuses keys longer than this many bytes, both off the wire and in
+    /// the engine.
+    #[arg(long = "max-key-size")]
+    max_key_size: Option<usize>,
+    /// Refuses values longer than this many bytes, both off the wire and in
+    /// the engine.
+    #[arg(long = "max-value-size")]
+    max_value_size: Option<usize>,
 }
 
 fn main() -> Result<()> {
@@ -50,22 +130,39 @@ fn main() -> Result<()> {
 
     info!(logger, "Starting server");
 
-    let num_threads = cli.threads;
-
-    let pool = SharedQueueThreadPool::new(num_threads)?;
-
     match cli.engine.as_str() {
         "kvs" => {
-            let engine = kvs::KvStore::open(PathBuf::from(&cli.dir))?;
-            run_with(engine, pool, cli.addr, cli.dir, logger);
+            let options = KvStoreOptions {
+                encryption_key: match &cli.encryption_key_file {
+                    Some(path) => Some(EncryptionKey::from_file(path)?),
+                    None => EncryptionKey::from_env()?,
+                },
+                old_encryption_keys: cli
+                    .old_encryption_key_files
+                    .iter()
+                    .map(|path| EncryptionKey::from_file(path))
+                    .collect::<Result<_>>()?,
+                compress_min_size: cli.compress_min_size,
+                logger: logger.clone(),
+                shards: cli.shards,
+                fs: segment_fs(&cli, &logger),
+                ..Default::default()
+            };
+            let engine = kvs::KvStore::open_with_options(PathBuf::from(&cli.dir), options)?;
+
+            let stats = engine.stats();
+            info!(logger, "Opened store";
+                  "keys" => stats.keys,
+                  "compression_ratio" => format!("{:.2}", stats.compression_ratio()));
+            serve(engine, &cli, logger)?;
         }
         "sled" => {
             let engine = kvs::SledKvsEngine::open(PathBuf::from(&cli.dir));
-            run_with(engine, pool, cli.addr, cli.dir, logger);
+            serve(engine, &cli, logger)?;
         }
         "inmem" => {
             let engine = kvs::InMemEngine::open(PathBuf::from(&cli.dir));
-            run_with(engine, pool, cli.addr, cli.dir, logger);
+            serve(engine, &cli, logger)?;
         }
         _ => panic!("Unknown engine"),
     };
@@ -73,13 +170,174 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn run_with<K: KvsEngine, P: ThreadPool>(
-    engine: K,
-    pool: P,
-    addr: SocketAddr,
-    dir: String,
-    logger: Logger,
-) {
-    let srv = KvsServer::new(addr, engine, dir, logger, pool);
-    srv.start();
+/// Stacks the layers the flags ask for onto `engine` and serves it.
+fn serve<K: KvsEngine>(engine: K, cli: &Cli, logger: Logger) -> Result<()> {
+    let metrics = cli.metrics_interval.map(|secs| {
+        let metrics = EngineMetrics::new();
+        report_metrics(metrics.clone(), Duration::from_secs(secs), logger.clone());
+        metrics
+    });
+    let limits = SizeLimitLayer::new(cli.max_key_size, cli.max_value_size);
+    let tls = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some(ServerTls::from_pem_files(
+            cert,
+            key,
+            cli.client_ca.as_deref(),
+        )?),
+        _ => None,
+    };
+    let acl = cli.acl.as_deref().map(Acl::from_file).transpose()?;
+    if acl.is_some()
+        && (cli.resp_addr.is_some() || cli.http_addr.is_some() || cli.protocol != "bincode")
+    {
+        // the other frontends would let anyone around it
+        return Err(failure::err_msg(
+            "An ACL is only enforced with the bincode protocol and no other listeners",
+        ));
+    }
+
+    let engine = EngineBuilder::new(engine)
+        .layer(cli.cache_entries.map(ReadCacheLayer::new))
+        .layer(limits)
+        .layer(metrics.map(MetricsLayer::new))
+        .layer(if cli.trace {
+            Some(TracingLayer::new(logger.clone()))
+        } else {
+            None
+        })
+        .build();
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_io()
+        .max_blocking_threads(cli.threads as usize)
+        .build()?;
+    if let Some(addr) = cli.resp_addr {
+        let resp = RespServer::new(addr, engine.clone(), logger.clone()).with_limits(limits);
+        let logger = logger.clone();
+        runtime.spawn(async move {
+            if let Err(e) = resp.run().await {
+                error!(logger, "RESP listener failed"; "error" => %e);
+            }
+        });
+    }
+    if let Some(addr) = cli.http_addr {
+        let gateway = HttpGateway::new(addr, engine.clone(), logger.clone()).with_limits(limits);
+        let logger = logger.clone();
+        // actix runs its own runtimes
+        thread::spawn(move || {
+            if let Err(e) = actix_web::rt::System::new().block_on(gateway.run()) {
+                error!(logger, "HTTP listener failed"; "error" => %e);
+            }
+        });
+    }
+
+    match (cli.protocol.as_str(), cli.server.as_str()) {
+        ("bincode", "async") => {
+            let mut srv = AsyncKvsServer::new(cli.addr, engine, logger.clone())
+                .with_limits(limits)
+                .with_drain_timeout(Duration::from_secs(cli.drain_timeout));
+            if let Some(tls) = tls {
+                srv = srv.with_tls(tls);
+            }
+            if let Some(acl) = acl {
+                srv = srv.with_acl(acl);
+            }
+            shutdown_on_signal(&runtime, srv.shutdown_handle(), logger);
+            runtime.block_on(srv.run())
+        }
+        ("bincode", "thread-pool") => {
+            let pool = SharedQueueThreadPool::new(cli.threads)?;
+            let mut srv = KvsServer::new(cli.addr, engine, cli.dir.clone(), logger.clone(), pool)
+                .with_limits(limits)
+                .with_drain_timeout(Duration::from_secs(cli.drain_timeout));
+            if let Some(tls) = tls {
+                srv = srv.with_tls(tls);
+            }
+            if let Some(acl) = acl {
+                srv = srv.with_acl(acl);
+            }
+            shutdown_on_signal(&runtime, srv.shutdown_handle(), logger);
+            srv.start()
+        }
+        ("memcached", _) if tls.is_some() => Err(failure::err_msg(
+            "TLS is only spoken with the bincode protocol",
+        )),
+        ("memcached", "async") => {
+            let srv = MemcachedServer::new(cli.addr, engine, logger).with_limits(limits);
+            runtime.block_on(srv.run())
+        }
+        ("bincode", other) | ("memcached", other) => Err(failure::err_msg(format!(
+            "Unknown server {} for protocol {}",
+            other, cli.protocol
+        ))),
+        (other, _) => Err(failure::err_msg(format!("Unknown protocol {}", other))),
+    }
+}
+
+/// Shuts the server down on Ctrl-C, or SIGTERM on Unix. The runtime's
+/// worker threads listen even while the thread-pool server holds the main
+/// thread.
+fn shutdown_on_signal(runtime: &tokio::runtime::Runtime, handle: ShutdownHandle, logger: Logger) {
+    runtime.spawn(async move {
+        #[cfg(unix)]
+        {
+            let mut terminate = match signal(SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(e) => {
+                    error!(logger, "Can't listen for SIGTERM"; "error" => %e);
+                    return;
+                }
+            };
+            tokio::select! {
+                _ = terminate.recv() => info!(logger, "Received SIGTERM"),
+                _ = tokio::signal::ctrl_c() => info!(logger, "Received Ctrl-C"),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                error!(logger, "Can't listen for Ctrl-C"; "error" => %e);
+                return;
+            }
+            info!(logger, "Received Ctrl-C");
+        }
+        handle.shutdown();
+    });
+}
+
+fn report_metrics(metrics: Arc<EngineMetrics>, interval: Duration, logger: Logger) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let snapshot = metrics.snapshot();
+        info!(logger, "Engine metrics";
+              "gets" => snapshot.gets.calls,
+              "get_hits" => snapshot.get_hits,
+              "get_mean_us" => snapshot.gets.mean_time().as_micros() as u64,
+              "sets" => snapshot.sets.calls,
+              "set_mean_us" => snapshot.sets.mean_time().as_micros() as u64,
+              "removes" => snapshot.removes.calls,
+              "scans" => snapshot.scans.calls,
+              "errors" => snapshot.gets.errors + snapshot.sets.errors
+                  + snapshot.removes.errors + snapshot.scans.errors);
+    });
+}
+
+/// Requests the io_uring backend can have in flight.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+const URING_ENTRIES: u32 = 256;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn segment_fs(cli: &Cli, logger: &Logger) -> Arc<dyn FileSystem> {
+    if cli.io_uring {
+        match kvs::UringFs::new(URING_ENTRIES) {
+            Ok(fs) => return Arc::new(fs),
+            Err(e) => warn!(logger, "io_uring unavailable, using std::fs"; "error" => %e),
+        }
+    }
+    Arc::new(StdFs)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+fn segment_fs(_cli: &Cli, _logger: &Logger) -> Arc<dyn FileSystem> {
+    Arc::new(StdFs)
 }
@@ -1,119 +1,312 @@
 extern crate serde;
 extern crate serde_bytes;
 
-extern crate bincode;
-use bincode::{deserialize, serialize};
 use slog::{error, info, Logger};
 
-use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Write};
 use std::net::{SocketAddr, TcpStream};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
 
 use crate::common::*;
-use crate::Result;
+use crate::{ClientTls, KvsEngine, KvsError, Result};
 
-fn send_request<R: Serialize>(addr: SocketAddr, request: R) -> Result<Response> {
-    let mut stream = TcpStream::connect(addr).expect("Failed to connect to server");
+/// Bytes of requests sent before reading their responses, few enough for
+/// the socket buffers to hold while the server is busy writing responses.
+const PIPELINE_WINDOW: usize = 64 * 1024;
 
-    let request_buf = serialize(&request)?;
+/// A plain or TLS stream.
+trait Stream: Read + Write + Send {}
 
-    // println!("Request buf: {:?}", request_buf);
+impl<S: Read + Write + Send> Stream for S {}
 
-    stream.write_all(&request_buf)?;
-    // send EOF
-    stream.write_all(b"\n")?;
+/// A long-lived connection to a `KvsServer`.
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>,
+    /// Frames waiting to be sent.
+    out: Vec<u8>,
+    next_id: u64,
+    /// What the server agreed to in the handshake.
+    features: u64,
+}
+
+impl Connection {
+    fn open(
+        addr: SocketAddr,
+        tls: Option<&ClientTls>,
+        credentials: Option<&Credentials>,
+    ) -> Result<Connection> {
+        let tcp = TcpStream::connect(addr)?;
+        tcp.set_nodelay(true)?;
+        let stream: Box<dyn Stream> = match tls {
+            Some(tls) => {
+                let conn =
+                    rustls::ClientConnection::new(tls.config.clone(), tls.server_name.clone())?;
+                Box::new(rustls::StreamOwned::new(conn, tcp))
+            }
+            None => Box::new(tcp),
+        };
+        let mut stream = BufReader::new(stream);
+        let mut out = Vec::new();
+
+        write_frame(&mut out, &Hello::current())?;
+        send(&mut stream, &mut out)?;
+        let features = match read_frame(&mut stream)? {
+            Some(Welcome::Accepted { version, features })
+                if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) =>
+            {
+                features
+            }
+            Some(Welcome::Accepted { version, .. }) => {
+                return Err(failure::err_msg(format!(
+                    "Server picked protocol version {}, which isn't spoken here",
+                    version
+                )))
+            }
+            Some(Welcome::Rejected(e)) => return Err(e.into()),
+            None => return Err(failure::err_msg("Server closed the connection")),
+        };
+
+        let mut connection = Connection {
+            stream,
+            out,
+            next_id: 0,
+            features,
+        };
+        if let Some(credentials) = credentials {
+            connection.authenticate(credentials.clone())?;
+        }
+        Ok(connection)
+    }
+
+    fn authenticate(&mut self, credentials: Credentials) -> Result<()> {
+        if self.features & features::AUTH == 0 {
+            return Err(failure::err_msg("Server doesn't support authentication"));
+        }
+        match self.exchange(vec![Request::Auth(credentials)])?.remove(0) {
+            Response::Ok => Ok(()),
+            Response::Denied(e) => Err(e.into()),
+            response => Err(unexpected(response)),
+        }
+    }
 
-    let mut response_buf = Vec::new();
-    stream.read_to_end(&mut response_buf)?;
+    /// Sends requests a window at a time, reading the responses to each
+    /// window before sending the next, so neither side ends up blocked
+    /// writing to the other while it isn't reading. Returns the responses in
+    /// request order whatever order they arrive in.
+    ///
+    /// A request larger than the window goes out on its own.
+    fn exchange(&mut self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        if self.features & features::SCAN == 0
+            && requests.iter().any(|r| matches!(r, Request::Scan(_)))
+        {
+            return Err(failure::err_msg("Server doesn't support scan"));
+        }
+        if self.features & features::PIPELINING == 0 && requests.len() > 1 {
+            let mut responses = Vec::with_capacity(requests.len());
+            for request in requests {
+                responses.append(&mut self.exchange(vec![request])?);
+            }
+            return Ok(responses);
+        }
+
+        let mut responses: Vec<Option<Response>> = Vec::new();
+        responses.resize_with(requests.len(), || None);
+        let mut slots = HashMap::new();
+        for (slot, body) in requests.into_iter().enumerate() {
+            let id = self.next_id;
+            self.next_id += 1;
+            let mut frame = Vec::new();
+            write_frame(&mut frame, &Envelope { id, body })?;
+            if !slots.is_empty() && self.out.len() + frame.len() > PIPELINE_WINDOW {
+                self.complete(&mut slots, &mut responses)?;
+            }
+            self.out.extend_from_slice(&frame);
+            slots.insert(id, slot);
+        }
+        self.complete(&mut slots, &mut responses)?;
 
-    let response: Response = deserialize(&response_buf)?;
+        responses
+            .into_iter()
+            .map(|response| response.ok_or_else(|| failure::err_msg("Missing response")))
+            .collect()
+    }
 
-    Ok(response)
+    /// Sends the requests waiting in `out` and reads a response to each of
+    /// them, by request id, into its slot.
+    fn complete(
+        &mut self,
+        slots: &mut HashMap<u64, usize>,
+        responses: &mut [Option<Response>],
+    ) -> Result<()> {
+        send(&mut self.stream, &mut self.out)?;
+        while !slots.is_empty() {
+            let envelope: Envelope<Response> = read_frame(&mut self.stream)?
+                .ok_or_else(|| failure::err_msg("Server closed the connection"))?;
+            let slot = slots.remove(&envelope.id).ok_or_else(|| {
+                failure::err_msg(format!("Response to unknown request {}", envelope.id))
+            })?;
+            responses[slot] = Some(envelope.body);
+        }
+        Ok(())
+    }
 }
 
+/// Talks to a `KvsServer`, so a remote store can be used like any other engine.
+///
+/// The client connects on first use and keeps the connection, which its
+/// clones share, for later calls. A call that fails on the connection drops
+/// it and the next one reconnects.
+#[derive(Clone)]
 pub struct KvsClient {
     addr: SocketAddr,
     logger: Logger,
+    tls: Option<ClientTls>,
+    credentials: Option<Credentials>,
+    connection: Arc<Mutex<Option<Connection>>>,
 }
 
 impl KvsClient {
     pub fn new(addr: SocketAddr, logger: Logger) -> KvsClient {
-        KvsClient { addr, logger }
+        KvsClient {
+            addr,
+            logger,
+            tls: None,
+            credentials: None,
+            connection: Arc::new(Mutex::new(None)),
+        }
     }
 
-    pub fn get(&self, key: String) -> Result<Option<String>> {
-        let get_request = GetRequest {
-            key: Vec::from(key.as_bytes()),
-        };
+    /// Connects over TLS, checking the server's certificate against `tls`.
+    pub fn with_tls(mut self, tls: ClientTls) -> KvsClient {
+        self.tls = Some(tls);
+        self
+    }
 
-        let request = Request::Get(get_request);
-
-        match send_request(self.addr, request) {
-            Ok(response) => match response {
-                Response::Success(data) => {
-                    let val = String::from_utf8(data).unwrap();
-                    // println!("{}", val);
-                    Ok(Some(val))
-                }
-                Response::Error(error) => {
-                    error!(self.logger, "GET Error: {}", error);
-                    Err(failure::err_msg(error))
-                }
-            },
-            Err(e) => {
-                error!(self.logger, "Error: {}", e);
-                Err(e)
-            }
+    /// Authenticates every connection it opens with `credentials`.
+    pub fn with_credentials(mut self, credentials: Credentials) -> KvsClient {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Sends `requests` down the connection without waiting for each
+    /// response in turn, saving a round trip per request, unless the server
+    /// doesn't support pipelining. Large pipelines go out a window at a
+    /// time. Responses come back in request order.
+    pub fn pipeline(&self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            *connection = Some(Connection::open(
+                self.addr,
+                self.tls.as_ref(),
+                self.credentials.as_ref(),
+            )?);
         }
+        let responses = connection.as_mut().unwrap().exchange(requests);
+        if responses.is_err() {
+            *connection = None;
+        }
+        responses
     }
 
-    pub fn set(&self, key: String, value: String) -> Result<()> {
-        let set_request = SetRequest {
-            key: Vec::from(key.as_bytes()),
-            value: Vec::from(value.as_bytes()),
-        };
-        let request = Request::Set(set_request);
-
-        match send_request(self.addr, request) {
-            Ok(response) => match response {
-                Response::Success(data) => {
-                    // println!("SET Response: {:?}", String::from_utf8(data).unwrap());
-                    Ok(())
-                }
-                Response::Error(error) => {
-                    error!(self.logger, "SET Error: {}", error);
-                    Err(failure::err_msg(error))
-                }
-            },
+    fn send_request(&self, request: Request) -> Result<Response> {
+        let mut responses = self.pipeline(vec![request])?;
+        Ok(responses.remove(0))
+    }
+}
+
+impl KvsClient {
+    /// Sends one request, logging whatever fails.
+    fn call(&self, op: &str, request: Request) -> Result<Response> {
+        match self.send_request(request) {
+            Ok(Response::Error(error)) => {
+                error!(self.logger, "{} Error: {}", op, error);
+                Err(failure::err_msg(error))
+            }
+            Ok(Response::InvalidRequest(error)) => {
+                error!(self.logger, "{} Error: {}", op, error);
+                Err(failure::err_msg(format!("Invalid request: {}", error)))
+            }
+            Ok(Response::Rejected(error)) | Ok(Response::Denied(error)) => {
+                error!(self.logger, "{} Error: {}", op, error);
+                Err(error.into())
+            }
+            Ok(response) => Ok(response),
             Err(e) => {
                 error!(self.logger, "Error: {}", e);
                 Err(e)
             }
         }
     }
+}
 
-    pub fn remove(&self, key: String) -> Result<()> {
-        let rm_request = RemoveRequest {
-            key: Vec::from(key.as_bytes()),
-        };
+fn unexpected(response: Response) -> failure::Error {
+    failure::err_msg(format!("Unexpected response: {:?}", response))
+}
 
-        let request = Request::Remove(rm_request);
-
-        match send_request(self.addr, request) {
-            Ok(response) => match response {
-                Response::Success(data) => {
-                    let val = String::from_utf8(data).unwrap();
-                    // println!("Remove Response: {:?}", val);
-                    Ok(())
-                }
-                Response::Error(error) => {
-                    error!(self.logger, "Remove Error: {}", error);
-                    Err(failure::err_msg(error))
-                }
-            },
-            Err(e) => {
-                error!(self.logger, "Error: {}", e);
-                Err(e)
+impl KvsEngine for KvsClient {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let request = Request::Get(GetRequest {
+            key: key.into_bytes(),
+        });
+
+        match self.call("GET", request)? {
+            Response::Value(value) => Ok(Some(String::from_utf8(value)?)),
+            Response::NotFound => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let request = Request::Set(SetRequest {
+            key: key.into_bytes(),
+            value: value.into_bytes(),
+        });
+
+        match self.call("SET", request)? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let request = Request::Remove(RemoveRequest {
+            key: key.into_bytes(),
+        });
+
+        match self.call("Remove", request)? {
+            Response::Ok => Ok(()),
+            Response::NotFound => Err(KvsError::KeyNotFound.into()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Asks for `MAX_SCAN_LIMIT` pairs at a time, as the server caps each
+    /// answer there.
+    fn scan(&self, mut start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        while pairs.len() < limit {
+            let wanted = (limit - pairs.len()).min(MAX_SCAN_LIMIT as usize);
+            let request = Request::Scan(ScanRequest {
+                start: start.map(String::into_bytes),
+                limit: wanted as u64,
+            });
+            let batch = match self.call("SCAN", request)? {
+                Response::Pairs(batch) => batch,
+                response => return Err(unexpected(response)),
+            };
+
+            // a short answer is the end of what the client may see
+            let done = batch.len() < wanted;
+            for (key, value) in batch {
+                pairs.push((String::from_utf8(key)?, String::from_utf8(value)?));
+            }
+            match pairs.last() {
+                Some((key, _)) if !done => start = Bound::Excluded(key.clone()),
+                _ => break,
             }
         }
+        Ok(pairs)
     }
 }
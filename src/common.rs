@@ -1,13 +1,110 @@
 extern crate serde;
 extern crate serde_bytes;
 
+use std::convert::TryFrom;
+use std::io::{self, BufReader, Read, Write};
+use std::ops::Bound;
+
+use serde::de::DeserializeOwned;
 pub use serde::{Deserialize, Serialize};
 
+use crate::{KvsError, Result};
+
+/// Newest version of the request and response encoding spoken here.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest version still spoken here. Version 1 answered with `Success`
+/// and a message where version 2 has typed responses.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Opens every `Hello`, so a server can tell a client that doesn't do the
+/// handshake from one it merely can't agree with.
+pub const HELLO_MAGIC: [u8; 4] = *b"KVS\0";
+
+/// Bits for the `features` of a `Hello`.
+pub mod features {
+    /// `Request::Scan`.
+    pub const SCAN: u64 = 1;
+    /// More than one request in flight on a connection.
+    pub const PIPELINING: u64 = 1 << 1;
+    /// `Request::Auth`.
+    pub const AUTH: u64 = 1 << 2;
+
+    /// Everything this build supports.
+    pub const ALL: u64 = SCAN | PIPELINING | AUTH;
+}
+
+/// The first frame on a connection, sent by the client. It and `Welcome`
+/// must keep their encoding across protocol versions.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Hello {
+    pub magic: [u8; 4],
+    pub min_version: u32,
+    pub max_version: u32,
+    pub features: u64,
+}
+
+/// Longest `Hello` a server reads, far more than its encoding needs.
+pub const MAX_HELLO_LEN: u32 = 1024;
+
+/// The server's answer to a `Hello`.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum Welcome {
+    /// The version the rest of the connection speaks and the features both
+    /// sides support.
+    Accepted { version: u32, features: u64 },
+    /// No version suits both sides, and the server hangs up.
+    Rejected(KvsError),
+}
+
+impl Hello {
+    /// What this build speaks.
+    pub fn current() -> Hello {
+        Hello {
+            magic: HELLO_MAGIC,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: features::ALL,
+        }
+    }
+
+    /// Picks the newest version both sides speak.
+    pub fn negotiate(&self, peer: &Hello) -> Welcome {
+        let version = self.max_version.min(peer.max_version);
+        if peer.magic != HELLO_MAGIC || version < self.min_version.max(peer.min_version) {
+            return self.reject();
+        }
+        Welcome::Accepted {
+            version,
+            features: self.features & peer.features,
+        }
+    }
+
+    /// Turns a peer away, telling it which versions would have done.
+    pub fn reject(&self) -> Welcome {
+        Welcome::Rejected(KvsError::IncompatibleProtocol {
+            min_version: self.min_version,
+            max_version: self.max_version,
+        })
+    }
+}
+
+/// A request or response with the id that pairs them up, so a client can
+/// have many requests in flight on one connection.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Envelope<T> {
+    pub id: u64,
+    pub body: T,
+}
+
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub enum Request {
     Get(GetRequest),
     Set(SetRequest),
     Remove(RemoveRequest),
+    Scan(ScanRequest),
+    /// Proves who the client is. Later requests on the connection are
+    /// allowed or denied by what the server's ACL grants them.
+    Auth(Credentials),
 }
 
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
@@ -26,8 +123,227 @@ pub struct RemoveRequest {
     pub key: Vec<u8>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ScanRequest {
+    pub start: Bound<Vec<u8>>,
+    /// Servers answer with at most `MAX_SCAN_LIMIT` pairs, whatever this
+    /// asks for.
+    pub limit: u64,
+}
+
+/// Most pairs a server puts in one `Response::Pairs`, so a scan can't have
+/// it collect the whole store into a single frame.
+pub const MAX_SCAN_LIMIT: u64 = 1000;
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum Credentials {
+    /// One of the server's static tokens.
+    Token(String),
+    /// A user and their password.
+    Password { user: String, password: String },
+}
+
+/// Longest token, user name or password a server reads.
+pub const MAX_CREDENTIAL_LEN: usize = 1024;
+
+/// Longest key or value a server reads where it has no limit of its own.
+pub const MAX_FIELD_LEN: usize = 64 * 1024 * 1024;
+
+/// Longest frame `read_frame` reads, room for a get of the longest value or
+/// a scan of ordinary ones.
+pub const MAX_FRAME_LEN: u32 = 4 * MAX_FIELD_LEN as u32;
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub enum Response {
-    Success(Vec<u8>),
+    /// A set or remove went through.
+    Ok,
+    /// The value a get found.
+    Value(Vec<u8>),
+    /// A get or remove found no value for the key.
+    NotFound,
+    /// The pairs a scan found.
+    Pairs(Vec<(Vec<u8>, Vec<u8>)>),
+    /// The request broke one of the server's rules, e.g. a size limit, or
+    /// failed in a way the engine gave a `KvsError` for.
+    Rejected(KvsError),
+    /// The request couldn't be decoded, or its keys or values aren't UTF-8.
+    InvalidRequest(String),
+    /// Any other engine failure.
     Error(String),
+    /// The credentials were wrong, with `KvsError::Unauthenticated`, or
+    /// the client may not touch the key, with `KvsError::PermissionDenied`.
+    /// The request never reached the engine.
+    Denied(KvsError),
+}
+
+/// Writes `message` as one frame: the length of its bincode as a
+/// little-endian `u32`, then the bincode.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let buf = bincode::serialize(message)?;
+    let len = u32::try_from(buf.len())
+        .map_err(|_| failure::err_msg(format!("Frame of {} bytes is too large", buf.len())))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+/// Reads the length of the next frame, or `None` if the stream ends cleanly
+/// before it.
+pub fn read_frame_len<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
+    let mut buf = [0; 4];
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            // a TLS peer that hangs up without a close_notify
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && filled == 0 => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(u32::from_le_bytes(buf)))
+}
+
+/// Writes out the frames gathered in `out` to the stream under `stream`'s
+/// buffer. A TLS stream can't be split into halves the way a `TcpStream`
+/// can, so the one stream is both read through `stream` and written here.
+pub(crate) fn send<S: Write>(stream: &mut BufReader<S>, out: &mut Vec<u8>) -> io::Result<()> {
+    let writer = stream.get_mut();
+    writer.write_all(out)?;
+    writer.flush()?;
+    out.clear();
+    Ok(())
+}
+
+/// Reads a frame written by `write_frame`, or `None` if the stream ends
+/// cleanly before it. Frames over `MAX_FRAME_LEN` fail without being read.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let len = match read_frame_len(reader)? {
+        Some(len) if len > MAX_FRAME_LEN => {
+            return Err(failure::err_msg(format!(
+                "Frame of {} bytes exceeds the limit of {}",
+                len, MAX_FRAME_LEN
+            )))
+        }
+        Some(len) => u64::from(len),
+        None => return Ok(None),
+    };
+    let mut buf = Vec::new();
+    if (reader.take(len).read_to_end(&mut buf)? as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(bincode::deserialize(&buf)?))
+}
+
+/// Reads one `Request` as bincode lays it out, checking every key and value
+/// length against the limits before reading the bytes it covers.
+///
+/// A key or value over its limit, or over `MAX_FIELD_LEN` where there's no
+/// limit, is skipped rather than buffered, so the stream stays in step with
+/// the client, and the request fails with `KvsError::KeyTooLarge` or
+/// `KvsError::ValueTooLarge`.
+pub fn read_request<R: Read>(
+    reader: &mut R,
+    max_key_size: Option<usize>,
+    max_value_size: Option<usize>,
+) -> Result<Request> {
+    let mut decoder = Decoder {
+        reader,
+        rejected: None,
+    };
+    let max_key_size = max_key_size.unwrap_or(MAX_FIELD_LEN);
+    let max_value_size = max_value_size.unwrap_or(MAX_FIELD_LEN);
+    let key_too_large = |size, limit| KvsError::KeyTooLarge { size, limit };
+    let value_too_large = |size, limit| KvsError::ValueTooLarge { size, limit };
+
+    // variant indices in declaration order, as bincode writes them
+    let request = match decoder.u32()? {
+        0 => Request::Get(GetRequest {
+            key: decoder.bytes(max_key_size, key_too_large)?,
+        }),
+        1 => Request::Set(SetRequest {
+            key: decoder.bytes(max_key_size, key_too_large)?,
+            value: decoder.bytes(max_value_size, value_too_large)?,
+        }),
+        2 => Request::Remove(RemoveRequest {
+            key: decoder.bytes(max_key_size, key_too_large)?,
+        }),
+        3 => {
+            let start = match decoder.u32()? {
+                0 => Bound::Unbounded,
+                1 => Bound::Included(decoder.bytes(max_key_size, key_too_large)?),
+                2 => Bound::Excluded(decoder.bytes(max_key_size, key_too_large)?),
+                tag => return Err(failure::err_msg(format!("Invalid bound tag {}", tag))),
+            };
+            Request::Scan(ScanRequest {
+                start,
+                limit: decoder.u64()?,
+            })
+        }
+        4 => Request::Auth(match decoder.u32()? {
+            0 => Credentials::Token(decoder.credential()?),
+            1 => Credentials::Password {
+                user: decoder.credential()?,
+                password: decoder.credential()?,
+            },
+            tag => return Err(failure::err_msg(format!("Invalid credentials tag {}", tag))),
+        }),
+        tag => return Err(failure::err_msg(format!("Invalid request tag {}", tag))),
+    };
+
+    match decoder.rejected {
+        Some(e) => Err(e.into()),
+        None => Ok(request),
+    }
+}
+
+struct Decoder<'a, R> {
+    reader: &'a mut R,
+    /// The first field found over its limit.
+    rejected: Option<KvsError>,
+}
+
+impl<R: Read> Decoder<'_, R> {
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        self.reader.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        self.reader.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// A length-prefixed byte string, or nothing if it's over `limit`.
+    fn bytes(
+        &mut self,
+        limit: usize,
+        too_large: fn(usize, usize) -> KvsError,
+    ) -> io::Result<Vec<u8>> {
+        let len = self.u64()?;
+        let mut field = (&mut *self.reader).take(len);
+        let mut buf = Vec::new();
+        let read = if len > limit as u64 {
+            let size = usize::try_from(len).unwrap_or(usize::MAX);
+            self.rejected.get_or_insert(too_large(size, limit));
+            io::copy(&mut field, &mut io::sink())?
+        } else {
+            field.read_to_end(&mut buf)? as u64
+        };
+        if read < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buf)
+    }
+
+    /// A token, user name or password. One that's too long fails the
+    /// request as `KvsError::Unauthenticated`.
+    fn credential(&mut self) -> io::Result<String> {
+        let bytes = self.bytes(MAX_CREDENTIAL_LEN, |_, _| KvsError::Unauthenticated)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
 }
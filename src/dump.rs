@@ -0,0 +1,112 @@
+//! Logical dumps of an engine as JSON Lines.
+//!
+//! Every line holds one pair, e.g. `{"key":"user:1","value":"alice"}`. A string
+//! with control characters is written as `{"base64":"..."}` instead, so the file
+//! stays readable and safe to edit with line-based tools.
+
+use std::io::{BufRead, Write};
+use std::ops::Bound;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+
+use crate::{KvsEngine, Result};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Field {
+    Text(String),
+    Binary { base64: String },
+}
+
+impl Field {
+    fn encode(s: String) -> Field {
+        if s.chars().any(char::is_control) {
+            Field::Binary {
+                base64: STANDARD.encode(s),
+            }
+        } else {
+            Field::Text(s)
+        }
+    }
+
+    fn decode(self) -> Result<String> {
+        match self {
+            Field::Text(s) => Ok(s),
+            Field::Binary { base64 } => Ok(String::from_utf8(STANDARD.decode(base64)?)?),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    key: Field,
+    value: Field,
+}
+
+/// Writes every pair of `engine` to `writer` in key order, fetching
+/// `batch_size` pairs at a time. Returns the number of pairs written.
+pub fn export<E: KvsEngine, W: Write>(engine: &E, mut writer: W, batch_size: usize) -> Result<u64> {
+    let batch_size = batch_size.max(1);
+    let mut start = Bound::Unbounded;
+    let mut exported = 0;
+
+    loop {
+        let batch = engine.scan(start, batch_size)?;
+        let last = match batch.last() {
+            Some((key, _)) => key.clone(),
+            None => break,
+        };
+        for (key, value) in batch {
+            let record = Record {
+                key: Field::encode(key),
+                value: Field::encode(value),
+            };
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+            exported += 1;
+        }
+        start = Bound::Excluded(last);
+    }
+
+    writer.flush()?;
+    Ok(exported)
+}
+
+/// Loads a dump written by [`export`] into `engine`. Lines are parsed
+/// `batch_size` at a time and a batch is only written once all of it parsed,
+/// so a malformed line never leaves half a batch behind. Returns the number of
+/// pairs imported.
+pub fn import<E: KvsEngine, R: BufRead>(engine: &E, reader: R, batch_size: usize) -> Result<u64> {
+    let batch_size = batch_size.max(1);
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut imported = 0;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let pair = serde_json::from_str::<Record>(&line)
+            .map_err(failure::Error::from)
+            .and_then(|record| Ok((record.key.decode()?, record.value.decode()?)))
+            .map_err(|e| failure::err_msg(format!("Invalid record on line {}: {}", i + 1, e)))?;
+        batch.push(pair);
+
+        if batch.len() == batch_size {
+            imported += apply(engine, &mut batch)?;
+        }
+    }
+    imported += apply(engine, &mut batch)?;
+
+    Ok(imported)
+}
+
+fn apply<E: KvsEngine>(engine: &E, batch: &mut Vec<(String, String)>) -> Result<u64> {
+    let len = batch.len() as u64;
+    for (key, value) in batch.drain(..) {
+        engine.set(key, value)?;
+    }
+    Ok(len)
+}
@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::oneshot;
+
+use crate::{AsyncKvsEngine, KvsEngine, Result, ThreadPool};
+
+/// Runs a blocking [`KvsEngine`] on tokio's blocking thread pool.
+#[derive(Clone)]
+pub struct BlockingEngine<E: KvsEngine + Sync> {
+    engine: E,
+}
+
+impl<E: KvsEngine + Sync> BlockingEngine<E> {
+    pub fn new(engine: E) -> Self {
+        Self { engine }
+    }
+
+    /// Returns the wrapped blocking engine.
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    async fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        tokio::task::spawn_blocking(move || job(engine)).await?
+    }
+}
+
+#[async_trait]
+impl<E: KvsEngine + Sync> AsyncKvsEngine for BlockingEngine<E> {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(move |engine| engine.set(key, value)).await
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.get(key)).await
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        self.run(move |engine| engine.remove(key)).await
+    }
+}
+
+/// Runs a blocking [`KvsEngine`] on a dedicated [`ThreadPool`], so engine calls
+/// never compete with the runtime's own blocking tasks.
+pub struct PooledEngine<E, P>
+where
+    E: KvsEngine + Sync,
+    P: ThreadPool + Send + Sync + 'static,
+{
+    engine: E,
+    pool: Arc<P>,
+}
+
+impl<E, P> Clone for PooledEngine<E, P>
+where
+    E: KvsEngine + Sync,
+    P: ThreadPool + Send + Sync + 'static,
+{
+    fn clone(&self) -> Self {
+        Self {
+            engine: self.engine.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<E, P> PooledEngine<E, P>
+where
+    E: KvsEngine + Sync,
+    P: ThreadPool + Send + Sync + 'static,
+{
+    pub fn new(engine: E, pool: P) -> Self {
+        Self {
+            engine,
+            pool: Arc::new(pool),
+        }
+    }
+
+    /// Returns the wrapped blocking engine.
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    async fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let engine = self.engine.clone();
+
+        self.pool.spawn(move || {
+            // the receiver may already be gone if the caller was cancelled
+            let _ = tx.send(job(engine));
+        });
+
+        // a panicking job drops the sender, which surfaces here as a `RecvError`
+        rx.await?
+    }
+}
+
+#[async_trait]
+impl<E, P> AsyncKvsEngine for PooledEngine<E, P>
+where
+    E: KvsEngine + Sync,
+    P: ThreadPool + Send + Sync + 'static,
+{
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(move |engine| engine.set(key, value)).await
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.get(key)).await
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        self.run(move |engine| engine.remove(key)).await
+    }
+}
@@ -0,0 +1,151 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::Result;
+
+const KEY_LEN: usize = 32;
+/// Environment variable holding a base64 encoded key.
+const KEY_ENV_VAR: &str = "KVS_ENCRYPTION_KEY";
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+
+/// A 256-bit key encrypting `KvStore` records with ChaCha20-Poly1305.
+///
+/// Every sealed record starts with the id of the key that sealed it, so a
+/// store opened with the wrong key fails with a clear error instead of
+/// mistaking ciphertext for corruption.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: ChaCha20Poly1305,
+    id: u64,
+}
+
+impl EncryptionKey {
+    pub fn new(key: [u8; KEY_LEN]) -> EncryptionKey {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+
+        // the tag of an empty message under a fixed nonce identifies the key
+        // without revealing anything about it
+        let check = cipher
+            .encrypt(&Nonce::default(), &[][..])
+            .expect("sealing an empty message can't fail");
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&check[..KEY_ID_LEN]);
+
+        EncryptionKey {
+            cipher,
+            id: u64::from_be_bytes(id),
+        }
+    }
+
+    /// Parses a base64 encoded key.
+    pub fn from_base64(encoded: &str) -> Result<EncryptionKey> {
+        let bytes = STANDARD.decode(encoded.trim())?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Reads the key in `KVS_ENCRYPTION_KEY`, if it is set.
+    pub fn from_env() -> Result<Option<EncryptionKey>> {
+        Self::from_env_var(KEY_ENV_VAR)
+    }
+
+    /// Reads the base64 key in the environment variable `name`, if it is set.
+    pub fn from_env_var(name: &str) -> Result<Option<EncryptionKey>> {
+        match env::var(name) {
+            Ok(encoded) => Ok(Some(Self::from_base64(&encoded)?)),
+            Err(env::VarError::NotPresent) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reads a key file holding either the raw 32 bytes or their base64.
+    pub fn from_file(path: &Path) -> Result<EncryptionKey> {
+        let bytes = fs::read(path)?;
+        if bytes.len() == KEY_LEN {
+            return Self::from_bytes(&bytes);
+        }
+
+        let text = String::from_utf8(bytes).map_err(|_| {
+            failure::err_msg(format!(
+                "Key file {} must hold {} raw bytes or their base64",
+                path.display(),
+                KEY_LEN
+            ))
+        })?;
+        Self::from_base64(&text)
+    }
+
+    /// Draws a fresh random key.
+    pub fn generate() -> EncryptionKey {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        Self::new(key.into())
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<EncryptionKey> {
+        if bytes.len() != KEY_LEN {
+            return Err(failure::err_msg(format!(
+                "Encryption key must be {} bytes, got {}",
+                KEY_LEN,
+                bytes.len()
+            )));
+        }
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(bytes);
+        Ok(Self::new(key))
+    }
+
+    /// Identifies the key in error messages and sealed records.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Encrypts `plaintext` as `key id | nonce | ciphertext`.
+    pub(crate) fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| failure::err_msg("Encryption failed"))?;
+
+        let mut sealed = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&self.id.to_be_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts what [`EncryptionKey::seal`] produced. Returns `None` if the
+    /// data was tampered with or isn't sealed at all.
+    pub(crate) fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed_key_id(sealed)? != self.id {
+            return None;
+        }
+        let nonce = Nonce::from_slice(&sealed[KEY_ID_LEN..KEY_ID_LEN + NONCE_LEN]);
+        self.cipher
+            .decrypt(nonce, &sealed[KEY_ID_LEN + NONCE_LEN..])
+            .ok()
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey({:016x})", self.id)
+    }
+}
+
+/// Id of the key that sealed `sealed`.
+pub(crate) fn sealed_key_id(sealed: &[u8]) -> Option<u64> {
+    if sealed.len() < KEY_ID_LEN + NONCE_LEN {
+        return None;
+    }
+    let mut id = [0; KEY_ID_LEN];
+    id.copy_from_slice(&sealed[..KEY_ID_LEN]);
+    Some(u64::from_be_bytes(id))
+}
@@ -0,0 +1,443 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// An open segment file.
+pub trait SegmentFile: Read + Write + Seek + Send + Sync + Debug {
+    /// Makes everything written so far durable.
+    fn sync_all(&self) -> io::Result<()>;
+
+    /// Truncates or extends the file to `size` bytes.
+    fn set_len(&self, size: u64) -> io::Result<()>;
+}
+
+/// The filesystem operations `KvStore` performs on its directory.
+///
+/// [`StdFs`] is the real thing. [`MemFs`] keeps everything in memory and can
+/// inject errors or cut the power, for crash-consistency testing.
+pub trait FileSystem: Send + Sync + Debug {
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// Lists the regular files directly inside `dir`.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Opens a file for appending, creating it if needed.
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn SegmentFile>>;
+
+    /// Opens an existing file for reading.
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn SegmentFile>>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Makes file creations, renames and removals inside `dir` durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+}
+
+impl SegmentFile for File {
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+}
+
+/// [`FileSystem`] backed by `std::fs`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdFs;
+
+impl FileSystem for StdFs {
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn SegmentFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn SegmentFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum FaultKind {
+    Error,
+    PowerCut,
+}
+
+#[derive(Debug, Default)]
+struct Inode {
+    data: Vec<u8>,
+    durable: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct MemFsState {
+    inodes: HashMap<u64, Inode>,
+    next_inode: u64,
+    names: BTreeMap<PathBuf, u64>,
+    durable_names: BTreeMap<PathBuf, u64>,
+    dirs: BTreeSet<PathBuf>,
+    ops: u64,
+    fault: Option<(u64, FaultKind)>,
+    powered_off: bool,
+    /// Bytes the files may take up in total.
+    capacity: Option<u64>,
+}
+
+impl MemFsState {
+    /// Counts one operation and reports whether it is the faulty one.
+    fn tick(&mut self) -> io::Result<Option<FaultKind>> {
+        if self.powered_off {
+            return Err(io::Error::other("power cut"));
+        }
+
+        self.ops += 1;
+        match self.fault {
+            Some((at, kind)) if at == self.ops => {
+                self.fault = None;
+                if let FaultKind::PowerCut = kind {
+                    self.powered_off = true;
+                }
+                Ok(Some(kind))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Counts one operation that has no partial effect.
+    fn op(&mut self) -> io::Result<()> {
+        match self.tick()? {
+            Some(kind) => Err(fault_error(kind)),
+            None => Ok(()),
+        }
+    }
+
+    fn inode(&self, path: &Path) -> io::Result<u64> {
+        self.names
+            .get(path)
+            .copied()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file"))
+    }
+
+    /// Bytes left before the capacity is reached.
+    fn room(&self) -> u64 {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return u64::MAX,
+        };
+        let inodes: BTreeSet<u64> = self.names.values().copied().collect();
+        let used: u64 = inodes
+            .iter()
+            .filter_map(|inode| self.inodes.get(inode))
+            .map(|inode| inode.data.len() as u64)
+            .sum();
+        capacity.saturating_sub(used)
+    }
+
+    fn data(&mut self, inode: u64) -> &mut Vec<u8> {
+        &mut self.inodes.entry(inode).or_default().data
+    }
+}
+
+fn storage_full() -> io::Error {
+    io::Error::new(io::ErrorKind::StorageFull, "no space left on device")
+}
+
+fn fault_error(kind: FaultKind) -> io::Error {
+    match kind {
+        FaultKind::Error => io::Error::other("injected error"),
+        FaultKind::PowerCut => io::Error::other("power cut"),
+    }
+}
+
+/// In-memory [`FileSystem`] with fault injection.
+///
+/// Written data only survives [`MemFs::restart`] once its file has been synced,
+/// and created, renamed or removed names only once their directory has been
+/// synced. Every call counts as one operation, and a fault can be scheduled at
+/// any operation: a faulty write still applies the first half of its buffer.
+#[derive(Clone, Debug, Default)]
+pub struct MemFs {
+    state: Arc<Mutex<MemFsState>>,
+}
+
+impl MemFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of operations performed so far.
+    pub fn ops(&self) -> u64 {
+        self.state.lock().unwrap().ops
+    }
+
+    /// Makes the `n`th operation from now fail with an I/O error.
+    pub fn fail_after(&self, n: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.fault = Some((state.ops + n, FaultKind::Error));
+    }
+
+    /// Cuts the power at the `n`th operation from now: it and every later
+    /// operation fail until [`MemFs::restart`].
+    pub fn power_cut_after(&self, n: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.fault = Some((state.ops + n, FaultKind::PowerCut));
+    }
+
+    /// Limits the total size of the files to `bytes`, past which writes fail
+    /// with `ErrorKind::StorageFull`. `None` lifts the limit.
+    pub fn set_capacity(&self, bytes: Option<u64>) {
+        self.state.lock().unwrap().capacity = bytes;
+    }
+
+    /// Boots the filesystem again, dropping every unsynced write and
+    /// directory entry, and clears any pending fault.
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.names = state.durable_names.clone();
+        for inode in state.inodes.values_mut() {
+            inode.data = inode.durable.clone();
+        }
+        state.fault = None;
+        state.powered_off = false;
+    }
+
+    fn open(&self, path: &Path, create: bool, append: bool) -> io::Result<Box<dyn SegmentFile>> {
+        let mut state = self.state.lock().unwrap();
+        state.op()?;
+
+        let inode = match state.inode(path) {
+            Ok(inode) => inode,
+            Err(e) if !create => return Err(e),
+            Err(_) => {
+                let parent = path.parent().unwrap_or_else(|| Path::new(""));
+                if !state.dirs.contains(parent) {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, "no such directory"));
+                }
+                state.next_inode += 1;
+                let inode = state.next_inode;
+                state.inodes.insert(inode, Inode::default());
+                state.names.insert(path.to_path_buf(), inode);
+                inode
+            }
+        };
+
+        Ok(Box::new(MemFile {
+            state: self.state.clone(),
+            inode,
+            pos: 0,
+            append,
+        }))
+    }
+}
+
+impl FileSystem for MemFs {
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.op()?;
+        for ancestor in dir.ancestors() {
+            state.dirs.insert(ancestor.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut state = self.state.lock().unwrap();
+        state.op()?;
+        if !state.dirs.contains(dir) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such directory"));
+        }
+        Ok(state
+            .names
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn SegmentFile>> {
+        self.open(path, true, true)
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn SegmentFile>> {
+        self.open(path, false, false)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.op()?;
+        let inode = state.inode(from)?;
+        state.names.remove(from);
+        state.names.insert(to.to_path_buf(), inode);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.op()?;
+        state.inode(path)?;
+        state.names.remove(path);
+        Ok(())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.op()?;
+
+        let in_dir = |path: &PathBuf| path.parent() == Some(dir);
+        state.durable_names.retain(|path, _| !in_dir(path));
+        let synced: Vec<_> = state
+            .names
+            .iter()
+            .filter(|(path, _)| in_dir(path))
+            .map(|(path, inode)| (path.clone(), *inode))
+            .collect();
+        state.durable_names.extend(synced);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct MemFile {
+    state: Arc<Mutex<MemFsState>>,
+    inode: u64,
+    pos: u64,
+    append: bool,
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.op()?;
+
+        let data = state.data(self.inode);
+        let start = (self.pos as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.append {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file opened read-only",
+            ));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let fault = state.tick()?;
+
+        // a faulty write is torn: only part of the buffer reaches the file
+        let len = match fault {
+            Some(_) => buf.len() / 2,
+            None => buf.len(),
+        };
+        let room = state.room();
+        if fault.is_none() && room == 0 && len > 0 {
+            return Err(storage_full());
+        }
+        let len = len.min(room.try_into().unwrap_or(usize::MAX));
+        let data = state.data(self.inode);
+        data.extend_from_slice(&buf[..len]);
+        self.pos = data.len() as u64;
+
+        match fault {
+            Some(kind) => Err(fault_error(kind)),
+            None => Ok(len),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let len = state.data(self.inode).len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before start of file",
+            ));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl SegmentFile for MemFile {
+    fn sync_all(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.op()?;
+
+        let inode = state.inodes.entry(self.inode).or_default();
+        inode.durable = inode.data.clone();
+        Ok(())
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.op()?;
+
+        let len = state.data(self.inode).len() as u64;
+        if size > len && size - len > state.room() {
+            return Err(storage_full());
+        }
+        state.data(self.inode).resize(size as usize, 0);
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
+use std::ops::Bound;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use crate::KvsEngine;
 use crate::Result;
+use crate::{KvsEngine, KvsError};
 use crossbeam_skiplist::SkipMap;
 
 #[derive(Clone)]
@@ -34,7 +35,16 @@ impl KvsEngine for InMemEngine {
     fn remove(&self, key: String) -> Result<()> {
         match self.store.remove(&key) {
             Some(_) => Ok(()),
-            None => Err(failure::err_msg("Key not found")),
+            None => Err(KvsError::KeyNotFound.into()),
         }
     }
+
+    fn scan(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .store
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect())
+    }
 }
@@ -1,396 +1,1305 @@
 extern crate failure;
 extern crate serde_json;
 
-use crate::{KvsEngine, Result};
+use crate::engines::crypto::{sealed_key_id, EncryptionKey};
+use crate::engines::fs::{FileSystem, SegmentFile, StdFs};
+use crate::{KvsEngine, KvsError, Result};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::fs::{self, OpenOptions};
+use slog::{debug, info, o, Discard, Logger};
+use std::collections::{BTreeMap, HashMap};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::{collections::HashMap, fs::File, path::PathBuf};
+use std::time::{Duration, Instant};
 
 const INITIAL_MAX_SEGMENT_SIZE: u64 = 1024;
 const NUM_SEGMENTS_COMPACTION_THREASHOLD: u32 = 4;
+const SEGMENT_EXT: &str = "dat";
+/// Extension of a compacted segment that has not been renamed into place yet.
+const COMPACTION_EXT: &str = "compact";
+
+/// The high byte of a record's size header holds flags, the rest its length.
+const FLAGS_SHIFT: u32 = 56;
+const LEN_MASK: u64 = (1 << FLAGS_SHIFT) - 1;
+/// The payload is sealed with an `EncryptionKey`.
+const FLAG_ENCRYPTED: u8 = 0x01;
+/// The payload, before any encryption, is LZ4 compressed.
+const FLAG_COMPRESSED: u8 = 0x02;
+/// The `KVPair` marks a tombstone with a null value. Records without it
+/// predate that and mark one with the value `"rm"`.
+const FLAG_NULL_TOMBSTONE: u8 = 0x04;
+const KNOWN_FLAGS: u8 = FLAG_ENCRYPTED | FLAG_COMPRESSED | FLAG_NULL_TOMBSTONE;
+/// Value of a tombstone in records without `FLAG_NULL_TOMBSTONE`.
+const LEGACY_TOMBSTONE: &str = "rm";
+
+/// How long a shard that ran out of space refuses writes before letting one
+/// through to find out whether there is room again.
+const FULL_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of shards a new store is split into.
+const DEFAULT_SHARDS: usize = 4;
+/// Holds the shard count of a sharded store, as keys can't move between shards.
+const MANIFEST: &str = "MANIFEST";
+
+/// Stores with at least this many bytes of segments log index rebuild progress.
+const PROGRESS_MIN_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Tuning knobs for [`KvStore::open_with_options`].
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    /// Size in bytes after which a generation 0 segment is rotated. Every
+    /// compaction multiplies it by the compaction threshold.
+    pub max_segment_size: u64,
+    /// Filesystem holding the segment files.
+    pub fs: Arc<dyn FileSystem>,
+    /// Key sealing every record written, compactions included. Without one,
+    /// records are written in the clear.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Keys that only decrypt. Records sealed with them are rewritten with
+    /// `encryption_key` by the next compaction, after which they can go.
+    pub old_encryption_keys: Vec<EncryptionKey>,
+    /// Records whose serialized form is at least this many bytes are LZ4
+    /// compressed, unless that doesn't make them smaller. `None` turns
+    /// compression off for new records; compressed ones stay readable.
+    pub compress_min_size: Option<usize>,
+    /// Receives index rebuild progress when opening a large store.
+    pub logger: Logger,
+    /// Number of shards a new store spreads its keys over, each with its own
+    /// segments, writer and compaction. Existing stores keep their count, and
+    /// ones created before sharding or with a single shard keep their
+    /// segments directly in the store directory.
+    pub shards: usize,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            max_segment_size: INITIAL_MAX_SEGMENT_SIZE,
+            fs: Arc::new(StdFs),
+            encryption_key: None,
+            old_encryption_keys: Vec::new(),
+            compress_min_size: None,
+            logger: Logger::root(Discard, o!()),
+            shards: DEFAULT_SHARDS,
+        }
+    }
+}
+
+/// Figures about the live records of a [`KvStore`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct KvStoreStats {
+    pub keys: u64,
+    /// Serialized size of the live records, before compression.
+    pub raw_bytes: u64,
+    /// Size the live records take up in segments, record headers excluded.
+    pub stored_bytes: u64,
+    pub compressed_records: u64,
+}
 
-#[derive(Clone, Debug, Default)]
+impl KvStoreStats {
+    /// How many times smaller the live records are on disk. Encryption adds
+    /// a fixed overhead per record, which shows up here as well.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            1.0
+        } else {
+            self.raw_bytes as f64 / self.stored_bytes as f64
+        }
+    }
+}
+
+/// A log-structured store that spreads keys over shards by hash, so writes to
+/// different shards don't wait on each other.
+#[derive(Clone, Debug)]
 pub struct KvStore {
-    index: Arc<Mutex<HashMap<String, SizeInfo>>>,
+    shards: Arc<Vec<Shard>>,
+}
+
+/// A bitcask holding the keys that hash to it.
+#[derive(Debug)]
+struct Shard {
+    index: Arc<Mutex<BTreeMap<String, SizeInfo>>>,
     writer: Arc<Mutex<StoreWriter>>,
     reader: StoreReader,
     dir: PathBuf,
+    fs: Arc<dyn FileSystem>,
+    codec: Arc<Codec>,
     rwmutex: Arc<std::sync::RwLock<()>>,
+    /// The writer's segment handle is then opened for reading only, just to
+    /// find where the active segment ends.
+    read_only: bool,
 }
 
-#[derive(Debug, Default)]
+/// Turns serialized `KVPair`s into record payloads and back.
+#[derive(Debug)]
+struct Codec {
+    key: Option<EncryptionKey>,
+    old_keys: Vec<EncryptionKey>,
+    compress_min_size: Option<usize>,
+}
+
+impl Codec {
+    /// Returns the flags and payload of a new record.
+    fn encode(&self, plain: Vec<u8>) -> Result<(u8, Vec<u8>)> {
+        let mut flags = FLAG_NULL_TOMBSTONE;
+        let mut payload = plain;
+
+        if self.compresses(payload.len() as u64) {
+            let compressed = lz4_flex::compress_prepend_size(&payload);
+            if compressed.len() < payload.len() {
+                flags |= FLAG_COMPRESSED;
+                payload = compressed;
+            }
+        }
+        if let Some(key) = &self.key {
+            flags |= FLAG_ENCRYPTED;
+            payload = key.seal(&payload)?;
+        }
+
+        Ok((flags, payload))
+    }
+
+    /// Returns the serialized `KVPair` of a record, or `None` if the payload
+    /// is corrupt. Records this store can't read at all are an error.
+    fn decode(&self, flags: u8, payload: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(failure::err_msg(format!(
+                "Record uses unsupported flags {:#04x}",
+                flags
+            )));
+        }
+
+        let payload = if flags & FLAG_ENCRYPTED != 0 {
+            match self.open(&payload)? {
+                Some(opened) => opened,
+                None => return Ok(None),
+            }
+        } else {
+            payload
+        };
+
+        if flags & FLAG_COMPRESSED != 0 {
+            Ok(lz4_flex::decompress_size_prepended(&payload).ok())
+        } else {
+            Ok(Some(payload))
+        }
+    }
+
+    fn compresses(&self, plain_size: u64) -> bool {
+        match self.compress_min_size {
+            Some(min_size) => plain_size >= min_size as u64,
+            None => false,
+        }
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key_id = match sealed_key_id(sealed) {
+            Some(key_id) => key_id,
+            None => return Ok(None),
+        };
+        let key = self
+            .key
+            .iter()
+            .chain(&self.old_keys)
+            .find(|key| key.id() == key_id);
+        match (key, &self.key) {
+            (Some(key), _) => Ok(key.open(sealed)),
+            (None, None) => Err(failure::err_msg(format!(
+                "Store is encrypted with key {:016x}, but no encryption key was given",
+                key_id
+            ))),
+            (None, Some(current)) => Err(failure::err_msg(format!(
+                "Store is encrypted with key {:016x}, not with the given key {:016x}",
+                key_id,
+                current.id()
+            ))),
+        }
+    }
+
+    /// Whether a record is already encoded the way `encode` would, so
+    /// compaction can copy it as is. Records that didn't shrink when
+    /// compressed get another try at every compaction.
+    fn is_current(&self, info: &SizeInfo, payload: &[u8]) -> bool {
+        let encrypted = info.flags & FLAG_ENCRYPTED != 0;
+        let compressed = info.flags & FLAG_COMPRESSED != 0;
+
+        let key_matches = match &self.key {
+            Some(key) => encrypted && sealed_key_id(payload) == Some(key.id()),
+            None => !encrypted,
+        };
+        key_matches && compressed == self.compresses(info.plain_size)
+    }
+}
+
+/// Opens segments for reading on demand, so cloning a store never touches
+/// files that a concurrent compaction may be removing.
+#[derive(Clone, Debug)]
 struct StoreReader {
     dir: PathBuf,
-    readers: Vec<BufReader<File>>,
+    fs: Arc<dyn FileSystem>,
 }
 
-impl Clone for StoreReader {
-    fn clone(&self) -> Self {
-        StoreReader {
-            dir: self.dir.clone(),
-            readers: get_readers_dir(self.dir.clone()),
-        }
+impl StoreReader {
+    fn segment(&self, gen: u32, segment_id: u32) -> Result<Box<dyn SegmentFile>> {
+        let file = self
+            .fs
+            .open_read(&segment_path(&self.dir, gen, segment_id))?;
+        Ok(file)
     }
 }
 
 #[derive(Debug)]
 struct StoreWriter {
     dir: PathBuf,
-    writer: BufWriter<File>,
+    fs: Arc<dyn FileSystem>,
+    writer: Box<dyn SegmentFile>,
+    /// Length of the active segment.
+    len: u64,
     max_segment_size: u64,
     curr_gen: u32,
-}
-
-impl Default for StoreWriter {
-    fn default() -> Self {
-        StoreWriter {
-            dir: PathBuf::new(),
-            writer: BufWriter::new(File::create("").unwrap()),
-            max_segment_size: INITIAL_MAX_SEGMENT_SIZE,
-            curr_gen: 0,
-        }
-    }
+    curr_segment: u32,
+    /// Offset of a partial record left behind by a failed append whose
+    /// rollback failed too. It is cut off before anything else is written.
+    torn_at: Option<u64>,
+    /// Whether the active segment's directory entry may not be durable yet.
+    dir_dirty: bool,
+    /// When an append or compaction last failed for lack of space, while the
+    /// shard is degraded to read-only.
+    full_since: Option<Instant>,
 }
 
 /// File format:
-/// size | struct{key, value}
+/// flags | size | struct{key, value}
 ///
+/// `flags` is the high byte of the big endian u64 size header. Records written
+/// before flags existed have none, so they read as plain JSON with `"rm"`
+/// tombstones.
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 struct SizeInfo {
     start: u64,
     segment_id: u32,
     size: u64,
+    flags: u8,
+    /// Size of the serialized `KVPair`, before compression and encryption.
+    plain_size: u64,
+}
+
+/// Output of `KvStore::write_compacted`.
+struct Compacted {
+    file: Box<dyn SegmentFile>,
+    len: u64,
+    /// New location of every live key.
+    moved: Vec<(String, SizeInfo)>,
+}
+
+/// Output of `KvStore::scan_segment`.
+struct SegmentScan {
+    /// Records in log order, a `None` entry being a tombstone.
+    records: Vec<(String, Option<SizeInfo>)>,
+    /// How many bytes of the segment hold complete records.
+    valid_len: u64,
+}
+
+/// Bytes of segments read so far by `KvStore::build_index`.
+struct Progress<'a> {
+    total: u64,
+    done: AtomicU64,
+    logger: Option<&'a Logger>,
 }
 
+impl<'a> Progress<'a> {
+    fn new(total: u64, logger: &'a Logger) -> Self {
+        let logger = if total >= PROGRESS_MIN_BYTES {
+            info!(logger, "Rebuilding index"; "bytes" => total);
+            Some(logger)
+        } else {
+            None
+        };
+        Progress {
+            total,
+            done: AtomicU64::new(0),
+            logger,
+        }
+    }
+
+    /// Logs every tenth of the way.
+    fn advance(&self, bytes: u64) {
+        if let Some(logger) = self.logger {
+            let before = self.done.fetch_add(bytes, Ordering::Relaxed);
+            let percent = (before + bytes) * 100 / self.total;
+            if before * 10 / self.total != (before + bytes) * 10 / self.total {
+                info!(logger, "Rebuilding index"; "percent" => percent);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    shards: usize,
+}
+
+/// A `None` value is the tombstone of a removed key.
 #[derive(Debug, Serialize, Deserialize)]
 struct KVPair {
     key: String,
-    value: String,
+    value: Option<String>,
+}
+
+impl KVPair {
+    /// Parses the serialized pair of a record with `flags`, reading the
+    /// tombstones of records without `FLAG_NULL_TOMBSTONE` as `None` too.
+    fn from_slice(flags: u8, plain: &[u8]) -> serde_json::Result<KVPair> {
+        let mut pair: KVPair = serde_json::from_slice(plain)?;
+        if flags & FLAG_NULL_TOMBSTONE == 0
+            && pair.value.as_ref().map(String::as_str) == Some(LEGACY_TOMBSTONE)
+        {
+            pair.value = None;
+        }
+        Ok(pair)
+    }
 }
 
 impl KvStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = path.into();
+        let fs = options.fs.as_ref();
+        fs.create_dir_all(&path)?;
+
+        // left behind by a crash while creating a sharded store
+        let _ = fs.remove_file(&manifest_tmp_path(&path));
+
+        let dirs = match read_manifest(fs, &path)? {
+            Some(shards) => shard_dirs(&path, shards),
+            None if options.shards <= 1 || has_segments(fs, &path)? => vec![path],
+            None => {
+                let dirs = shard_dirs(&path, options.shards);
+                for dir in &dirs {
+                    fs.create_dir_all(dir)?;
+                }
+                write_manifest(fs, &path, options.shards)?;
+                dirs
+            }
+        };
+
+        Self::open_shards(dirs, options, false)
+    }
+
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_read_only_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Opens an existing store without ever writing to it: no file is created,
+    /// truncated or removed, `set`, `remove` and `compact` fail, and compaction
+    /// never runs. Records appended by a writer process since show up after
+    /// [`KvStore::refresh`]. `options.shards` is ignored.
+    pub fn open_read_only_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<KvStore> {
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        let fs = options.fs.as_ref();
+
+        let dirs = match read_manifest(fs, &path)? {
+            Some(shards) => shard_dirs(&path, shards),
+            None if has_segments(fs, &path)? => vec![path],
+            None => {
+                return Err(failure::err_msg(format!(
+                    "No KvStore in {}",
+                    path.display()
+                )))
+            }
+        };
+
+        Self::open_shards(dirs, options, true)
+    }
+
+    fn open_shards(
+        dirs: Vec<PathBuf>,
+        options: KvStoreOptions,
+        read_only: bool,
+    ) -> Result<KvStore> {
+        let codec = Arc::new(Codec {
+            key: options.encryption_key.clone(),
+            old_keys: options.old_encryption_keys.clone(),
+            compress_min_size: options.compress_min_size,
+        });
+        let shards = dirs
+            .into_par_iter()
+            .enumerate()
+            .map(|(i, dir)| {
+                let logger = options.logger.new(o!("shard" => i));
+                Shard::open(dir, &options, codec.clone(), read_only, &logger)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(KvStore {
+            shards: Arc::new(shards),
+        })
+    }
+
+    /// Forces a compaction of every segment into a new generation.
+    pub fn compact(&self) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Picks up what a writer process appended since the store was opened or
+    /// last refreshed, starting over if it has compacted in the meantime.
+    /// Does nothing for a writable store, which sees its own writes.
+    pub fn refresh(&self) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.refresh()?;
+        }
+        Ok(())
+    }
+
+    /// Whether a shard ran out of space and refuses writes for now.
+    pub fn is_storage_full(&self) -> bool {
+        self.shards
+            .iter()
+            .any(|shard| shard.writer.lock().unwrap().full_since.is_some())
+    }
 
-        // println!("path {}", path.clone().display());
+    /// Counts the live records and how much room they take up.
+    pub fn stats(&self) -> KvStoreStats {
+        let mut stats = KvStoreStats::default();
+        for shard in self.shards.iter() {
+            let shard_stats = shard.stats();
+            stats.keys += shard_stats.keys;
+            stats.raw_bytes += shard_stats.raw_bytes;
+            stats.stored_bytes += shard_stats.stored_bytes;
+            stats.compressed_records += shard_stats.compressed_records;
+        }
+        stats
+    }
 
-        let mut filepaths = get_file_paths_sorted(path.clone());
+    fn shard(&self, key: &str) -> &Shard {
+        let i = fnv1a(key.as_bytes()) % self.shards.len() as u64;
+        &self.shards[i as usize]
+    }
+}
 
-        if filepaths.len() == 0 {
-            let fname = format!("0_kv_0.dat");
+impl KvsEngine for KvStore {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
 
-            let file_path = path.join(fname.clone());
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).append(key, Some(value))
+    }
 
-            let _ = new_file(file_path.clone()).unwrap();
+    fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
 
-            filepaths.push(file_path.clone());
+    fn scan(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            pairs.extend(shard.scan(start.clone(), limit)?);
         }
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
 
-        // println!("filepaths {:?}", &filepaths);
+    fn flush(&self) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.flush()?;
+        }
+        Ok(())
+    }
+}
 
-        let readers = get_readers(&filepaths);
-        let writer = get_current_writer(&filepaths);
-        let curr_gen = get_curr_gen(&filepaths);
+impl Shard {
+    fn open(
+        path: PathBuf,
+        options: &KvStoreOptions,
+        codec: Arc<Codec>,
+        read_only: bool,
+        logger: &Logger,
+    ) -> Result<Shard> {
+        let fs = options.fs.clone();
+
+        let (curr_gen, segments) = match recover_segments(fs.as_ref(), &path, read_only)? {
+            Some(live) => live,
+            None if read_only => {
+                return Err(failure::err_msg(format!(
+                    "No segments in {}",
+                    path.display()
+                )))
+            }
+            None => {
+                new_segment(fs.as_ref(), &path, 0, 0)?;
+                (0, vec![0])
+            }
+        };
+        let curr_segment = *segments.last().unwrap();
+        let active_path = segment_path(&path, curr_gen, curr_segment);
+        let writer = if read_only {
+            fs.open_read(&active_path)?
+        } else {
+            fs.open_append(&active_path)?
+        };
 
-        let mut kvstore = KvStore {
-            index: Arc::new(Mutex::new(HashMap::new())),
+        let shard = Shard {
+            index: Arc::new(Mutex::new(BTreeMap::new())),
             writer: Arc::new(Mutex::new(StoreWriter {
                 dir: path.clone(),
-                writer: writer,
-                max_segment_size: INITIAL_MAX_SEGMENT_SIZE
+                fs: fs.clone(),
+                writer,
+                len: 0,
+                max_segment_size: options.max_segment_size
                     * u64::pow(NUM_SEGMENTS_COMPACTION_THREASHOLD as u64, curr_gen),
                 curr_gen,
+                curr_segment,
+                torn_at: None,
+                dir_dirty: false,
+                full_since: None,
             })),
             reader: StoreReader {
                 dir: path.clone(),
-                readers,
+                fs: fs.clone(),
             },
             dir: path.clone(),
+            fs,
+            codec,
             rwmutex: Arc::new(std::sync::RwLock::new(())),
+            read_only,
         };
 
-        // println!("{:?}", filepaths);
+        let len = shard.build_index(curr_gen, &segments, 0, logger)?;
 
-        kvstore.build_index()?;
+        // drop a record torn by a crash in the middle of an append, unless it
+        // may be one the writer process is still busy with
+        let mut writer = shard.writer.lock().unwrap();
+        if !read_only && writer.writer.seek(SeekFrom::End(0))? > len {
+            writer.writer.set_len(len)?;
+            writer.writer.sync_all()?;
+        }
+        writer.len = len;
+        drop(writer);
 
-        return Ok(kvstore);
+        Ok(shard)
     }
 
-    fn build_index(&mut self) -> Result<()> {
-        self.index = Arc::new(Mutex::new(HashMap::new()));
-        let mut readers = self.reader.clone().readers;
-        let mut index = self.index.lock().unwrap();
+    /// Replays `segments`, the first one from offset `from`, into the index
+    /// and returns how many bytes of the last one hold complete records.
+    ///
+    /// Segments are immutable, so they are read and decoded in parallel; only
+    /// applying their records to the index happens in segment order.
+    fn build_index(&self, gen: u32, segments: &[u32], from: u64, logger: &Logger) -> Result<u64> {
+        let started = Instant::now();
+        let mut lens = Vec::with_capacity(segments.len());
+        for &segment_id in segments {
+            let mut file = self.reader.segment(gen, segment_id)?;
+            lens.push(file.seek(SeekFrom::End(0))?);
+        }
+        let progress = Progress::new(lens.iter().sum::<u64>().saturating_sub(from), logger);
+
+        let scans = segments
+            .par_iter()
+            .zip(lens.par_iter())
+            .enumerate()
+            .map(|(i, (&segment_id, &len))| {
+                let is_active = i == segments.len() - 1;
+                let from = if i == 0 { from } else { 0 };
+                self.scan_segment(gen, segment_id, from, len, is_active, &progress)
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-        for (i, reader) in readers.iter_mut().enumerate() {
-            let mut pos = 0;
-            let lastpos = reader.seek(SeekFrom::End(0))?;
-
-            while pos < lastpos {
-                reader.seek(SeekFrom::Start(pos))?;
-
-                let sz = reader.read_u64::<BigEndian>().unwrap();
-
-                reader.seek(SeekFrom::Start(pos + 8))?;
-                let mut buf = vec![0; sz as usize];
-                reader.read_exact(&mut buf)?;
-                let kvpair: KVPair = serde_json::from_slice(&buf)?;
-
-                index.insert(
-                    kvpair.key.clone(),
-                    SizeInfo {
-                        start: pos + 8,
-                        segment_id: i as u32,
-                        size: sz,
-                    },
-                );
-
-                if kvpair.value == "rm".to_string() {
-                    if let Some(_) = index.remove(&kvpair.key) {
-                    } else {
-                        return Err(failure::err_msg("Couldn't delete key"));
+        let mut index = self.index.lock().unwrap();
+        let mut valid_len = 0;
+        for scan in scans {
+            for (key, info) in scan.records {
+                match info {
+                    Some(info) => {
+                        index.insert(key, info);
+                    }
+                    None => {
+                        if index.remove(&key).is_none() {
+                            return Err(failure::err_msg("Couldn't delete key"));
+                        }
                     }
                 }
-
-                pos += 8 + sz;
             }
+            valid_len = scan.valid_len;
         }
 
-        Ok(())
+        debug!(logger, "Rebuilt index";
+               "keys" => index.len(),
+               "segments" => segments.len(),
+               "elapsed_ms" => started.elapsed().as_millis() as u64);
+        Ok(valid_len)
     }
 
-    // Size-tiered compaction strategy
-    fn compact(&self) -> Result<()> {
-        let mut writer = self.writer.lock().unwrap();
-        let mut reader = self.reader.clone();
+    /// Reads the records of a segment between offsets `from` and `len`.
+    fn scan_segment(
+        &self,
+        gen: u32,
+        segment_id: u32,
+        from: u64,
+        len: u64,
+        is_active: bool,
+        progress: &Progress,
+    ) -> Result<SegmentScan> {
+        let mut reader = BufReader::new(self.reader.segment(gen, segment_id)?);
+        reader.seek(SeekFrom::Start(from))?;
+        let mut records = Vec::new();
+        let mut pos = from;
+
+        while pos < len {
+            let (kvpair, info) =
+                match read_record(&mut reader, segment_id, pos, len - pos, &self.codec)? {
+                    Some(record) => record,
+                    // only the active segment can end in an unacknowledged write
+                    None if is_active => break,
+                    None => {
+                        return Err(failure::err_msg(format!(
+                            "Corrupt record in segment {}_kv_{} at offset {}",
+                            gen, segment_id, pos
+                        )))
+                    }
+                };
 
-        let compaction_gen = writer.curr_gen + 1;
-        let filepaths = get_file_paths_sorted(self.dir.clone());
-        let compacted_file_path = self.dir.join(format!("{}_kv_0.dat", compaction_gen));
-        let _ = new_file(compacted_file_path.clone())?;
-        let mut compaction_writer = get_writer(compacted_file_path.clone());
-        let mut index = self.index.lock().unwrap();
+            pos += 8 + info.size;
+            progress.advance(8 + info.size);
+            let info = kvpair.value.map(|_| info);
+            records.push((kvpair.key, info));
+        }
 
-        for (key, info) in index.iter_mut() {
-            let reader = reader.readers.get_mut(info.segment_id as usize).unwrap();
+        Ok(SegmentScan {
+            records,
+            valid_len: pos,
+        })
+    }
 
-            reader.seek(SeekFrom::Start(info.start))?;
+    /// Tails the active segment and any segments rotated in since, or rebuilds
+    /// the index if a compaction started a new generation.
+    fn refresh(&self) -> Result<()> {
+        if !self.read_only {
+            return Ok(());
+        }
+        let _guard = self.rwmutex.write().unwrap();
+        let mut writer = self.writer.lock().unwrap();
 
-            let cmd_reader = reader.take(info.size);
-            info.start = compaction_writer.seek(SeekFrom::End(0))? + 8;
+        let (gen, segments) = recover_segments(self.fs.as_ref(), &self.dir, true)?
+            .ok_or_else(|| failure::err_msg(format!("No segments in {}", self.dir.display())))?;
+        let (tail, from) = if gen == writer.curr_gen {
+            let tail: Vec<u32> = segments
+                .into_iter()
+                .filter(|&segment_id| segment_id >= writer.curr_segment)
+                .collect();
+            (tail, writer.len)
+        } else {
+            self.index.lock().unwrap().clear();
+            (segments, 0)
+        };
 
-            compaction_writer.write_u64::<BigEndian>(info.size)?;
+        let discard = Logger::root(Discard, o!());
+        let len = self.build_index(gen, &tail, from, &discard)?;
 
-            let kvpair: KVPair = serde_json::from_reader(cmd_reader).unwrap();
+        let curr_segment = *tail.last().unwrap();
+        writer.writer = self
+            .fs
+            .open_read(&segment_path(&self.dir, gen, curr_segment))?;
+        writer.curr_gen = gen;
+        writer.curr_segment = curr_segment;
+        writer.len = len;
 
-            write!(compaction_writer, "{}", serde_json::to_string(&kvpair)?)?;
+        Ok(())
+    }
 
-            info.segment_id = 0;
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(failure::err_msg("KvStore is opened read-only"))
+        } else {
+            Ok(())
         }
-        compaction_writer.flush()?;
+    }
 
-        // remove old files
-        for filepath in filepaths {
-            let _ = fs::remove_file(filepath);
+    /// Syncs the active segment and its directory entry. Appends sync
+    /// themselves, so this only catches a rotation nothing was written
+    /// after.
+    fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let mut writer = self.writer.lock().unwrap();
+        writer.sync_dir()?;
+        writer.writer.flush()?;
+        writer.writer.sync_all()?;
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.check_writable()?;
+        let _guard = self.rwmutex.write().unwrap();
+
+        self.compact_segments()
+    }
+
+    fn stats(&self) -> KvStoreStats {
+        let index = self.index.lock().unwrap();
+        let mut stats = KvStoreStats::default();
+
+        for info in index.values() {
+            stats.keys += 1;
+            stats.raw_bytes += info.plain_size;
+            stats.stored_bytes += info.size;
+            if info.flags & FLAG_COMPRESSED != 0 {
+                stats.compressed_records += 1;
+            }
         }
 
-        let mut reader = self.reader.clone();
-        reader.readers = get_readers_dir(self.dir.clone());
+        stats
+    }
 
-        writer.writer = compaction_writer;
+    // Size-tiered compaction strategy
+    //
+    // Live records are written to a temporary file which is synced and then
+    // renamed to segment 0 of the next generation. Only then are the old
+    // segments removed; `recover_segments` finishes that job after a crash.
+    fn compact_segments(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let mut index = self.index.lock().unwrap();
+
+        let compaction_gen = writer.curr_gen + 1;
+        let compacted_file_path = segment_path(&self.dir, compaction_gen, 0);
+        let tmp_path = compacted_file_path.with_extension(COMPACTION_EXT);
+
+        // a previous attempt may have failed halfway
+        let _ = self.fs.remove_file(&tmp_path);
+
+        let compacted = match self.write_compacted(&writer, &index, &tmp_path) {
+            Ok(compacted) => compacted,
+            Err(e) => {
+                let _ = self.fs.remove_file(&tmp_path);
+                return Err(writer.check_full(e));
+            }
+        };
 
-        let new_rdr = BufReader::new(File::open(compacted_file_path).unwrap());
-        reader.readers.push(new_rdr);
+        self.fs.rename(&tmp_path, &compacted_file_path)?;
 
+        // from here on the new generation is the live one
+        let old_gen = writer.curr_gen;
+        let old_segment = writer.curr_segment;
+        writer.writer = compacted.file;
+        writer.len = compacted.len;
         writer.curr_gen = compaction_gen;
+        writer.curr_segment = 0;
         writer.max_segment_size *= NUM_SEGMENTS_COMPACTION_THREASHOLD as u64;
+        writer.torn_at = None;
+        writer.dir_dirty = true;
+        writer.full_since = None;
+
+        for (key, info) in compacted.moved {
+            index.insert(key, info);
+        }
+        drop(index);
+
+        writer.sync_dir()?;
+
+        // remove old files
+        for segment_id in 0..=old_segment {
+            let _ = self
+                .fs
+                .remove_file(&segment_path(&self.dir, old_gen, segment_id));
+        }
 
         Ok(())
     }
+
+    /// Writes every live record to `path` and syncs it.
+    fn write_compacted(
+        &self,
+        writer: &StoreWriter,
+        index: &BTreeMap<String, SizeInfo>,
+        path: &Path,
+    ) -> Result<Compacted> {
+        let mut compaction_writer = BufWriter::new(self.fs.open_append(path)?);
+        let mut readers: HashMap<u32, Box<dyn SegmentFile>> = HashMap::new();
+        let mut moved = Vec::with_capacity(index.len());
+        let mut pos = 0;
+
+        for (key, info) in index.iter() {
+            let reader = match readers.get_mut(&info.segment_id) {
+                Some(reader) => reader,
+                None => {
+                    let reader = self.reader.segment(writer.curr_gen, info.segment_id)?;
+                    readers.entry(info.segment_id).or_insert(reader)
+                }
+            };
+
+            reader.seek(SeekFrom::Start(info.start))?;
+            let mut buf = vec![0; info.size as usize];
+            reader.read_exact(&mut buf)?;
+
+            // this is where records move to a new encryption key or
+            // compression setting
+            let (flags, payload) = if self.codec.is_current(info, &buf) {
+                (info.flags, buf)
+            } else {
+                let plain = self
+                    .codec
+                    .decode(info.flags, buf)?
+                    .ok_or_else(|| corrupt_record(writer.curr_gen, info))?;
+                self.codec.encode(plain)?
+            };
+            let size = payload.len() as u64;
+
+            compaction_writer.write_u64::<BigEndian>(header(flags, size))?;
+            compaction_writer.write_all(&payload)?;
+
+            moved.push((
+                key.clone(),
+                SizeInfo {
+                    start: pos + 8,
+                    segment_id: 0,
+                    size,
+                    flags,
+                    plain_size: info.plain_size,
+                },
+            ));
+            pos += 8 + size;
+        }
+
+        let file = compaction_writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+
+        Ok(Compacted {
+            file,
+            len: pos,
+            moved,
+        })
+    }
 }
 
-impl KvsEngine for KvStore {
+impl Shard {
     fn get(&self, key: String) -> Result<Option<String>> {
-        let x = self.rwmutex.read().unwrap();
-        let index = self.index.lock().unwrap();
+        self.retry_stale(|| self.read_value(&key))
+    }
 
-        if let Some(info) = index.get(&key) {
-            let idx = info.segment_id;
-            let mut reader = self.reader.clone();
-            let reader = reader.readers.get_mut(idx as usize).unwrap();
-            reader.seek(SeekFrom::Start(info.start))?;
-            let cmd_reader = reader.take(info.size);
+    fn scan(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.retry_stale(|| self.read_range(start.clone(), limit))
+    }
 
-            let KVPair { value, .. } = serde_json::from_reader(cmd_reader).unwrap();
-            match value.as_str() {
-                "rm" => Ok(None),
-                _ => Ok(Some(value)),
+    /// Runs a read again after a refresh if a read-only shard's segments were
+    /// compacted away by the writer process.
+    fn retry_stale<T>(&self, read: impl Fn() -> Result<T>) -> Result<T> {
+        match read() {
+            Err(e) if self.read_only && is_not_found(&e) => {
+                self.refresh()?;
+                read()
             }
+            result => result,
+        }
+    }
+
+    fn read_value(&self, key: &str) -> Result<Option<String>> {
+        let _guard = self.rwmutex.read().unwrap();
+        let gen = self.writer.lock().unwrap().curr_gen;
+        let index = self.index.lock().unwrap();
+
+        if let Some(info) = index.get(key) {
+            let mut reader = self.reader.segment(gen, info.segment_id)?;
+            let KVPair { value, .. } = read_at(&mut reader, gen, info, &self.codec)?;
+            Ok(value)
         } else {
             Ok(None)
         }
     }
 
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let x = self.rwmutex.write().unwrap();
-        let mut writer = self.writer.lock().unwrap();
-        let mut reader = self.reader.clone();
-
-        let pos = writer.writer.seek(SeekFrom::End(0))?;
-        let num_segments = reader.readers.len();
+    fn remove(&self, key: String) -> Result<()> {
+        self.append(key, None)
+    }
 
-        if pos >= writer.max_segment_size {
-            let fname = format!("{}_kv_{}.dat", writer.curr_gen, num_segments);
-            let file_path = self.dir.join(fname);
-            new_file(file_path.clone()).unwrap();
+    fn read_range(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let _guard = self.rwmutex.read().unwrap();
+        let gen = self.writer.lock().unwrap().curr_gen;
+        let infos: Vec<SizeInfo> = self
+            .index
+            .lock()
+            .unwrap()
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(_, info)| *info)
+            .collect();
+
+        let mut readers: HashMap<u32, Box<dyn SegmentFile>> = HashMap::new();
+        let mut pairs = Vec::with_capacity(infos.len());
+        for info in infos {
+            let reader = match readers.get_mut(&info.segment_id) {
+                Some(reader) => reader,
+                None => {
+                    let reader = self.reader.segment(gen, info.segment_id)?;
+                    readers.entry(info.segment_id).or_insert(reader)
+                }
+            };
+
+            // tombstones are never indexed
+            if let KVPair {
+                key,
+                value: Some(value),
+            } = read_at(reader, gen, &info, &self.codec)?
+            {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
 
-            reader.readers.push(get_reader(&file_path));
+    /// Appends a record to the active segment, `None` being a removal.
+    fn append(&self, key: String, value: Option<String>) -> Result<()> {
+        self.check_writable()?;
+        let _guard = self.rwmutex.write().unwrap();
+        let mut writer = self.writer.lock().unwrap();
 
-            writer.writer = get_writer(file_path);
+        if let Some(since) = writer.full_since {
+            if since.elapsed() < FULL_PROBE_INTERVAL {
+                return Err(KvsError::StorageFull.into());
+            }
+        }
+        if value.is_none() && !self.index.lock().unwrap().contains_key(&key) {
+            return Err(KvsError::KeyNotFound.into());
         }
-
-        let pos = writer.writer.seek(SeekFrom::End(0))?;
 
         let kv_pair = KVPair {
             key: key.clone(),
-            value: value.clone(),
+            value,
         };
-        let kv_pair_serialized = serde_json::to_string(&kv_pair)?;
+        let plain = serde_json::to_vec(&kv_pair)?;
+        let plain_size = plain.len() as u64;
+        let (flags, payload) = self.codec.encode(plain)?;
 
-        writer
-            .writer
-            .write_u64::<BigEndian>(kv_pair_serialized.len() as u64)?;
-        write!(writer.writer, "{}", kv_pair_serialized)?;
-
-        writer.writer.flush()?;
-        writer.writer.get_ref().sync_all()?;
+        let (segment_id, pos) = match writer.append(flags, &payload) {
+            Ok(at) => at,
+            Err(e) => return Err(writer.check_full(e)),
+        };
+        writer.full_since = None;
 
         let mut index = self.index.lock().unwrap();
 
-        let num_segments = reader.readers.len();
-        // println!("num_segments: {}", num_segments);
-
-        index.insert(
-            key.clone(),
-            SizeInfo {
-                start: pos + 8,
-                segment_id: (num_segments - 1) as u32,
-                size: kv_pair_serialized.len() as u64,
-            },
-        );
+        // a tombstone never lives in the index, otherwise removing a key twice
+        // would succeed and compaction would carry dead keys forward
+        if kv_pair.value.is_none() {
+            index.remove(&key);
+        } else {
+            index.insert(
+                key,
+                SizeInfo {
+                    start: pos + 8,
+                    segment_id,
+                    size: payload.len() as u64,
+                    flags,
+                    plain_size,
+                },
+            );
+        }
 
-        if reader.readers.len() > NUM_SEGMENTS_COMPACTION_THREASHOLD as usize {
+        if writer.curr_segment + 1 > NUM_SEGMENTS_COMPACTION_THREASHOLD {
             drop(writer);
             drop(index);
 
-            self.compact()?;
+            // the record is durable either way; without room to compact into,
+            // the shard goes read-only until the next probe
+            match self.compact_segments() {
+                Err(e) if !is_storage_full(&e) => return Err(e),
+                _ => {}
+            }
         }
 
         Ok(())
     }
+}
 
-    fn remove(&self, key: String) -> Result<()> {
-        let mut index = self.index.lock().unwrap();
-
-        if let Some(_) = index.remove(&key) {
-            drop(index);
-            self.set(key, "rm".to_string())
+impl StoreWriter {
+    /// Degrades the shard to read-only if `e` means the disk is full.
+    fn check_full(&mut self, e: failure::Error) -> failure::Error {
+        if is_storage_full(&e) {
+            self.full_since = Some(Instant::now());
+            KvsError::StorageFull.into()
         } else {
-            Err(failure::err_msg("Key not found"))
+            e
         }
     }
-}
 
-fn new_file(path: PathBuf) -> Result<File> {
-    let file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .read(true)
-        .open(&path)?;
+    /// Appends one record, rotating the segment first if it is full, and syncs
+    /// it. Returns the segment and offset the record was written at.
+    fn append(&mut self, flags: u8, payload: &[u8]) -> Result<(u32, u64)> {
+        if let Some(pos) = self.torn_at {
+            self.writer.set_len(pos)?;
+            self.torn_at = None;
+        }
+        self.sync_dir()?;
 
-    Ok(file)
-}
+        if self.len >= self.max_segment_size {
+            self.rotate()?;
+        }
 
-fn get_readers_dir(path: PathBuf) -> Vec<BufReader<File>> {
-    let filepaths = get_file_paths_sorted(path);
-    // println!("filepaths: {:?}", filepaths);
+        let pos = self.len;
+        let mut record = Vec::with_capacity(8 + payload.len());
+        record.write_u64::<BigEndian>(header(flags, payload.len() as u64))?;
+        record.extend_from_slice(payload);
 
-    get_readers(&filepaths)
-}
+        let written = self
+            .writer
+            .write_all(&record)
+            .and_then(|_| self.writer.flush())
+            .and_then(|_| self.writer.sync_all());
+
+        if let Err(e) = written {
+            // never leave a partial record behind, later appends would land after it
+            if self.writer.set_len(pos).is_err() {
+                self.torn_at = Some(pos);
+            }
+            return Err(e.into());
+        }
+
+        self.len += record.len() as u64;
+        Ok((self.curr_segment, pos))
+    }
+
+    /// Starts the next segment of the current generation.
+    fn rotate(&mut self) -> Result<()> {
+        let segment_id = self.curr_segment + 1;
+        self.writer = new_segment(self.fs.as_ref(), &self.dir, self.curr_gen, segment_id)?;
+        self.curr_segment = segment_id;
+        self.len = 0;
 
-fn get_readers(filepaths: &Vec<PathBuf>) -> Vec<BufReader<File>> {
-    let mut readers = Vec::new();
+        Ok(())
+    }
+
+    fn sync_dir(&mut self) -> Result<()> {
+        if self.dir_dirty {
+            self.fs.sync_dir(&self.dir)?;
+            self.dir_dirty = false;
+        }
 
-    for filepath in filepaths {
-        readers.push(get_reader(filepath));
+        Ok(())
     }
+}
 
-    readers
+fn header(flags: u8, size: u64) -> u64 {
+    (flags as u64) << FLAGS_SHIFT | size
 }
 
-fn get_reader(path: &PathBuf) -> BufReader<File> {
-    let file = File::open(path).unwrap();
-    BufReader::new(file)
+fn corrupt_record(gen: u32, info: &SizeInfo) -> failure::Error {
+    failure::err_msg(format!(
+        "Corrupt record in segment {}_kv_{} at offset {}",
+        gen,
+        info.segment_id,
+        info.start - 8
+    ))
 }
 
-fn get_current_writer(filepaths: &Vec<PathBuf>) -> BufWriter<File> {
-    let len = filepaths.len();
-    let file_path = filepaths[len - 1].clone();
+/// Reads the record `info` points at.
+fn read_at(
+    reader: &mut Box<dyn SegmentFile>,
+    gen: u32,
+    info: &SizeInfo,
+    codec: &Codec,
+) -> Result<KVPair> {
+    reader.seek(SeekFrom::Start(info.start))?;
+    let mut buf = vec![0; info.size as usize];
+    reader.read_exact(&mut buf)?;
+
+    let plain = codec
+        .decode(info.flags, buf)?
+        .ok_or_else(|| corrupt_record(gen, info))?;
+    Ok(KVPair::from_slice(info.flags, &plain)?)
+}
 
-    get_writer(file_path)
+/// Reads the record at `pos`, the reader's position, and returns it with its
+/// index entry. Returns `None` if fewer than `remaining` bytes hold a
+/// complete, well-formed record.
+fn read_record(
+    reader: &mut impl Read,
+    segment_id: u32,
+    pos: u64,
+    remaining: u64,
+    codec: &Codec,
+) -> Result<Option<(KVPair, SizeInfo)>> {
+    if remaining < 8 {
+        return Ok(None);
+    }
+
+    let header = reader.read_u64::<BigEndian>()?;
+    let flags = (header >> FLAGS_SHIFT) as u8;
+    let sz = header & LEN_MASK;
+    if sz > remaining - 8 {
+        return Ok(None);
+    }
+
+    let mut buf = vec![0; sz as usize];
+    reader.read_exact(&mut buf)?;
+
+    let plain = match codec.decode(flags, buf)? {
+        Some(plain) => plain,
+        None => return Ok(None),
+    };
+    let info = SizeInfo {
+        start: pos + 8,
+        segment_id,
+        size: sz,
+        flags,
+        plain_size: plain.len() as u64,
+    };
+    match KVPair::from_slice(flags, &plain) {
+        Ok(kvpair) => Ok(Some((kvpair, info))),
+        Err(_) => Ok(None),
+    }
 }
 
-fn get_writer(path: PathBuf) -> BufWriter<File> {
-    let file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(&path)
-        .unwrap();
+/// Creates an empty segment and makes its directory entry durable.
+fn new_segment(
+    fs: &dyn FileSystem,
+    dir: &Path,
+    gen: u32,
+    segment_id: u32,
+) -> Result<Box<dyn SegmentFile>> {
+    let file = fs.open_append(&segment_path(dir, gen, segment_id))?;
+    fs.sync_dir(dir)?;
 
-    BufWriter::new(file)
+    Ok(file)
 }
 
-fn get_file_paths_sorted(path: PathBuf) -> Vec<PathBuf> {
-    let mut filepaths = Vec::new();
+fn segment_path(dir: &Path, gen: u32, segment_id: u32) -> PathBuf {
+    dir.join(format!("{}_kv_{}.{}", gen, segment_id, SEGMENT_EXT))
+}
 
-    let mut dir_iter = fs::read_dir(path).unwrap();
+/// Parses `{gen}_kv_{segment_id}` out of a segment file name.
+fn parse_segment_path(path: &Path) -> Option<(u32, u32)> {
+    let filename = path.file_stem()?.to_str()?;
+    let filename_split: Vec<&str> = filename.split('_').collect();
 
-    while let Some(entry) = dir_iter.next() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_file() {
-            filepaths.push(path);
-        }
+    match filename_split.as_slice() {
+        [gen, "kv", segment_id] => Some((gen.parse().ok()?, segment_id.parse().ok()?)),
+        _ => None,
     }
+}
+
+fn shard_dirs(dir: &Path, shards: usize) -> Vec<PathBuf> {
+    (0..shards)
+        .map(|i| dir.join(format!("shard-{}", i)))
+        .collect()
+}
+
+fn manifest_tmp_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST).with_extension("tmp")
+}
 
-    filepaths.sort_by(|a, b| {
-        // get filename without extension
-        let a_filename = a.file_stem().unwrap().to_str().unwrap();
-        let b_filename = b.file_stem().unwrap().to_str().unwrap();
+/// Reads the shard count of a sharded store, `None` if it isn't one.
+fn read_manifest(fs: &dyn FileSystem, dir: &Path) -> Result<Option<usize>> {
+    let mut file = match fs.open_read(&dir.join(MANIFEST)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    let manifest: Manifest = serde_json::from_slice(&buf)?;
+    Ok(Some(manifest.shards))
+}
 
-        // get segment id
-        let a_filename_split: Vec<&str> = a_filename.split("_").collect();
-        let a_segment_id = a_filename_split[2].parse::<u32>().unwrap();
+/// Records the shard count, atomically so a store is never half sharded.
+fn write_manifest(fs: &dyn FileSystem, dir: &Path, shards: usize) -> Result<()> {
+    let tmp_path = manifest_tmp_path(dir);
+    let mut file = fs.open_append(&tmp_path)?;
+    file.write_all(&serde_json::to_vec(&Manifest { shards })?)?;
+    file.sync_all()?;
+    fs.rename(&tmp_path, &dir.join(MANIFEST))?;
+    fs.sync_dir(dir)?;
+    Ok(())
+}
 
-        let b_filename_split: Vec<&str> = b_filename.split("_").collect();
-        let b_segment_id = b_filename_split[2].parse::<u32>().unwrap();
+/// Whether `dir` holds the segments of an unsharded store.
+fn has_segments(fs: &dyn FileSystem, dir: &Path) -> Result<bool> {
+    Ok(fs
+        .list(dir)?
+        .iter()
+        .any(|path| parse_segment_path(path).is_some()))
+}
 
-        // sort by segment id
-        a_segment_id.cmp(&b_segment_id)
-    });
+fn is_storage_full(e: &failure::Error) -> bool {
+    if let Some(KvsError::StorageFull) = e.downcast_ref::<KvsError>() {
+        return true;
+    }
+    e.downcast_ref::<io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+        )
+    })
+}
+
+fn is_not_found(e: &failure::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+}
 
-    filepaths
+/// FNV-1a. Unlike `DefaultHasher` it is fixed across Rust releases, which a
+/// key's shard has to be.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
 }
 
-fn get_curr_gen(filepaths: &Vec<PathBuf>) -> u32 {
-    let len = filepaths.len();
-    let filepath = filepaths[len - 1].clone();
-    let filename = filepath.file_name().unwrap().to_str().unwrap();
-    let filename_split: Vec<&str> = filename.split("_").collect();
-    filename_split[0].parse::<u32>().unwrap()
+/// Finishes or rolls back an interrupted compaction, then returns the live
+/// generation with its segment ids in order, or `None` for an empty directory.
+/// A `read_only` caller leaves leftovers of a compaction alone instead, which
+/// may be one the writer process is in the middle of.
+fn recover_segments(
+    fs: &dyn FileSystem,
+    dir: &Path,
+    read_only: bool,
+) -> Result<Option<(u32, Vec<u32>)>> {
+    let mut segments = Vec::new();
+
+    for path in fs.list(dir)? {
+        let ext = path.extension().and_then(|ext| ext.to_str());
+        match (ext, parse_segment_path(&path)) {
+            // never renamed into place, so it holds nothing live
+            (Some(COMPACTION_EXT), Some(_)) if read_only => {}
+            (Some(COMPACTION_EXT), Some(_)) => fs.remove_file(&path)?,
+            (Some(SEGMENT_EXT), Some(segment)) => segments.push(segment),
+            _ => {
+                return Err(failure::err_msg(format!(
+                    "Unexpected file {} in KvStore directory",
+                    path.display()
+                )))
+            }
+        }
+    }
+
+    let curr_gen = match segments.iter().map(|&(gen, _)| gen).max() {
+        Some(gen) => gen,
+        None => return Ok(None),
+    };
+
+    // older generations were fully compacted into the newest one
+    let mut live = Vec::new();
+    for (gen, segment_id) in segments {
+        if gen < curr_gen {
+            if !read_only {
+                fs.remove_file(&segment_path(dir, gen, segment_id))?;
+            }
+        } else {
+            live.push(segment_id);
+        }
+    }
+    live.sort_unstable();
+
+    Ok(Some((curr_gen, live)))
 }
@@ -1,3 +1,7 @@
+use std::ops::Bound;
+
+use async_trait::async_trait;
+
 use crate::Result;
 
 /// Trait for a key value storage engine.
@@ -18,12 +22,50 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Returns up to `limit` key-value pairs in key order, beginning at `start`.
+    ///
+    /// Paging through an engine is a matter of passing the last key returned as
+    /// `Bound::Excluded` to the next call.
+    fn scan(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>>;
+
+    /// Makes every write that has returned durable, for shutting down
+    /// cleanly. Engines that sync as they write have nothing to do.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Async counterpart of [`KvsEngine`] for callers running on a tokio runtime.
+///
+/// Blocking engines are adapted with [`BlockingEngine`] or [`PooledEngine`], which
+/// move every call off the async worker threads.
+#[async_trait]
+pub trait AsyncKvsEngine: Clone + Send + Sync + 'static {
+    /// Sets the value of a string key to a string.
+    async fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Gets the string value of a given string key.
+    async fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Removes a given key.
+    async fn remove(&self, key: String) -> Result<()>;
 }
 
+pub mod blocking;
+pub mod crypto;
+pub mod fs;
 pub mod inmem;
 pub mod kvs;
 pub mod sled_kvs;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
 
+pub use self::blocking::{BlockingEngine, PooledEngine};
+pub use self::crypto::EncryptionKey;
+pub use self::fs::{FileSystem, MemFs, SegmentFile, StdFs};
 pub use self::inmem::InMemEngine;
-pub use self::kvs::KvStore;
+pub use self::kvs::{KvStore, KvStoreOptions, KvStoreStats};
 pub use self::sled_kvs::SledKvsEngine;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use self::uring::UringFs;
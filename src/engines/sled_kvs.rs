@@ -1,6 +1,7 @@
+use std::ops::Bound;
 use std::path::PathBuf;
 
-use crate::{KvsEngine, Result};
+use crate::{KvsEngine, KvsError, Result};
 
 #[derive(Clone)]
 pub struct SledKvsEngine {
@@ -38,8 +39,30 @@ impl KvsEngine for SledKvsEngine {
                 self.store.flush()?;
                 Ok(())
             }
-            Ok(None) => Err(failure::err_msg("Key not found")),
+            Ok(None) => Err(KvsError::KeyNotFound.into()),
             Err(e) => Err(e.into()),
         }
     }
+
+    fn scan(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let start = start.as_ref().map(String::as_bytes);
+        let mut pairs = Vec::new();
+        for entry in self
+            .store
+            .range::<&[u8], _>((start, Bound::Unbounded))
+            .take(limit)
+        {
+            let (key, value) = entry?;
+            pairs.push((
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ));
+        }
+        Ok(pairs)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.store.flush()?;
+        Ok(())
+    }
 }
@@ -0,0 +1,303 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crossbeam_channel::{Receiver, Sender};
+use io_uring::{opcode, squeue, types, IoUring};
+
+use crate::engines::fs::{FileSystem, SegmentFile, StdFs};
+
+/// [`FileSystem`] doing segment appends, fsyncs and reads through io_uring.
+///
+/// Requests from every file share one ring. A driver thread submits whatever
+/// has queued up while the previous batch was in flight with a single
+/// syscall, so concurrent appends and their fsyncs reach the disk together.
+/// Directory operations go through `std::fs`.
+#[derive(Clone, Debug)]
+pub struct UringFs {
+    ring: Arc<Ring>,
+}
+
+impl UringFs {
+    /// Sets up a ring with room for `entries` requests in flight. Fails where
+    /// the kernel doesn't offer io_uring, in which case [`StdFs`] is the
+    /// fallback.
+    pub fn new(entries: u32) -> io::Result<UringFs> {
+        let ring = IoUring::new(entries)?;
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        thread::Builder::new()
+            .name("kvs-io-uring".to_owned())
+            .spawn(move || drive(ring, receiver))?;
+
+        Ok(UringFs {
+            ring: Arc::new(Ring { requests: sender }),
+        })
+    }
+
+    fn open(&self, path: &Path, write: bool) -> io::Result<Box<dyn SegmentFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(write)
+            .create(write)
+            .open(path)?;
+        let end = file.metadata()?.len();
+
+        Ok(Box::new(UringFile {
+            file,
+            ring: self.ring.clone(),
+            pos: 0,
+            end: AtomicU64::new(end),
+        }))
+    }
+}
+
+impl FileSystem for UringFs {
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        StdFs.create_dir_all(dir)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        StdFs.list(dir)
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn SegmentFile>> {
+        self.open(path, true)
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn SegmentFile>> {
+        self.open(path, false)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        StdFs.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        StdFs.remove_file(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        StdFs.sync_dir(dir)
+    }
+}
+
+/// Hands requests to the driver thread, which exits with the last sender.
+#[derive(Debug)]
+struct Ring {
+    requests: Sender<Request>,
+}
+
+impl Ring {
+    /// Runs `op` and waits for it, returning the kernel's result and the
+    /// buffer the op owned.
+    fn run(&self, op: Op) -> io::Result<(usize, Vec<u8>)> {
+        let (reply, done) = crossbeam_channel::bounded(1);
+        self.requests
+            .send(Request { op, reply })
+            .map_err(|_| io::Error::other("io_uring driver stopped"))?;
+        done.recv()
+            .map_err(|_| io::Error::other("io_uring driver stopped"))?
+    }
+}
+
+enum Op {
+    Write {
+        fd: RawFd,
+        buf: Vec<u8>,
+        offset: u64,
+    },
+    Read {
+        fd: RawFd,
+        buf: Vec<u8>,
+        offset: u64,
+    },
+    Fsync {
+        fd: RawFd,
+    },
+}
+
+impl Op {
+    /// The submission entry. Buffers stay put until the op completes, as they
+    /// live on the heap of a request the driver holds on to.
+    fn entry(&mut self) -> squeue::Entry {
+        match self {
+            Op::Write { fd, buf, offset } => {
+                opcode::Write::new(types::Fd(*fd), buf.as_ptr(), buf.len() as u32)
+                    .offset(*offset)
+                    .build()
+            }
+            Op::Read { fd, buf, offset } => {
+                opcode::Read::new(types::Fd(*fd), buf.as_mut_ptr(), buf.len() as u32)
+                    .offset(*offset)
+                    .build()
+            }
+            Op::Fsync { fd } => opcode::Fsync::new(types::Fd(*fd)).build(),
+        }
+    }
+
+    fn into_buf(self) -> Vec<u8> {
+        match self {
+            Op::Write { buf, .. } | Op::Read { buf, .. } => buf,
+            Op::Fsync { .. } => Vec::new(),
+        }
+    }
+}
+
+struct Request {
+    op: Op,
+    reply: Sender<io::Result<(usize, Vec<u8>)>>,
+}
+
+/// Submits requests in batches until every `UringFs` is gone.
+///
+/// A failed submit leaves ops in flight whose completions could land in a
+/// later batch, so from then on every request fails with its error instead.
+fn drive(mut ring: IoUring, requests: Receiver<Request>) {
+    let capacity = ring.params().sq_entries() as usize;
+    let mut poisoned: Option<String> = None;
+
+    while let Ok(first) = requests.recv() {
+        if let Some(message) = &poisoned {
+            let _ = first.reply.send(Err(io::Error::other(format!(
+                "io_uring failed earlier: {}",
+                message
+            ))));
+            continue;
+        }
+
+        let mut batch = vec![first];
+        while batch.len() < capacity {
+            match requests.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_) => break,
+            }
+        }
+
+        for (i, request) in batch.iter_mut().enumerate() {
+            let entry = request.op.entry().user_data(i as u64);
+            // safe as the batch, buffers included, outlives its completions
+            unsafe {
+                ring.submission()
+                    .push(&entry)
+                    .expect("a batch never exceeds the submission queue");
+            }
+        }
+
+        let mut results = vec![None; batch.len()];
+        let mut pending = batch.len();
+        while pending > 0 {
+            match ring.submit_and_wait(pending) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // the kernel may still write into the buffers, so they
+                    // can never be freed
+                    let message = e.to_string();
+                    for request in batch.drain(..) {
+                        mem::forget(request.op);
+                        let _ = request.reply.send(Err(io::Error::other(message.clone())));
+                    }
+                    poisoned = Some(message);
+                    break;
+                }
+            }
+            for completion in ring.completion() {
+                results[completion.user_data() as usize] = Some(completion.result());
+                pending -= 1;
+            }
+        }
+
+        for (request, result) in batch.into_iter().zip(results) {
+            let result = result.expect("every submitted op completes");
+            let reply = if result < 0 {
+                Err(io::Error::from_raw_os_error(-result))
+            } else {
+                Ok((result as usize, request.op.into_buf()))
+            };
+            let _ = request.reply.send(reply);
+        }
+    }
+}
+
+/// Segment file whose reads and writes are positional, so appends land at
+/// `end` whatever the read position.
+#[derive(Debug)]
+struct UringFile {
+    file: File,
+    ring: Arc<Ring>,
+    pos: u64,
+    end: AtomicU64,
+}
+
+impl Read for UringFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let (n, data) = self.ring.run(Op::Read {
+            fd: self.file.as_raw_fd(),
+            buf: vec![0; buf.len()],
+            offset: self.pos,
+        })?;
+        buf[..n].copy_from_slice(&data[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for UringFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let offset = self.end.load(Ordering::SeqCst);
+        let (n, _) = self.ring.run(Op::Write {
+            fd: self.file.as_raw_fd(),
+            buf: buf.to_vec(),
+            offset,
+        })?;
+        self.end.store(offset + n as u64, Ordering::SeqCst);
+        self.pos = offset + n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for UringFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => self.file.metadata()?.len() as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before start of file",
+            ));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl SegmentFile for UringFile {
+    fn sync_all(&self) -> io::Result<()> {
+        self.ring.run(Op::Fsync {
+            fd: self.file.as_raw_fd(),
+        })?;
+        Ok(())
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)?;
+        self.end.store(size, Ordering::SeqCst);
+        Ok(())
+    }
+}
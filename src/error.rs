@@ -1,3 +1,55 @@
+use std::fmt;
+
 use failure::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors callers may need to tell apart, found with `Error::downcast_ref`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum KvsError {
+    /// `KvsEngine::remove` found no value to remove.
+    KeyNotFound,
+    /// The disk or the quota is full. Writes are refused until a probe finds
+    /// room again or a compaction frees some; reads keep working.
+    StorageFull,
+    /// A key is longer than a `SizeLimitLayer` or a `KvsServer` allows.
+    KeyTooLarge { size: usize, limit: usize },
+    /// A value is longer than a `SizeLimitLayer` or a `KvsServer` allows.
+    ValueTooLarge { size: usize, limit: usize },
+    /// The other end of a connection speaks none of the protocol versions
+    /// from `min_version` to `max_version`.
+    IncompatibleProtocol { min_version: u32, max_version: u32 },
+    /// The server knows no such token, or no such user with that password.
+    Unauthenticated,
+    /// The server's ACL doesn't let the client read or write the key.
+    PermissionDenied,
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::StorageFull => {
+                write!(f, "Storage full, KvStore is read-only until space is freed")
+            }
+            KvsError::KeyTooLarge { size, limit } => {
+                write!(f, "Key of {} bytes exceeds the limit of {}", size, limit)
+            }
+            KvsError::ValueTooLarge { size, limit } => {
+                write!(f, "Value of {} bytes exceeds the limit of {}", size, limit)
+            }
+            KvsError::IncompatibleProtocol {
+                min_version,
+                max_version,
+            } => write!(
+                f,
+                "Incompatible peer, expected protocol version {} to {}",
+                min_version, max_version
+            ),
+            KvsError::Unauthenticated => write!(f, "Invalid credentials"),
+            KvsError::PermissionDenied => write!(f, "Permission denied"),
+        }
+    }
+}
+
+impl std::error::Error for KvsError {}
@@ -0,0 +1,210 @@
+use std::net::SocketAddr;
+use std::ops::Bound;
+
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Bytes, Data, Path, Query};
+use actix_web::{App, HttpResponse, HttpServer};
+use slog::{info, warn, Logger};
+
+use crate::layers::SizeLimitLayer;
+use crate::{KvsEngine, KvsError, Result};
+
+/// Largest request body when no value size limit is set.
+const DEFAULT_BODY_LIMIT: usize = 64 * 1024 * 1024;
+/// Pairs a listing returns when it isn't given a limit.
+const DEFAULT_LIST_LIMIT: usize = 100;
+/// Most pairs a listing returns.
+const MAX_LIST_LIMIT: usize = 1000;
+
+const TEXT: &str = "text/plain; charset=utf-8";
+
+/// Serves a `KvsEngine` as a REST resource:
+///
+/// - `GET /kv/{key}` answers with the value as text, or 404.
+/// - `PUT /kv/{key}` stores the request body as the value, answering 204.
+/// - `DELETE /kv/{key}` answers 204, or 404 if there was nothing to delete.
+/// - `GET /kv?prefix=&after=&limit=` lists pairs whose keys start with
+///   `prefix` as JSON, in key order from after `after`, with the `after`
+///   for the next page as `next`.
+///
+/// Errors come back as JSON with an `error` message.
+pub struct HttpGateway<E: KvsEngine> {
+    addr: SocketAddr,
+    engine: E,
+    logger: Logger,
+    limits: SizeLimitLayer,
+}
+
+impl<E: KvsEngine> HttpGateway<E> {
+    pub fn new(addr: SocketAddr, engine: E, logger: Logger) -> Self {
+        HttpGateway {
+            addr,
+            engine,
+            logger,
+            limits: SizeLimitLayer::default(),
+        }
+    }
+
+    /// Refuses request bodies over the value size limit with a 413 before
+    /// reading them.
+    pub fn with_limits(mut self, limits: SizeLimitLayer) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Binds the address and serves requests on actix's workers, with
+    /// engine calls on its blocking pool. Must run inside an actix `System`.
+    pub async fn run(self) -> Result<()> {
+        let engine = self.engine;
+        let logger = self.logger.clone();
+        let body_limit = self.limits.max_value_size().unwrap_or(DEFAULT_BODY_LIMIT);
+
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(Data::new(State {
+                    engine: engine.clone(),
+                    logger: logger.clone(),
+                }))
+                .app_data(web::PayloadConfig::new(body_limit))
+                .route("/kv", web::get().to(list::<E>))
+                .route("/kv/{key:.*}", web::get().to(get::<E>))
+                .route("/kv/{key:.*}", web::put().to(put::<E>))
+                .route("/kv/{key:.*}", web::delete().to(delete::<E>))
+        })
+        .bind(self.addr)?;
+        info!(self.logger, "Started HTTP listener on {}", self.addr);
+        server.run().await?;
+        Ok(())
+    }
+}
+
+struct State<E> {
+    engine: E,
+    logger: Logger,
+}
+
+impl<E: KvsEngine> State<E> {
+    /// Runs `f` on the blocking pool.
+    async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        web::block(move || f(engine))
+            .await
+            .map_err(|e| failure::err_msg(e.to_string()))?
+    }
+
+    fn error(&self, e: failure::Error) -> HttpResponse {
+        let status = match e.downcast_ref::<KvsError>() {
+            Some(KvsError::KeyNotFound) => StatusCode::NOT_FOUND,
+            Some(KvsError::KeyTooLarge { .. }) | Some(KvsError::ValueTooLarge { .. }) => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            Some(KvsError::StorageFull) => StatusCode::INSUFFICIENT_STORAGE,
+            _ => {
+                warn!(self.logger, "HTTP request failed"; "error" => %e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        error(status, e)
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+fn error(status: StatusCode, message: impl ToString) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorBody {
+        error: message.to_string(),
+    })
+}
+
+async fn get<E: KvsEngine>(state: Data<State<E>>, key: Path<String>) -> HttpResponse {
+    let key = key.into_inner();
+    match state.call(move |engine| engine.get(key)).await {
+        Ok(Some(value)) => HttpResponse::Ok().content_type(TEXT).body(value),
+        Ok(None) => state.error(KvsError::KeyNotFound.into()),
+        Err(e) => state.error(e),
+    }
+}
+
+async fn put<E: KvsEngine>(state: Data<State<E>>, key: Path<String>, body: Bytes) -> HttpResponse {
+    let key = key.into_inner();
+    let value = match String::from_utf8(body.to_vec()) {
+        Ok(value) => value,
+        Err(_) => return error(StatusCode::BAD_REQUEST, "Value is not UTF-8"),
+    };
+    match state.call(move |engine| engine.set(key, value)).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => state.error(e),
+    }
+}
+
+async fn delete<E: KvsEngine>(state: Data<State<E>>, key: Path<String>) -> HttpResponse {
+    let key = key.into_inner();
+    match state.call(move |engine| engine.remove(key)).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => state.error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    prefix: String,
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct ListBody {
+    items: Vec<Pair>,
+    /// Where the next page starts, if there may be one.
+    next: Option<String>,
+}
+
+async fn list<E: KvsEngine>(state: Data<State<E>>, query: Query<ListQuery>) -> HttpResponse {
+    let ListQuery {
+        prefix,
+        after,
+        limit,
+    } = query.into_inner();
+    let limit = match limit {
+        Some(limit) if limit == 0 || limit > MAX_LIST_LIMIT => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                format!("limit must be from 1 to {}", MAX_LIST_LIMIT),
+            )
+        }
+        limit => limit.unwrap_or(DEFAULT_LIST_LIMIT),
+    };
+    let start = match after {
+        Some(after) if after >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix.clone()),
+    };
+
+    let pairs = match state.call(move |engine| engine.scan(start, limit)).await {
+        Ok(pairs) => pairs,
+        Err(e) => return state.error(e),
+    };
+    let items: Vec<_> = pairs
+        .into_iter()
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .map(|(key, value)| Pair { key, value })
+        .collect();
+    let next = match items.last() {
+        Some(last) if items.len() == limit => Some(last.key.clone()),
+        _ => None,
+    };
+    HttpResponse::Ok().json(ListBody { items, next })
+}
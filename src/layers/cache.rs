@@ -0,0 +1,129 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+use crate::layers::Layer;
+use crate::{KvsEngine, Result};
+
+/// Keeps the results of recent gets, missing keys included, in an LRU cache
+/// of up to `capacity` keys. Writes invalidate their key once the wrapped
+/// engine has applied them; scans always go through.
+#[derive(Clone, Copy, Debug)]
+pub struct ReadCacheLayer {
+    capacity: usize,
+}
+
+impl ReadCacheLayer {
+    pub fn new(capacity: usize) -> Self {
+        ReadCacheLayer { capacity }
+    }
+}
+
+impl<E: KvsEngine> Layer<E> for ReadCacheLayer {
+    type Engine = ReadCache<E>;
+
+    fn layer(&self, inner: E) -> ReadCache<E> {
+        ReadCache {
+            inner,
+            cache: Arc::new(Mutex::new(Lru {
+                capacity: self.capacity,
+                ..Default::default()
+            })),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReadCache<E> {
+    inner: E,
+    cache: Arc<Mutex<Lru>>,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    capacity: usize,
+    /// Value and last use of every cached key.
+    entries: HashMap<String, (Option<String>, u64)>,
+    /// Cached keys by last use.
+    by_use: BTreeMap<u64, String>,
+    clock: u64,
+    /// Bumped by every write, so a get racing one doesn't cache what the
+    /// write replaced.
+    version: u64,
+}
+
+impl Lru {
+    fn get(&mut self, key: &str) -> Option<Option<String>> {
+        self.clock += 1;
+        let (value, used) = self.entries.get_mut(key)?;
+        self.by_use.remove(used);
+        *used = self.clock;
+        self.by_use.insert(self.clock, key.to_owned());
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: String, value: Option<String>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.by_use.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.by_use.insert(self.clock, key.clone());
+        self.entries.insert(key, (value, self.clock));
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.by_use.remove(&used);
+        }
+    }
+
+    fn invalidate(&mut self, key: &str) {
+        self.version += 1;
+        self.remove(key);
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ReadCache<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let result = self.inner.set(key.clone(), value);
+        self.cache.lock().unwrap().invalidate(&key);
+        result
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let version = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(value) = cache.get(&key) {
+                return Ok(value);
+            }
+            cache.version
+        };
+
+        let value = self.inner.get(key.clone())?;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.version == version {
+            cache.insert(key, value.clone());
+        }
+        Ok(value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let result = self.inner.remove(key.clone());
+        self.cache.lock().unwrap().invalidate(&key);
+        result
+    }
+
+    fn scan(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.inner.scan(start, limit)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
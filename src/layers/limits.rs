@@ -0,0 +1,92 @@
+use std::ops::Bound;
+
+use crate::layers::Layer;
+use crate::{KvsEngine, KvsError, Result};
+
+/// Refuses keys and values longer than the given number of bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SizeLimitLayer {
+    max_key_size: Option<usize>,
+    max_value_size: Option<usize>,
+}
+
+impl SizeLimitLayer {
+    pub fn new(max_key_size: Option<usize>, max_value_size: Option<usize>) -> Self {
+        SizeLimitLayer {
+            max_key_size,
+            max_value_size,
+        }
+    }
+
+    pub fn max_key_size(&self) -> Option<usize> {
+        self.max_key_size
+    }
+
+    pub fn max_value_size(&self) -> Option<usize> {
+        self.max_value_size
+    }
+}
+
+impl<E: KvsEngine> Layer<E> for SizeLimitLayer {
+    type Engine = SizeLimit<E>;
+
+    fn layer(&self, inner: E) -> SizeLimit<E> {
+        SizeLimit {
+            inner,
+            limits: *self,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SizeLimit<E> {
+    inner: E,
+    limits: SizeLimitLayer,
+}
+
+impl<E> SizeLimit<E> {
+    fn check_key(&self, key: &str) -> Result<()> {
+        match self.limits.max_key_size {
+            Some(limit) if key.len() > limit => Err(KvsError::KeyTooLarge {
+                size: key.len(),
+                limit,
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for SizeLimit<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.check_key(&key)?;
+        if let Some(limit) = self.limits.max_value_size {
+            if value.len() > limit {
+                return Err(KvsError::ValueTooLarge {
+                    size: value.len(),
+                    limit,
+                }
+                .into());
+            }
+        }
+        self.inner.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.check_key(&key)?;
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.check_key(&key)?;
+        self.inner.remove(key)
+    }
+
+    fn scan(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.inner.scan(start, limit)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,148 @@
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::layers::Layer;
+use crate::{KvsEngine, Result};
+
+/// Counters shared by every engine a [`MetricsLayer`] wraps.
+#[derive(Debug, Default)]
+pub struct EngineMetrics {
+    gets: OpCounter,
+    sets: OpCounter,
+    removes: OpCounter,
+    scans: OpCounter,
+    /// Gets that found a value.
+    get_hits: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct OpCounter {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    nanos: AtomicU64,
+}
+
+/// Figures about one kind of call.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OpStats {
+    pub calls: u64,
+    pub errors: u64,
+    /// Time spent in the wrapped engine, summed over every call.
+    pub total_time: Duration,
+}
+
+/// Figures collected by an [`EngineMetrics`] so far.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MetricsSnapshot {
+    pub gets: OpStats,
+    pub get_hits: u64,
+    pub sets: OpStats,
+    pub removes: OpStats,
+    pub scans: OpStats,
+}
+
+impl EngineMetrics {
+    pub fn new() -> Arc<EngineMetrics> {
+        Arc::new(EngineMetrics::default())
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            gets: self.gets.stats(),
+            get_hits: self.get_hits.load(Ordering::Relaxed),
+            sets: self.sets.stats(),
+            removes: self.removes.stats(),
+            scans: self.scans.stats(),
+        }
+    }
+}
+
+impl OpCounter {
+    fn time<T>(&self, call: impl FnOnce() -> Result<T>) -> Result<T> {
+        let started = Instant::now();
+        let result = call();
+        self.nanos
+            .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn stats(&self) -> OpStats {
+        OpStats {
+            calls: self.calls.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            total_time: Duration::from_nanos(self.nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl OpStats {
+    pub fn mean_time(&self) -> Duration {
+        if self.calls == 0 {
+            Duration::default()
+        } else {
+            // a u32 count would wrap past 2^32 calls
+            Duration::from_nanos((self.total_time.as_nanos() / u128::from(self.calls)) as u64)
+        }
+    }
+}
+
+/// Counts calls, errors and time spent into an [`EngineMetrics`].
+#[derive(Clone, Debug)]
+pub struct MetricsLayer {
+    metrics: Arc<EngineMetrics>,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<EngineMetrics>) -> Self {
+        MetricsLayer { metrics }
+    }
+}
+
+impl<E: KvsEngine> Layer<E> for MetricsLayer {
+    type Engine = Metrics<E>;
+
+    fn layer(&self, inner: E) -> Metrics<E> {
+        Metrics {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Metrics<E> {
+    inner: E,
+    metrics: Arc<EngineMetrics>,
+}
+
+impl<E: KvsEngine> KvsEngine for Metrics<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.metrics.sets.time(|| self.inner.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let value = self.metrics.gets.time(|| self.inner.get(key))?;
+        if value.is_some() {
+            self.metrics.get_hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.metrics.removes.time(|| self.inner.remove(key))
+    }
+
+    fn scan(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.metrics.scans.time(|| self.inner.scan(start, limit))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
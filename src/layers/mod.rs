@@ -0,0 +1,119 @@
+//! Middleware for engines.
+//!
+//! A [`Layer`] wraps a [`KvsEngine`] into another `KvsEngine` that adds
+//! behaviour around the calls it passes on, and an [`EngineBuilder`] stacks
+//! layers onto an engine:
+//!
+//! ```no_run
+//! # fn main() -> kvs::Result<()> {
+//! use kvs::layers::{EngineMetrics, MetricsLayer, ReadCacheLayer};
+//! use kvs::{EngineBuilder, KvStore};
+//!
+//! let metrics = EngineMetrics::new();
+//! let engine = EngineBuilder::new(KvStore::open("db")?)
+//!     .layer(ReadCacheLayer::new(10_000))
+//!     .layer(MetricsLayer::new(metrics.clone()))
+//!     .build();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ops::Bound;
+
+use crate::{KvsEngine, Result};
+
+pub mod cache;
+pub mod limits;
+pub mod metrics;
+pub mod trace;
+
+pub use self::cache::{ReadCache, ReadCacheLayer};
+pub use self::limits::{SizeLimit, SizeLimitLayer};
+pub use self::metrics::{EngineMetrics, Metrics, MetricsLayer, MetricsSnapshot, OpStats};
+pub use self::trace::{Tracing, TracingLayer};
+
+/// Wraps an engine into one that adds behaviour around its calls.
+pub trait Layer<E: KvsEngine> {
+    type Engine: KvsEngine;
+
+    fn layer(&self, inner: E) -> Self::Engine;
+}
+
+/// A layer that may be turned off, leaving the engine as it is.
+impl<E: KvsEngine, L: Layer<E>> Layer<E> for Option<L> {
+    type Engine = Either<L::Engine, E>;
+
+    fn layer(&self, inner: E) -> Self::Engine {
+        match self {
+            Some(layer) => Either::Left(layer.layer(inner)),
+            None => Either::Right(inner),
+        }
+    }
+}
+
+/// Stacks layers onto an engine. Every layer wraps the ones added before it,
+/// so the last one added sees calls first.
+#[derive(Debug)]
+pub struct EngineBuilder<E> {
+    engine: E,
+}
+
+impl<E: KvsEngine> EngineBuilder<E> {
+    pub fn new(engine: E) -> Self {
+        EngineBuilder { engine }
+    }
+
+    pub fn layer<L: Layer<E>>(self, layer: L) -> EngineBuilder<L::Engine> {
+        EngineBuilder {
+            engine: layer.layer(self.engine),
+        }
+    }
+
+    pub fn build(self) -> E {
+        self.engine
+    }
+}
+
+/// One of two engines, as picked by an optional layer.
+#[derive(Clone, Debug)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<A: KvsEngine, B: KvsEngine> KvsEngine for Either<A, B> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        match self {
+            Either::Left(engine) => engine.set(key, value),
+            Either::Right(engine) => engine.set(key, value),
+        }
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self {
+            Either::Left(engine) => engine.get(key),
+            Either::Right(engine) => engine.get(key),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self {
+            Either::Left(engine) => engine.remove(key),
+            Either::Right(engine) => engine.remove(key),
+        }
+    }
+
+    fn scan(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        match self {
+            Either::Left(engine) => engine.scan(start, limit),
+            Either::Right(engine) => engine.scan(start, limit),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        match self {
+            Either::Left(engine) => engine.flush(),
+            Either::Right(engine) => engine.flush(),
+        }
+    }
+}
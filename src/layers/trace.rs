@@ -0,0 +1,76 @@
+use std::ops::Bound;
+use std::time::Instant;
+
+use slog::{debug, Logger};
+
+use crate::layers::Layer;
+use crate::{KvsEngine, Result};
+
+/// Logs every call at debug level with its key, outcome and duration.
+#[derive(Clone, Debug)]
+pub struct TracingLayer {
+    logger: Logger,
+}
+
+impl TracingLayer {
+    pub fn new(logger: Logger) -> Self {
+        TracingLayer { logger }
+    }
+}
+
+impl<E: KvsEngine> Layer<E> for TracingLayer {
+    type Engine = Tracing<E>;
+
+    fn layer(&self, inner: E) -> Tracing<E> {
+        Tracing {
+            inner,
+            logger: self.logger.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Tracing<E> {
+    inner: E,
+    logger: Logger,
+}
+
+impl<E> Tracing<E> {
+    fn trace<T>(&self, op: &str, key: &str, call: impl FnOnce() -> Result<T>) -> Result<T> {
+        let started = Instant::now();
+        let result = call();
+        let elapsed_us = started.elapsed().as_micros() as u64;
+        match &result {
+            Ok(_) => debug!(self.logger, "{}", op; "key" => key, "elapsed_us" => elapsed_us),
+            Err(e) => debug!(self.logger, "{} failed", op;
+                             "key" => key, "elapsed_us" => elapsed_us, "error" => %e),
+        }
+        result
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Tracing<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.trace("set", &key.clone(), || self.inner.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.trace("get", &key.clone(), || self.inner.get(key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.trace("remove", &key.clone(), || self.inner.remove(key))
+    }
+
+    fn scan(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let from = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => key.clone(),
+            Bound::Unbounded => String::new(),
+        };
+        self.trace("scan", &from, || self.inner.scan(start, limit))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
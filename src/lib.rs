@@ -4,18 +4,43 @@
 #[macro_use]
 extern crate serde_derive;
 
+pub use async_server::AsyncKvsServer;
+pub use auth::Acl;
 pub use client::KvsClient;
-pub use engines::{InMemEngine, KvStore, KvsEngine, SledKvsEngine};
-pub use error::Result;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use engines::UringFs;
+pub use engines::{
+    AsyncKvsEngine, BlockingEngine, EncryptionKey, FileSystem, InMemEngine, KvStore,
+    KvStoreOptions, KvStoreStats, KvsEngine, MemFs, PooledEngine, SegmentFile, SledKvsEngine,
+    StdFs,
+};
+pub use error::{KvsError, Result};
+pub use http::HttpGateway;
+pub use layers::{EngineBuilder, Layer};
+pub use memcached::MemcachedServer;
+pub use migrate::{Migration, MigrationSummary};
+pub use resp::RespServer;
 pub use server::KvsServer;
+pub use shutdown::ShutdownHandle;
 pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use tls::{ClientTls, ServerTls};
 
+mod async_server;
+mod auth;
 mod client;
 pub mod common;
+pub mod dump;
 mod engines;
 mod error;
+mod http;
+pub mod layers;
+mod memcached;
+mod migrate;
+mod resp;
 mod server;
+mod shutdown;
 mod thread_pool;
+mod tls;
 
 use std::io::Cursor;
 use std::path::PathBuf;
@@ -52,8 +77,11 @@ openraft::declare_raft_types!(
     Entry = openraft::Entry<TypeConfig>, SnapshotData = Cursor<Vec<u8>>, AsyncRuntime = TokioRuntime
 );
 
-pub type LogStore = Adaptor<TypeConfig, Arc<Store<InMemEngine>>>;
-pub type StateMachineStore = Adaptor<TypeConfig, Arc<Store<InMemEngine>>>;
+/// Engine backing the raft state machine, driven through [`AsyncKvsEngine`].
+pub type StateMachineEngine = BlockingEngine<InMemEngine>;
+
+pub type LogStore = Adaptor<TypeConfig, Arc<Store<StateMachineEngine>>>;
+pub type StateMachineStore = Adaptor<TypeConfig, Arc<Store<StateMachineEngine>>>;
 pub type Raft = openraft::Raft<TypeConfig, Network, LogStore, StateMachineStore>;
 
 pub mod typ {
@@ -86,7 +114,7 @@ pub async fn start_example_raft_node(node_id: NodeId, http_addr: String) -> std:
     let config = Arc::new(config.validate().unwrap());
 
     // let kvengine = KvStore::open(format!("./raft-{}", node_id)).unwrap();
-    let kvengine = InMemEngine::open(PathBuf::from("."));
+    let kvengine = BlockingEngine::new(InMemEngine::open(PathBuf::from(".")));
 
     // Create a instance of where the Raft data will be stored.
     let store = Arc::new(Store::new(kvengine));
@@ -0,0 +1,511 @@
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use slog::{info, warn, Logger};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::layers::SizeLimitLayer;
+use crate::{KvsEngine, KvsError, Result};
+
+/// Longest command line, as in memcached.
+const MAX_LINE_LEN: u64 = 2048;
+/// Longest key, as in memcached.
+const MAX_KEY_LEN: usize = 250;
+/// Largest data block when there's no value limit, memcached's default
+/// item size.
+const MAX_ITEM_SIZE: u64 = 1024 * 1024;
+/// Exptimes up to this many seconds are relative, later ones are Unix times.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+/// Locks that serialize read-modify-write commands, picked by key hash.
+const LOCK_STRIPES: usize = 64;
+
+/// Starts an engine value holding an item with flags or an expiry, or data
+/// that isn't UTF-8. The rest is `<flags> <expires> <base64 data>`.
+const ITEM_MARKER: &str = "\0mc1 ";
+
+/// Serves a `KvsEngine` to memcached clients over the ASCII protocol.
+///
+/// Supports get, gets, set, add, replace, cas, delete, incr, decr, version
+/// and quit. An item's data is stored as the engine value when it has no
+/// flags or expiry, so other protocols see it as is, and is otherwise
+/// wrapped with them. Expired items read as missing until overwritten or
+/// deleted. Commands that read before they write hold a lock for the key,
+/// which keeps them atomic against this server's connections but not
+/// against writers using other protocols.
+pub struct MemcachedServer<E: KvsEngine> {
+    addr: SocketAddr,
+    engine: E,
+    logger: Logger,
+    limits: SizeLimitLayer,
+}
+
+impl<E: KvsEngine> MemcachedServer<E> {
+    pub fn new(addr: SocketAddr, engine: E, logger: Logger) -> Self {
+        MemcachedServer {
+            addr,
+            engine,
+            logger,
+            limits: SizeLimitLayer::default(),
+        }
+    }
+
+    /// Turns away keys or data over these sizes, skipping the data rather
+    /// than reading it.
+    pub fn with_limits(mut self, limits: SizeLimitLayer) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Binds the address and serves connections until accepting fails.
+    /// Engine calls run on the runtime's blocking pool.
+    pub async fn run(self) -> Result<()> {
+        let listener = TcpListener::bind(self.addr).await?;
+        info!(self.logger, "Started memcached listener on {}", self.addr);
+        let store = Store {
+            engine: self.engine,
+            locks: Arc::new((0..LOCK_STRIPES).map(|_| Mutex::new(())).collect()),
+        };
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let store = store.clone();
+            let limits = self.limits;
+            let logger = self.logger.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_connection(store, stream, limits).await {
+                    warn!(logger, "Error serving memcached connection"; "peer" => %peer, "error" => %e);
+                }
+            });
+        }
+    }
+}
+
+async fn serve_connection<E: KvsEngine>(
+    store: Store<E>,
+    stream: TcpStream,
+    limits: SizeLimitLayer,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    loop {
+        let line = match read_line(&mut reader).await? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        let (command, noreply) = match parse(&line) {
+            Ok(parsed) => parsed,
+            Err(reply) => {
+                writer.write_all(reply.as_bytes()).await?;
+                writer.flush().await?;
+                continue;
+            }
+        };
+
+        let reply = match command {
+            Command::Quit => return Ok(()),
+            Command::Version => format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+            Command::Store(request, bytes) => {
+                let max_value_size = limits
+                    .max_value_size()
+                    .map_or(MAX_ITEM_SIZE, |limit| limit as u64);
+                let too_large = match limits.max_key_size() {
+                    Some(limit) if request.key.len() > limit => {
+                        Some("CLIENT_ERROR key too long\r\n")
+                    }
+                    _ if bytes > max_value_size => {
+                        Some("SERVER_ERROR object too large for cache\r\n")
+                    }
+                    _ => None,
+                };
+                let len = match bytes.checked_add(2) {
+                    Some(len) => len,
+                    None => {
+                        // no block that long can ever end, so there's no
+                        // skipping it
+                        writer
+                            .write_all(b"SERVER_ERROR object too large for cache\r\n")
+                            .await?;
+                        writer.flush().await?;
+                        return Ok(());
+                    }
+                };
+                let mut data = Vec::new();
+                let mut chunk = (&mut reader).take(len);
+                if too_large.is_some() {
+                    tokio::io::copy(&mut chunk, &mut tokio::io::sink()).await?;
+                } else {
+                    chunk.read_to_end(&mut data).await?;
+                }
+                if chunk.limit() > 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+
+                match too_large {
+                    Some(error) => error.as_bytes().to_vec(),
+                    None if !data.ends_with(b"\r\n") => {
+                        // the client and server no longer agree where commands start
+                        writer.write_all(b"CLIENT_ERROR bad data chunk\r\n").await?;
+                        writer.flush().await?;
+                        return Ok(());
+                    }
+                    None => {
+                        data.truncate(data.len() - 2);
+                        store.call(move |store| store.store(request, data)).await
+                    }
+                }
+            }
+            Command::Get { keys, cas } => store.call(move |store| store.get(keys, cas)).await,
+            Command::Delete { key } => store.call(move |store| store.delete(key)).await,
+            Command::Arith { key, delta, incr } => {
+                store.call(move |store| store.arith(key, delta, incr)).await
+            }
+        };
+
+        if !noreply {
+            writer.write_all(&reply).await?;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+/// A line without its `\r\n`, or `None` at the end of the stream.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(failure::err_msg("Command line too long or cut off"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+enum Command {
+    Get {
+        keys: Vec<String>,
+        /// `gets`, which adds each item's cas unique.
+        cas: bool,
+    },
+    /// A storage command and the length of the data block after it.
+    Store(StoreRequest, u64),
+    Delete {
+        key: String,
+    },
+    Arith {
+        key: String,
+        delta: u64,
+        incr: bool,
+    },
+    Version,
+    Quit,
+}
+
+struct StoreRequest {
+    key: String,
+    mode: StoreMode,
+    flags: u32,
+    exptime: i64,
+}
+
+#[derive(Clone, Copy)]
+enum StoreMode {
+    Set,
+    Add,
+    Replace,
+    /// Stores only if the item's cas unique is still this.
+    Cas(u64),
+}
+
+const BAD_FORMAT: &str = "CLIENT_ERROR bad command line format\r\n";
+
+/// Parses a command line, returning the command and whether it asked for
+/// no reply, or the error line to send.
+fn parse(line: &[u8]) -> std::result::Result<(Command, bool), &'static str> {
+    let line = std::str::from_utf8(line).map_err(|_| BAD_FORMAT)?;
+    let mut words: Vec<&str> = line.split_ascii_whitespace().collect();
+    let noreply = words.last() == Some(&"noreply");
+    if noreply {
+        words.pop();
+    }
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Err("ERROR\r\n"),
+    };
+
+    let command = match (name, args) {
+        ("get", keys) | ("gets", keys) if !keys.is_empty() => Command::Get {
+            keys: keys
+                .iter()
+                .map(|k| key(k))
+                .collect::<std::result::Result<_, _>>()?,
+            cas: name == "gets",
+        },
+        ("set", [k, flags, exptime, bytes])
+        | ("add", [k, flags, exptime, bytes])
+        | ("replace", [k, flags, exptime, bytes])
+        | ("cas", [k, flags, exptime, bytes, _]) => {
+            let mode = match args {
+                [.., unique] if name == "cas" => StoreMode::Cas(number(unique)?),
+                _ if name == "add" => StoreMode::Add,
+                _ if name == "replace" => StoreMode::Replace,
+                _ => StoreMode::Set,
+            };
+            let request = StoreRequest {
+                key: key(k)?,
+                mode,
+                flags: number(flags)?,
+                exptime: number(exptime)?,
+            };
+            Command::Store(request, number(bytes)?)
+        }
+        ("delete", [k]) => Command::Delete { key: key(k)? },
+        ("incr", [k, delta]) | ("decr", [k, delta]) => Command::Arith {
+            key: key(k)?,
+            delta: delta
+                .parse()
+                .map_err(|_| "CLIENT_ERROR invalid numeric delta argument\r\n")?,
+            incr: name == "incr",
+        },
+        ("version", []) => Command::Version,
+        ("quit", []) => Command::Quit,
+        _ => return Err("ERROR\r\n"),
+    };
+    Ok((command, noreply))
+}
+
+fn key(key: &str) -> std::result::Result<String, &'static str> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(BAD_FORMAT);
+    }
+    Ok(key.to_owned())
+}
+
+fn number<T: FromStr>(word: &str) -> std::result::Result<T, &'static str> {
+    word.parse().map_err(|_| BAD_FORMAT)
+}
+
+/// What memcached stores for a key.
+struct Item {
+    flags: u32,
+    /// Unix time it expires at, or 0 if it doesn't.
+    expires: u64,
+    data: Vec<u8>,
+}
+
+impl Item {
+    fn decode(value: String) -> Item {
+        if let Some(rest) = value.strip_prefix(ITEM_MARKER) {
+            let mut fields = rest.splitn(3, ' ');
+            if let (Some(flags), Some(expires), Some(data)) =
+                (fields.next(), fields.next(), fields.next())
+            {
+                if let (Ok(flags), Ok(expires), Ok(data)) =
+                    (flags.parse(), expires.parse(), STANDARD.decode(data))
+                {
+                    return Item {
+                        flags,
+                        expires,
+                        data,
+                    };
+                }
+            }
+        }
+        Item {
+            flags: 0,
+            expires: 0,
+            data: value.into_bytes(),
+        }
+    }
+
+    fn encode(&self) -> String {
+        if self.flags == 0 && self.expires == 0 && !self.data.starts_with(ITEM_MARKER.as_bytes()) {
+            if let Ok(data) = std::str::from_utf8(&self.data) {
+                return data.to_owned();
+            }
+        }
+        format!(
+            "{}{} {} {}",
+            ITEM_MARKER,
+            self.flags,
+            self.expires,
+            STANDARD.encode(&self.data)
+        )
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+
+    /// Changes whenever the item does, short of it being set back to what
+    /// it was.
+    fn cas(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (self.flags, self.expires, &self.data).hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// The Unix time an exptime means, 0 for never.
+fn expires_at(exptime: i64, now: u64) -> u64 {
+    match exptime {
+        0 => 0,
+        // already expired
+        exptime if exptime < 0 => 1,
+        exptime if exptime <= MAX_RELATIVE_EXPTIME => now + exptime as u64,
+        exptime => exptime as u64,
+    }
+}
+
+#[derive(Clone)]
+struct Store<E> {
+    engine: E,
+    locks: Arc<Vec<Mutex<()>>>,
+}
+
+impl<E: KvsEngine> Store<E> {
+    /// Runs `f` on the blocking pool, turning a failure into a server error
+    /// reply.
+    fn call<F>(&self, f: F) -> impl Future<Output = Vec<u8>>
+    where
+        F: FnOnce(&Store<E>) -> Result<Vec<u8>> + Send + 'static,
+    {
+        let store = self.clone();
+        async move {
+            let error = match tokio::task::spawn_blocking(move || f(&store)).await {
+                Ok(Ok(reply)) => return reply,
+                Ok(Err(e)) => e.to_string(),
+                Err(e) => e.to_string(),
+            };
+            format!("SERVER_ERROR {}\r\n", error.replace(['\r', '\n'], " ")).into_bytes()
+        }
+    }
+
+    fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let stripe = hasher.finish() as usize % self.locks.len();
+        self.locks[stripe].lock().unwrap()
+    }
+
+    fn load(&self, key: &str, now: u64) -> Result<Option<Item>> {
+        Ok(self
+            .engine
+            .get(key.to_owned())?
+            .map(Item::decode)
+            .filter(|item| !item.expired(now)))
+    }
+
+    fn get(&self, keys: Vec<String>, cas: bool) -> Result<Vec<u8>> {
+        let now = now();
+        let mut reply = Vec::new();
+        for key in keys {
+            if let Some(item) = self.load(&key, now)? {
+                write!(reply, "VALUE {} {} {}", key, item.flags, item.data.len())?;
+                if cas {
+                    write!(reply, " {}", item.cas())?;
+                }
+                reply.extend_from_slice(b"\r\n");
+                reply.extend_from_slice(&item.data);
+                reply.extend_from_slice(b"\r\n");
+            }
+        }
+        reply.extend_from_slice(b"END\r\n");
+        Ok(reply)
+    }
+
+    fn store(&self, request: StoreRequest, data: Vec<u8>) -> Result<Vec<u8>> {
+        let now = now();
+        let item = Item {
+            flags: request.flags,
+            expires: expires_at(request.exptime, now),
+            data,
+        };
+
+        let _lock = self.lock(&request.key);
+        let stored = match request.mode {
+            StoreMode::Set => true,
+            StoreMode::Add => self.load(&request.key, now)?.is_none(),
+            StoreMode::Replace => self.load(&request.key, now)?.is_some(),
+            StoreMode::Cas(unique) => match self.load(&request.key, now)? {
+                None => return Ok(b"NOT_FOUND\r\n".to_vec()),
+                Some(old) if old.cas() != unique => return Ok(b"EXISTS\r\n".to_vec()),
+                Some(_) => true,
+            },
+        };
+        if !stored {
+            return Ok(b"NOT_STORED\r\n".to_vec());
+        }
+        self.engine.set(request.key, item.encode())?;
+        Ok(b"STORED\r\n".to_vec())
+    }
+
+    fn delete(&self, key: String) -> Result<Vec<u8>> {
+        let _lock = self.lock(&key);
+        if self.load(&key, now())?.is_none() {
+            return Ok(b"NOT_FOUND\r\n".to_vec());
+        }
+        match self.engine.remove(key) {
+            Ok(()) => Ok(b"DELETED\r\n".to_vec()),
+            Err(e) if e.downcast_ref() == Some(&KvsError::KeyNotFound) => {
+                Ok(b"NOT_FOUND\r\n".to_vec())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// incr wraps around at 2^64, decr stops at 0.
+    fn arith(&self, key: String, delta: u64, incr: bool) -> Result<Vec<u8>> {
+        let _lock = self.lock(&key);
+        let mut item = match self.load(&key, now())? {
+            Some(item) => item,
+            None => return Ok(b"NOT_FOUND\r\n".to_vec()),
+        };
+        let value = match std::str::from_utf8(&item.data)
+            .ok()
+            .and_then(|data| data.trim_end().parse::<u64>().ok())
+        {
+            Some(value) => value,
+            None => {
+                return Ok(
+                    b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec(),
+                )
+            }
+        };
+        let value = if incr {
+            value.wrapping_add(delta)
+        } else {
+            value.saturating_sub(delta)
+        };
+
+        item.data = value.to_string().into_bytes();
+        self.engine.set(key, item.encode())?;
+        Ok(format!("{}\r\n", value).into_bytes())
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{BufReader, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use crate::{KvsEngine, Result};
+
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// How far a migration got, persisted after every batch.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    /// Last key copied. Keys are copied in order, so everything up to and
+    /// including it is already in the destination.
+    last_key: Option<String>,
+    copied: u64,
+}
+
+impl Checkpoint {
+    fn load(path: &Path) -> Result<Option<Checkpoint>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let reader = BufReader::new(File::open(path)?);
+        Ok(Some(serde_json::from_reader(reader)?))
+    }
+
+    /// Replaces the checkpoint file, so an interruption leaves either the old
+    /// checkpoint or the new one.
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Key count and checksum of an engine's whole content.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MigrationSummary {
+    pub keys: u64,
+    pub checksum: u64,
+}
+
+/// Copies every key from one engine into another, in key order and in batches.
+///
+/// Progress is saved to a checkpoint file after every batch. Creating a
+/// `Migration` with the same checkpoint file after an interruption resumes
+/// after the last batch saved. The checkpoint must live outside both engine
+/// directories.
+pub struct Migration<S: KvsEngine, D: KvsEngine> {
+    source: S,
+    dest: D,
+    checkpoint_path: PathBuf,
+    checkpoint: Checkpoint,
+    batch_size: usize,
+    done: bool,
+}
+
+impl<S: KvsEngine, D: KvsEngine> Migration<S, D> {
+    /// Starts a migration, or resumes the one recorded in `checkpoint_path`.
+    ///
+    /// A fresh migration requires an empty destination.
+    pub fn new(source: S, dest: D, checkpoint_path: PathBuf) -> Result<Self> {
+        let checkpoint = match Checkpoint::load(&checkpoint_path)? {
+            Some(checkpoint) => checkpoint,
+            None => {
+                if !dest.scan(Bound::Unbounded, 1)?.is_empty() {
+                    return Err(failure::err_msg(
+                        "Destination is not empty and there is no checkpoint to resume from",
+                    ));
+                }
+                Checkpoint::default()
+            }
+        };
+
+        Ok(Migration {
+            source,
+            dest,
+            checkpoint_path,
+            checkpoint,
+            batch_size: DEFAULT_BATCH_SIZE,
+            done: false,
+        })
+    }
+
+    /// Sets how many keys are copied between two checkpoints.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Number of keys copied so far, including before a resume.
+    pub fn copied(&self) -> u64 {
+        self.checkpoint.copied
+    }
+
+    /// Copies the next batch and saves the checkpoint. Returns `false` once
+    /// every key has been copied.
+    pub fn step(&mut self) -> Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+
+        let start = match &self.checkpoint.last_key {
+            Some(key) => Bound::Excluded(key.clone()),
+            None => Bound::Unbounded,
+        };
+        let batch = self.source.scan(start, self.batch_size)?;
+        if batch.len() < self.batch_size {
+            self.done = true;
+        }
+        if batch.is_empty() {
+            return Ok(false);
+        }
+
+        self.checkpoint.copied += batch.len() as u64;
+        for (key, value) in batch {
+            self.checkpoint.last_key = Some(key.clone());
+            self.dest.set(key, value)?;
+        }
+        self.checkpoint.save(&self.checkpoint_path)?;
+
+        Ok(true)
+    }
+
+    /// Copies whatever is left, checks that both engines hold the same data
+    /// and removes the checkpoint.
+    pub fn run(mut self) -> Result<MigrationSummary> {
+        while self.step()? {}
+        self.finish()
+    }
+
+    /// Checks that both engines hold the same data and removes the
+    /// checkpoint. Every batch must have been copied.
+    pub fn finish(self) -> Result<MigrationSummary> {
+        if !self.done {
+            return Err(failure::err_msg("Migration is not complete"));
+        }
+
+        let source = summarize(&self.source, self.batch_size)?;
+        let dest = summarize(&self.dest, self.batch_size)?;
+        if source != dest {
+            return Err(failure::err_msg(format!(
+                "Verification failed: source has {} keys (checksum {:016x}), \
+                 destination has {} keys (checksum {:016x})",
+                source.keys, source.checksum, dest.keys, dest.checksum
+            )));
+        }
+
+        // an empty source never saves a checkpoint
+        if self.checkpoint_path.exists() {
+            fs::remove_file(&self.checkpoint_path)?;
+        }
+        Ok(source)
+    }
+}
+
+/// Counts and hashes every pair of `engine` in key order.
+fn summarize<E: KvsEngine>(engine: &E, batch_size: usize) -> Result<MigrationSummary> {
+    let mut hasher = DefaultHasher::new();
+    let mut keys = 0;
+    let mut start = Bound::Unbounded;
+
+    loop {
+        let batch = engine.scan(start, batch_size)?;
+        let last = match batch.last() {
+            Some((key, _)) => key.clone(),
+            None => break,
+        };
+        for (key, value) in &batch {
+            // length-prefixed, so ("ab", "c") and ("a", "bc") differ
+            hasher.write_usize(key.len());
+            hasher.write(key.as_bytes());
+            hasher.write_usize(value.len());
+            hasher.write(value.as_bytes());
+        }
+        keys += batch.len() as u64;
+        start = Bound::Excluded(last);
+    }
+
+    Ok(MigrationSummary {
+        keys,
+        checksum: hasher.finish(),
+    })
+}
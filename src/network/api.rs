@@ -10,9 +10,9 @@ use web::Json;
 
 use crate::app::App;
 use crate::storage::Request;
-use crate::InMemEngine;
-use crate::KvsEngine;
+use crate::AsyncKvsEngine;
 use crate::NodeId;
+use crate::StateMachineEngine;
 
 /**
  * Application API
@@ -25,7 +25,7 @@ use crate::NodeId;
  */
 #[post("/write")]
 pub async fn write(
-    app: Data<App<InMemEngine>>,
+    app: Data<App<StateMachineEngine>>,
     req: Json<Request>,
 ) -> actix_web::Result<impl Responder> {
     let response = app.raft.client_write(req.0).await;
@@ -34,12 +34,16 @@ pub async fn write(
 
 #[post("/read")]
 pub async fn read(
-    app: Data<App<InMemEngine>>,
+    app: Data<App<StateMachineEngine>>,
     req: Json<String>,
 ) -> actix_web::Result<impl Responder> {
-    let state_machine = app.store.state_machine.read().await;
+    // clone the engine handle so the state machine lock is not held across the read
+    let data = app.store.state_machine.read().await.data.clone();
     let key = req.0;
-    let value = state_machine.data.get(key.clone()).unwrap();
+    let value = data
+        .get(key)
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
 
     let res: Result<String, Infallible> = Ok(value.unwrap_or_default());
     Ok(Json(res))
@@ -47,16 +51,19 @@ pub async fn read(
 
 #[post("/consistent_read")]
 pub async fn consistent_read(
-    app: Data<App<InMemEngine>>,
+    app: Data<App<StateMachineEngine>>,
     req: Json<String>,
 ) -> actix_web::Result<impl Responder> {
     let ret = app.raft.is_leader().await;
 
     match ret {
         Ok(_) => {
-            let state_machine = app.store.state_machine.read().await;
+            let data = app.store.state_machine.read().await.data.clone();
             let key = req.0;
-            let value = state_machine.data.get(key.clone()).unwrap();
+            let value = data
+                .get(key)
+                .await
+                .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
 
             let res: Result<String, RaftError<NodeId, CheckIsLeaderError<NodeId, BasicNode>>> =
                 Ok(value.unwrap_or_default());
@@ -11,9 +11,8 @@ use openraft::BasicNode;
 use openraft::RaftMetrics;
 
 use crate::app::App;
-use crate::InMemEngine;
-use crate::KvsEngine;
 use crate::NodeId;
+use crate::StateMachineEngine;
 
 // --- Cluster management
 
@@ -24,7 +23,7 @@ use crate::NodeId;
 /// (by calling `change-membership`)
 #[post("/add-learner")]
 pub async fn add_learner(
-    app: Data<App<InMemEngine>>,
+    app: Data<App<StateMachineEngine>>,
     req: Json<(NodeId, String)>,
 ) -> actix_web::Result<impl Responder> {
     let node_id = req.0 .0;
@@ -38,7 +37,7 @@ pub async fn add_learner(
 /// Changes specified learners to members, or remove members.
 #[post("/change-membership")]
 pub async fn change_membership(
-    app: Data<App<InMemEngine>>,
+    app: Data<App<StateMachineEngine>>,
     req: Json<BTreeSet<NodeId>>,
 ) -> actix_web::Result<impl Responder> {
     let res = app.raft.change_membership(req.0, false).await;
@@ -47,7 +46,7 @@ pub async fn change_membership(
 
 /// Initialize a single-node cluster.
 #[post("/init")]
-pub async fn init(app: Data<App<InMemEngine>>) -> actix_web::Result<impl Responder> {
+pub async fn init(app: Data<App<StateMachineEngine>>) -> actix_web::Result<impl Responder> {
     let mut nodes = BTreeMap::new();
     nodes.insert(
         app.id,
@@ -61,7 +60,7 @@ pub async fn init(app: Data<App<InMemEngine>>) -> actix_web::Result<impl Respond
 
 /// Get the latest metrics of the cluster
 #[get("/metrics")]
-pub async fn metrics(app: Data<App<InMemEngine>>) -> actix_web::Result<impl Responder> {
+pub async fn metrics(app: Data<App<StateMachineEngine>>) -> actix_web::Result<impl Responder> {
     let metrics = app.raft.metrics().borrow().clone();
 
     let res: Result<RaftMetrics<NodeId, BasicNode>, Infallible> = Ok(metrics);
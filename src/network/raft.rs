@@ -7,16 +7,15 @@ use openraft::raft::InstallSnapshotRequest;
 use openraft::raft::VoteRequest;
 
 use crate::app::App;
-use crate::InMemEngine;
-use crate::KvsEngine;
 use crate::NodeId;
+use crate::StateMachineEngine;
 use crate::TypeConfig;
 
 // --- Raft communication
 
 #[post("/raft-vote")]
 pub async fn vote(
-    app: Data<App<InMemEngine>>,
+    app: Data<App<StateMachineEngine>>,
     req: Json<VoteRequest<NodeId>>,
 ) -> actix_web::Result<impl Responder> {
     let res = app.raft.vote(req.0).await;
@@ -25,7 +24,7 @@ pub async fn vote(
 
 #[post("/raft-append")]
 pub async fn append(
-    app: Data<App<InMemEngine>>,
+    app: Data<App<StateMachineEngine>>,
     req: Json<AppendEntriesRequest<TypeConfig>>,
 ) -> actix_web::Result<impl Responder> {
     let res = app.raft.append_entries(req.0).await;
@@ -34,7 +33,7 @@ pub async fn append(
 
 #[post("/raft-snapshot")]
 pub async fn snapshot(
-    app: Data<App<InMemEngine>>,
+    app: Data<App<StateMachineEngine>>,
     req: Json<InstallSnapshotRequest<TypeConfig>>,
 ) -> actix_web::Result<impl Responder> {
     let res = app.raft.install_snapshot(req.0).await;
@@ -0,0 +1,781 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use slog::{info, warn, Logger};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::common::MAX_FIELD_LEN;
+use crate::layers::SizeLimitLayer;
+use crate::{KvsEngine, KvsError, Result};
+
+/// Longest bulk string a client may send, as in Redis.
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+/// Most arguments a command may have, as in Redis.
+const MAX_MULTIBULK_LEN: i64 = 1024 * 1024;
+/// Most bytes of arguments a command may have, as Redis's
+/// client-query-buffer-limit.
+const MAX_COMMAND_LEN: u64 = 1024 * 1024 * 1024;
+/// Longest line, which bounds inline commands.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+/// SCAN cursors a connection keeps before dropping the oldest.
+const MAX_CURSORS: usize = 128;
+
+const NO_EXPIRY: &str = "ERR keys don't expire in kvrs";
+
+/// Serves a `KvsEngine` to Redis clients, speaking RESP2 and, after
+/// `HELLO 3`, RESP3.
+///
+/// GET, SET, DEL, EXISTS, MGET, MSET, SCAN, PING and INFO work as in Redis.
+/// Keys never expire, so TTL answers -1 for every key and EXPIRE, or SET
+/// with EX or PX, fails. SET's NX and XX check with a get first, which other
+/// writers can race.
+pub struct RespServer<E: KvsEngine> {
+    addr: SocketAddr,
+    engine: E,
+    logger: Logger,
+    limits: SizeLimitLayer,
+}
+
+impl<E: KvsEngine> RespServer<E> {
+    pub fn new(addr: SocketAddr, engine: E, logger: Logger) -> Self {
+        RespServer {
+            addr,
+            engine,
+            logger,
+            limits: SizeLimitLayer::default(),
+        }
+    }
+
+    /// Turns away commands with an argument longer than the larger of the
+    /// limits set, skipping the argument rather than reading it.
+    pub fn with_limits(mut self, limits: SizeLimitLayer) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Binds the address and serves connections until accepting fails.
+    /// Engine calls run on the runtime's blocking pool.
+    pub async fn run(self) -> Result<()> {
+        let listener = TcpListener::bind(self.addr).await?;
+        info!(self.logger, "Started RESP listener on {}", self.addr);
+        let stats = Arc::new(Stats {
+            started: Instant::now(),
+            port: self.addr.port(),
+            connected: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            commands: AtomicU64::new(0),
+        });
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let engine = self.engine.clone();
+            let stats = stats.clone();
+            let logger = self.logger.clone();
+            let limits = self.limits;
+            tokio::spawn(async move {
+                stats.connected.fetch_add(1, Ordering::Relaxed);
+                if let Err(e) = serve_connection(engine, stream, limits, &stats).await {
+                    warn!(logger, "Error serving RESP connection"; "peer" => %peer, "error" => %e);
+                }
+                stats.connected.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    }
+}
+
+/// What INFO reports.
+struct Stats {
+    started: Instant,
+    port: u16,
+    connected: AtomicU64,
+    connections: AtomicU64,
+    commands: AtomicU64,
+}
+
+impl Stats {
+    fn info(&self, section: Option<&str>) -> String {
+        let sections = vec![
+            (
+                "server",
+                vec![
+                    ("kvrs_version", env!("CARGO_PKG_VERSION").to_owned()),
+                    ("redis_mode", "standalone".to_owned()),
+                    ("tcp_port", self.port.to_string()),
+                    (
+                        "uptime_in_seconds",
+                        self.started.elapsed().as_secs().to_string(),
+                    ),
+                ],
+            ),
+            (
+                "clients",
+                vec![(
+                    "connected_clients",
+                    self.connected.load(Ordering::Relaxed).to_string(),
+                )],
+            ),
+            (
+                "stats",
+                vec![
+                    (
+                        "total_connections_received",
+                        self.connections.load(Ordering::Relaxed).to_string(),
+                    ),
+                    (
+                        "total_commands_processed",
+                        self.commands.load(Ordering::Relaxed).to_string(),
+                    ),
+                ],
+            ),
+        ];
+
+        let mut info = String::new();
+        for (name, fields) in sections {
+            let wanted = match section {
+                None => true,
+                Some(section) => {
+                    section.eq_ignore_ascii_case(name)
+                        || ["all", "default", "everything"]
+                            .iter()
+                            .any(|all| section.eq_ignore_ascii_case(all))
+                }
+            };
+            if !wanted {
+                continue;
+            }
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            info.push_str(&format!("# {}{}\r\n", name[..1].to_uppercase(), &name[1..]));
+            for (field, value) in fields {
+                info.push_str(&format!("{}:{}\r\n", field, value));
+            }
+        }
+        info
+    }
+}
+
+/// A client broke the protocol, and the connection is closed after telling
+/// it so.
+#[derive(Debug)]
+struct ProtocolError(String);
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Protocol error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+fn protocol_error(message: &str) -> failure::Error {
+    ProtocolError(message.to_owned()).into()
+}
+
+/// A command was too large to run. Its arguments were read off the
+/// connection, so the connection carries on.
+#[derive(Debug)]
+struct TooLarge(String);
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    /// `$-1` in RESP2, `_` in RESP3.
+    Null,
+    Array(Vec<Reply>),
+    /// A flat array of keys and values in RESP2.
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Simple("OK")
+    }
+
+    fn error(message: impl fmt::Display) -> Reply {
+        // an error reply is a single line
+        Reply::Error(message.to_string().replace(['\r', '\n'], " "))
+    }
+
+    fn bulk(value: impl Into<Vec<u8>>) -> Reply {
+        Reply::Bulk(value.into())
+    }
+
+    fn encode(&self, version: u8, buf: &mut Vec<u8>) {
+        // writes to a Vec can't fail
+        let _ = match self {
+            Reply::Simple(s) => write!(buf, "+{}\r\n", s),
+            Reply::Error(e) => write!(buf, "-{}\r\n", e),
+            Reply::Integer(n) => write!(buf, ":{}\r\n", n),
+            Reply::Bulk(value) => {
+                let _ = write!(buf, "${}\r\n", value.len());
+                buf.extend_from_slice(value);
+                write!(buf, "\r\n")
+            }
+            Reply::Null if version >= 3 => write!(buf, "_\r\n"),
+            Reply::Null => write!(buf, "$-1\r\n"),
+            Reply::Array(items) => {
+                let _ = write!(buf, "*{}\r\n", items.len());
+                for item in items {
+                    item.encode(version, buf);
+                }
+                Ok(())
+            }
+            Reply::Map(pairs) => {
+                let _ = if version >= 3 {
+                    write!(buf, "%{}\r\n", pairs.len())
+                } else {
+                    write!(buf, "*{}\r\n", pairs.len() * 2)
+                };
+                for (key, value) in pairs {
+                    key.encode(version, buf);
+                    value.encode(version, buf);
+                }
+                Ok(())
+            }
+        };
+    }
+}
+
+impl From<Option<String>> for Reply {
+    fn from(value: Option<String>) -> Reply {
+        match value {
+            Some(value) => Reply::bulk(value),
+            None => Reply::Null,
+        }
+    }
+}
+
+async fn serve_connection<E: KvsEngine>(
+    engine: E,
+    stream: TcpStream,
+    limits: SizeLimitLayer,
+    stats: &Stats,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut session = Session {
+        id: stats.connections.fetch_add(1, Ordering::Relaxed) + 1,
+        version: 2,
+        quit: false,
+        cursors: BTreeMap::new(),
+        next_cursor: 1,
+    };
+
+    let mut buf = Vec::new();
+    loop {
+        let reply = match read_command(&mut reader, limits).await {
+            Ok(Some(args)) => {
+                stats.commands.fetch_add(1, Ordering::Relaxed);
+                session.execute(engine.clone(), args, stats).await
+            }
+            Ok(None) => return Ok(()),
+            Err(e) => match e.downcast::<TooLarge>() {
+                Ok(e) => Reply::error(format!("ERR {}", e)),
+                Err(e) => {
+                    return match e.downcast::<ProtocolError>() {
+                        Ok(e) => {
+                            buf.clear();
+                            Reply::error(format!("ERR {}", e)).encode(session.version, &mut buf);
+                            writer.write_all(&buf).await?;
+                            writer.flush().await?;
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    };
+                }
+            },
+        };
+        buf.clear();
+        reply.encode(session.version, &mut buf);
+        writer.write_all(&buf).await?;
+        if session.quit {
+            writer.flush().await?;
+            return Ok(());
+        }
+        // the replies to pipelined commands go out together
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+/// Reads the next command, either a multibulk array or an inline line of
+/// space-separated arguments, skipping empty ones. `None` if the client
+/// hangs up between commands.
+///
+/// An argument longer than the larger of the limits set, or
+/// `MAX_FIELD_LEN` if neither is, or past `MAX_COMMAND_LEN` in all, is
+/// skipped rather than buffered, and the command fails with `TooLarge`
+/// once it has been read.
+async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    limits: SizeLimitLayer,
+) -> Result<Option<Vec<Vec<u8>>>> {
+    // keys and values are the only arguments that get long, and which an
+    // argument is depends on the command
+    let max_arg_len = match (limits.max_key_size(), limits.max_value_size()) {
+        (Some(key), Some(value)) => key.max(value),
+        (Some(limit), None) | (None, Some(limit)) => limit,
+        (None, None) => MAX_FIELD_LEN,
+    } as u64;
+    loop {
+        let line = match read_line(reader).await? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        let args = if line.first() == Some(&b'*') {
+            let count = match parse_len(&line[1..]) {
+                Some(count) if count <= MAX_MULTIBULK_LEN => count,
+                _ => return Err(protocol_error("invalid multibulk length")),
+            };
+            let mut args = Vec::new();
+            let mut buffered = 0;
+            let mut rejected = None;
+            for _ in 0..count {
+                let line = read_line(reader)
+                    .await?
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                if line.first() != Some(&b'$') {
+                    return Err(protocol_error("expected '$'"));
+                }
+                let len = match parse_len(&line[1..]) {
+                    Some(len) if (0..=MAX_BULK_LEN).contains(&len) => len as u64,
+                    _ => return Err(protocol_error("invalid bulk length")),
+                };
+                let mut chunk = (&mut *reader).take(len + 2);
+                if len > max_arg_len || buffered + len > MAX_COMMAND_LEN {
+                    rejected.get_or_insert_with(|| {
+                        if len > max_arg_len {
+                            format!(
+                                "argument of {} bytes exceeds the limit of {}",
+                                len, max_arg_len
+                            )
+                        } else {
+                            "command too large".to_owned()
+                        }
+                    });
+                    tokio::io::copy(&mut chunk, &mut tokio::io::sink()).await?;
+                    if chunk.limit() > 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    continue;
+                }
+                let mut arg = Vec::new();
+                chunk.read_to_end(&mut arg).await?;
+                if (arg.len() as u64) < len + 2 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                if !arg.ends_with(b"\r\n") {
+                    return Err(protocol_error("invalid bulk terminator"));
+                }
+                arg.truncate(len as usize);
+                buffered += len;
+                args.push(arg);
+            }
+            if let Some(message) = rejected {
+                return Err(TooLarge(message).into());
+            }
+            args
+        } else {
+            line.split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect()
+        };
+
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+/// A line without its `\r\n`, or `None` at the end of the stream.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(if line.len() as u64 + 1 >= MAX_LINE_LEN {
+            protocol_error("too big inline request")
+        } else {
+            io::Error::from(io::ErrorKind::UnexpectedEof).into()
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> Option<i64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// What a connection remembers between commands.
+struct Session {
+    id: u64,
+    /// 2 or 3, as picked by HELLO.
+    version: u8,
+    /// Set by QUIT, to close the connection after the reply.
+    quit: bool,
+    /// The last key each open SCAN cursor returned.
+    cursors: BTreeMap<u64, String>,
+    next_cursor: u64,
+}
+
+impl Session {
+    async fn execute<E: KvsEngine>(
+        &mut self,
+        engine: E,
+        mut args: Vec<Vec<u8>>,
+        stats: &Stats,
+    ) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let args = args.split_off(1);
+
+        match name.as_str() {
+            "PING" => match args.as_slice() {
+                [] => Reply::Simple("PONG"),
+                [message] => Reply::bulk(message.clone()),
+                _ => wrong_arity(&name),
+            },
+            "HELLO" => self.hello(&args),
+            "QUIT" => {
+                self.quit = true;
+                Reply::ok()
+            }
+            "SELECT" => match args.as_slice() {
+                [db] if db.as_slice() == b"0" => Reply::ok(),
+                [_] => Reply::error("ERR DB index is out of range"),
+                _ => wrong_arity(&name),
+            },
+            "COMMAND" => Reply::Array(Vec::new()),
+            "INFO" => match args.as_slice() {
+                [] => Reply::bulk(stats.info(None)),
+                [section] => Reply::bulk(stats.info(Some(&String::from_utf8_lossy(section)))),
+                _ => wrong_arity(&name),
+            },
+            "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => Reply::error(NO_EXPIRY),
+            "SCAN" => self.scan(engine, &args).await,
+            "GET" | "SET" | "DEL" | "EXISTS" | "MGET" | "MSET" | "TTL" | "PTTL" => {
+                tokio::task::spawn_blocking(move || {
+                    execute(&engine, &name, args)
+                        .unwrap_or_else(|e| Reply::error(format!("ERR {}", e)))
+                })
+                .await
+                .unwrap_or_else(|e| Reply::error(format!("ERR {}", e)))
+            }
+            _ => Reply::error(format!(
+                "ERR unknown command '{}'",
+                name.to_ascii_lowercase()
+            )),
+        }
+    }
+
+    /// Switches the connection to the protocol version asked for, if any.
+    /// AUTH and SETNAME are ignored.
+    fn hello(&mut self, args: &[Vec<u8>]) -> Reply {
+        if let Some(version) = args.first() {
+            match parse_len(version) {
+                Some(version @ 2..=3) => self.version = version as u8,
+                _ => return Reply::error("NOPROTO unsupported protocol version"),
+            }
+        }
+
+        let field = |name: &str, value| (Reply::bulk(name), value);
+        Reply::Map(vec![
+            field("server", Reply::bulk("kvrs")),
+            field("version", Reply::bulk(env!("CARGO_PKG_VERSION"))),
+            field("proto", Reply::Integer(i64::from(self.version))),
+            field("id", Reply::Integer(self.id as i64)),
+            field("mode", Reply::bulk("standalone")),
+            field("role", Reply::bulk("master")),
+            field("modules", Reply::Array(Vec::new())),
+        ])
+    }
+
+    /// Pages through the engine in key order. A cursor stands for the last
+    /// key it returned, so every key present for the whole scan is returned
+    /// once, but cursors only work on the connection that got them.
+    async fn scan<E: KvsEngine>(&mut self, engine: E, args: &[Vec<u8>]) -> Reply {
+        let (cursor, options) = match args.split_first() {
+            Some((cursor, options)) => (cursor, options),
+            None => return wrong_arity("SCAN"),
+        };
+        let start = match parse_len(cursor) {
+            Some(0) => Bound::Unbounded,
+            Some(cursor) => match self.cursors.remove(&(cursor as u64)) {
+                Some(key) => Bound::Excluded(key),
+                None => return Reply::error("ERR invalid cursor"),
+            },
+            None => return Reply::error("ERR invalid cursor"),
+        };
+
+        let mut pattern = None;
+        let mut count = 10;
+        for option in options.chunks(2) {
+            match option {
+                [name, value] if name.eq_ignore_ascii_case(b"MATCH") => {
+                    pattern = Some(value.clone())
+                }
+                [name, value] if name.eq_ignore_ascii_case(b"COUNT") => match parse_len(value) {
+                    Some(value) if value > 0 => count = value as usize,
+                    _ => return Reply::error("ERR value is out of range, must be positive"),
+                },
+                _ => return Reply::error("ERR syntax error"),
+            }
+        }
+
+        // a pattern's literal start bounds the keys worth reading
+        let prefix = pattern.as_deref().map_or(&[][..], literal_prefix).to_vec();
+        let start = match start {
+            Bound::Unbounded if !prefix.is_empty() => {
+                Bound::Included(String::from_utf8_lossy(&prefix).into_owned())
+            }
+            start => start,
+        };
+        let keys = match tokio::task::spawn_blocking(move || engine.scan(start, count)).await {
+            Ok(Ok(pairs)) => pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>(),
+            Ok(Err(e)) => return Reply::error(format!("ERR {}", e)),
+            Err(e) => return Reply::error(format!("ERR {}", e)),
+        };
+
+        let done = keys.len() < count
+            || !keys
+                .last()
+                .is_some_and(|key| key.as_bytes().starts_with(&prefix));
+        let next = if done {
+            0
+        } else {
+            let cursor = self.next_cursor;
+            self.next_cursor += 1;
+            self.cursors.insert(cursor, keys[keys.len() - 1].clone());
+            if self.cursors.len() > MAX_CURSORS {
+                let oldest = *self.cursors.keys().next().unwrap();
+                self.cursors.remove(&oldest);
+            }
+            cursor
+        };
+
+        let keys = keys
+            .into_iter()
+            .filter(|key| match &pattern {
+                Some(pattern) => glob_match(pattern, key.as_bytes()),
+                None => true,
+            })
+            .map(Reply::bulk)
+            .collect();
+        Reply::Array(vec![Reply::bulk(next.to_string()), Reply::Array(keys)])
+    }
+}
+
+fn wrong_arity(name: &str) -> Reply {
+    Reply::error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+/// Runs one of the commands that only need the engine.
+fn execute<E: KvsEngine>(engine: &E, name: &str, args: Vec<Vec<u8>>) -> Result<Reply> {
+    let args = match args
+        .into_iter()
+        .map(String::from_utf8)
+        .collect::<std::result::Result<Vec<_>, _>>()
+    {
+        Ok(args) => args,
+        Err(_) => return Ok(Reply::error("ERR keys and values must be UTF-8")),
+    };
+
+    let reply = match (name, args.as_slice()) {
+        ("GET", [key]) => Reply::from(engine.get(key.clone())?),
+        ("SET", [key, value, options @ ..]) => set(engine, key, value, options)?,
+        ("DEL", keys) if !keys.is_empty() => {
+            let mut removed = 0;
+            for key in keys {
+                match engine.remove(key.clone()) {
+                    Ok(()) => removed += 1,
+                    Err(e) if e.downcast_ref() == Some(&KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Reply::Integer(removed)
+        }
+        ("EXISTS", keys) if !keys.is_empty() => {
+            let mut found = 0;
+            for key in keys {
+                if engine.get(key.clone())?.is_some() {
+                    found += 1;
+                }
+            }
+            Reply::Integer(found)
+        }
+        ("MGET", keys) if !keys.is_empty() => Reply::Array(
+            keys.iter()
+                .map(|key| Ok(Reply::from(engine.get(key.clone())?)))
+                .collect::<Result<_>>()?,
+        ),
+        ("MSET", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            for pair in pairs.chunks(2) {
+                engine.set(pair[0].clone(), pair[1].clone())?;
+            }
+            Reply::ok()
+        }
+        ("TTL", [key]) | ("PTTL", [key]) => match engine.get(key.clone())? {
+            Some(_) => Reply::Integer(-1),
+            None => Reply::Integer(-2),
+        },
+        _ => wrong_arity(name),
+    };
+    Ok(reply)
+}
+
+fn set<E: KvsEngine>(engine: &E, key: &str, value: &str, options: &[String]) -> Result<Reply> {
+    let (mut nx, mut xx, mut get) = (false, false, false);
+    for option in options {
+        match option.to_ascii_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "GET" => get = true,
+            "KEEPTTL" => {}
+            "EX" | "PX" | "EXAT" | "PXAT" => return Ok(Reply::error(NO_EXPIRY)),
+            _ => return Ok(Reply::error("ERR syntax error")),
+        }
+    }
+    if nx && xx {
+        return Ok(Reply::error("ERR syntax error"));
+    }
+
+    let old = if nx || xx || get {
+        engine.get(key.to_owned())?
+    } else {
+        None
+    };
+    let skip = (nx && old.is_some()) || (xx && old.is_none());
+    if !skip {
+        engine.set(key.to_owned(), value.to_owned())?;
+    }
+    Ok(match (get, skip) {
+        (true, _) => Reply::from(old),
+        (false, true) => Reply::Null,
+        (false, false) => Reply::ok(),
+    })
+}
+
+/// The part of a glob pattern before its first special character.
+fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|c| b"*?[\\".contains(c))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Redis glob matching: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+///
+/// A mismatch goes back to the last `*` and lets it take one more byte, so
+/// patterns with many stars take time proportional to the pattern times the
+/// text rather than exponential time.
+fn glob_match(mut pattern: &[u8], mut text: &[u8]) -> bool {
+    // the pattern after the last `*`, and the text it was last tried at
+    let mut backtrack: Option<(&[u8], &[u8])> = None;
+    loop {
+        match pattern.split_first() {
+            Some((b'*', rest)) => {
+                pattern = rest;
+                backtrack = Some((rest, text));
+                continue;
+            }
+            Some(_) if !text.is_empty() => {
+                let (matched, rest) = match_one(pattern, text[0]);
+                if matched {
+                    pattern = rest;
+                    text = &text[1..];
+                    continue;
+                }
+            }
+            None if text.is_empty() => return true,
+            _ => {}
+        }
+        match backtrack {
+            Some((after, tried)) if !tried.is_empty() => {
+                pattern = after;
+                text = &tried[1..];
+                backtrack = Some((after, text));
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Matches `c` against the first character of a non-empty pattern that
+/// doesn't start with `*`, returning whether it matched and the rest of the
+/// pattern.
+fn match_one(pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    match pattern {
+        [b'?', rest @ ..] => (true, rest),
+        [b'[', rest @ ..] => match_class(rest, c),
+        [b'\\', x, rest @ ..] | [x, rest @ ..] => (*x == c, rest),
+        [] => (false, pattern),
+    }
+}
+
+/// Matches `c` against the class after a `[`, returning whether it matched
+/// and the pattern after the closing `]`.
+fn match_class(mut pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let negate = pattern.first() == Some(&b'^');
+    if negate {
+        pattern = &pattern[1..];
+    }
+    let mut matched = false;
+    loop {
+        match pattern {
+            // an unclosed class ends with the pattern
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [b'\\', x, rest @ ..] => {
+                matched |= *x == c;
+                pattern = rest;
+            }
+            [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                matched |= (*lo.min(hi)..=*lo.max(hi)).contains(&c);
+                pattern = rest;
+            }
+            [x, rest @ ..] => {
+                matched |= *x == c;
+                pattern = rest;
+            }
+        }
+    }
+    (matched != negate, pattern)
+}
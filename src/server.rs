@@ -1,21 +1,27 @@
 use std::borrow::BorrowMut;
 use std::cell::RefCell;
-use std::io::{BufRead, BufReader, Read, Write};
+use std::convert::TryFrom;
+use std::io::{self, BufReader, Read, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::ops::Bound;
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::string::FromUtf8Error;
 use std::sync::Arc;
+use std::time::Duration;
 
-use slog::{info, Logger};
+use slog::{error, info, warn, Logger};
 extern crate bincode;
 extern crate serde;
 extern crate serde_bytes;
-use bincode::{deserialize, serialize, Error};
 use std::thread;
 
+use crate::auth::Session;
 use crate::engines::SledKvsEngine;
+use crate::layers::SizeLimitLayer;
+use crate::shutdown::{Connections, DEFAULT_DRAIN_TIMEOUT};
 use crate::{common::*, engines, ThreadPool};
-use crate::{KvStore, KvsEngine};
+use crate::{Acl, KvStore, KvsEngine, KvsError, ServerTls, ShutdownHandle};
 
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     addr: SocketAddr,
@@ -24,6 +30,12 @@ pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     dir: PathBuf,
     logger: Logger,
     pool: P,
+    limits: SizeLimitLayer,
+    tls: Option<ServerTls>,
+    acl: Option<Acl>,
+    shutdown: ShutdownHandle,
+    connections: Arc<Connections>,
+    drain_timeout: Duration,
 }
 
 impl<E, P> KvsServer<E, P>
@@ -33,30 +45,84 @@ where
 {
     pub fn new(addr: SocketAddr, engine: E, dir: String, logger: Logger, pool: P) -> Self {
         let dir = PathBuf::from(dir);
+        let listener = TcpListener::bind(addr).expect("Failed to bind to address");
+        let shutdown = ShutdownHandle::new(Some(listener.local_addr().unwrap_or(addr)));
 
         Self {
             addr,
             engine,
-            listener: TcpListener::bind(addr).expect("Failed to bind to address"),
+            listener,
             dir,
             logger,
             pool,
+            limits: SizeLimitLayer::default(),
+            tls: None,
+            acl: None,
+            shutdown,
+            connections: Connections::new(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
         }
     }
 
-    pub fn start(&self) {
+    /// Rejects requests with keys or values over these sizes before reading
+    /// them off the socket. Stack a `SizeLimitLayer` onto the engine as well
+    /// to cover calls made around the server.
+    pub fn with_limits(mut self, limits: SizeLimitLayer) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Speaks TLS on every connection, refusing clients that don't.
+    pub fn with_tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Only runs requests the ACL grants the client, who starts out
+    /// anonymous and can authenticate with `Request::Auth`.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// How long shutting down waits for requests in flight before cutting
+    /// their connections off, 10 seconds by default.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// A handle that makes `start` return once the server has drained.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves connections until shut down through a `ShutdownHandle`. A
+    /// connection keeps one pool thread for as long as the client holds it
+    /// open.
+    ///
+    /// Shutting down stops reading from every connection, waits up to the
+    /// drain timeout for the requests already read to be answered, then
+    /// flushes the engine.
+    pub fn start(&self) -> crate::Result<()> {
         info!(self.logger, "Started listening on {}", self.addr);
 
         for stream in self.listener.incoming() {
+            if self.shutdown.is_shutdown() {
+                break;
+            }
             match stream {
                 Ok(stream) => {
-                    // println!("New connection: {:?}", stream.peer_addr());
-                    // std::thread::spawn(move || {
-                    // });
                     let engine = self.engine.clone();
+                    let limits = self.limits;
+                    let tls = self.tls.clone();
+                    let session = Session::new(self.acl.clone());
+                    let guard = self.connections.open(stream.try_clone().ok());
+                    let logger = self.logger.clone();
 
                     self.pool.spawn(move || {
-                        handle_client(engine, stream);
+                        handle_client(engine, stream, limits, tls, session, logger);
+                        drop(guard);
                     });
                 }
                 Err(e) => {
@@ -64,58 +130,199 @@ where
                 }
             }
         }
+
+        info!(self.logger, "Shutting down"; "connections" => self.connections.len());
+        self.connections.close_reads();
+        if !self.connections.wait(self.drain_timeout) {
+            warn!(self.logger, "Closing connections that didn't drain in time";
+                  "connections" => self.connections.len());
+            self.connections.close_all();
+        }
+        self.engine.flush()?;
+        info!(self.logger, "Shut down");
+        Ok(())
     }
 }
 
-fn handle_client<T>(engine: T, stream: TcpStream)
+/// The response for an engine error, typed where the client may want to
+/// tell it apart.
+fn error_response(e: failure::Error) -> Response {
+    match e.downcast_ref::<KvsError>() {
+        Some(KvsError::KeyNotFound) => Response::NotFound,
+        Some(error) => Response::Rejected(*error),
+        None if e.downcast_ref::<FromUtf8Error>().is_some() => {
+            Response::InvalidRequest(e.to_string())
+        }
+        None => Response::Error(e.to_string()),
+    }
+}
+
+fn handle_client<T>(
+    engine: T,
+    stream: TcpStream,
+    limits: SizeLimitLayer,
+    tls: Option<ServerTls>,
+    session: Session,
+    logger: Logger,
+) where
+    T: KvsEngine,
+{
+    let served = match tls {
+        Some(tls) => rustls::ServerConnection::new(tls.config)
+            .map_err(failure::Error::from)
+            .and_then(|conn| {
+                let stream = rustls::StreamOwned::new(conn, stream);
+                serve_connection(engine, stream, limits, session)
+            }),
+        None => serve_connection(engine, stream, limits, session),
+    };
+    if let Err(e) = served {
+        error!(logger, "Error serving connection"; "error" => %e);
+    }
+}
+
+/// Answers the client's `Hello`, returning whether they settled on a
+/// version.
+fn handshake<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> crate::Result<bool> {
+    let ours = Hello::current();
+    let welcome = match read_frame_len(reader)? {
+        None => return Ok(false),
+        Some(len) if len <= MAX_HELLO_LEN => {
+            let mut frame = reader.take(u64::from(len));
+            let welcome = match bincode::deserialize_from(&mut frame) {
+                Ok(hello) => ours.negotiate(&hello),
+                Err(_) => ours.reject(),
+            };
+            io::copy(&mut frame, &mut io::sink())?;
+            welcome
+        }
+        // most likely a request from a client that skipped the handshake
+        Some(_) => ours.reject(),
+    };
+
+    write_frame(writer, &welcome)?;
+    Ok(matches!(welcome, Welcome::Accepted { .. }))
+}
+
+/// Answers requests in the order they arrive until the client hangs up. The
+/// responses to pipelined requests go out together once the client pauses.
+fn serve_connection<T, S>(
+    engine: T,
+    stream: S,
+    limits: SizeLimitLayer,
+    mut session: Session,
+) -> crate::Result<()>
 where
     T: KvsEngine,
+    S: Read + Write,
 {
-    let mut stream = BufReader::new(stream);
+    let mut reader = BufReader::new(stream);
+    let mut out = Vec::new();
 
-    let mut buf = Vec::new();
-    if let Err(e) = stream.read_until(b'\n', &mut buf) {
-        eprintln!("Error reading from stream: {}", e);
-        return;
+    // only version 2 is spoken so far, later ones would pick a decoder here
+    let accepted = handshake(&mut reader, &mut out)?;
+    send(&mut reader, &mut out)?;
+    if !accepted {
+        return Ok(());
     }
 
-    // println!("Received buf: {:?}", buf);
+    while let Some(len) = read_frame_len(&mut reader)? {
+        let mut frame = (&mut reader).take(u64::from(len));
+        let mut id = [0; 8];
+        frame.read_exact(&mut id)?;
+        let request = read_request(&mut frame, limits.max_key_size(), limits.max_value_size());
+        // whatever an invalid request left of its frame
+        io::copy(&mut frame, &mut io::sink())?;
 
-    let request: Result<Request, Error> = deserialize(&buf);
+        let response = Envelope {
+            id: u64::from_le_bytes(id),
+            body: answer(&engine, &mut session, request),
+        };
+        write_frame(&mut out, &response)?;
+        if reader.buffer().is_empty() {
+            send(&mut reader, &mut out)?;
+        }
+    }
 
-    // println!("Received request: {:?}", request);
+    Ok(())
+}
 
-    let response = match request {
-        Ok(Request::Set(SetRequest { key, value })) => {
-            let key = String::from_utf8(key).unwrap();
-            let value = String::from_utf8(value).unwrap();
-            match engine.set(key, value) {
-                Ok(()) => Response::Success(b"SET operation successful".to_vec()),
-                Err(e) => Response::Error(format!("Error: {}", e)),
-            }
+/// Runs a decoded request against the engine, if the session lets the
+/// client make it.
+pub(crate) fn answer<T>(
+    engine: &T,
+    session: &mut Session,
+    request: crate::Result<Request>,
+) -> Response
+where
+    T: KvsEngine,
+{
+    let request = match request {
+        Ok(request) => match session.admit(request) {
+            Ok(request) => Ok(request),
+            Err(response) => return response,
+        },
+        // credentials too long to read
+        Err(e) if e.downcast_ref() == Some(&KvsError::Unauthenticated) => {
+            return session.unauthenticated()
         }
-        Ok(Request::Get(GetRequest { key })) => {
-            let key = String::from_utf8(key).unwrap();
-            match engine.get(key) {
-                Ok(Some(value)) => Response::Success(value.into_bytes()),
-                Ok(None) => Response::Success("Key not found".to_string().into_bytes()),
-                Err(e) => Response::Error(format!("Error: {}", e)),
+        Err(e) => Err(e),
+    };
+    session.filter(respond(engine, request))
+}
+
+/// Runs a decoded request against the engine.
+fn respond<T>(engine: &T, request: crate::Result<Request>) -> Response
+where
+    T: KvsEngine,
+{
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            return match e.downcast::<KvsError>() {
+                Ok(error) => Response::Rejected(error),
+                Err(e) => Response::InvalidRequest(e.to_string()),
             }
         }
-        Ok(Request::Remove(RemoveRequest { key })) => {
-            let key = String::from_utf8(key).unwrap();
-            match engine.remove(key) {
-                Ok(()) => Response::Success(b"REMOVE operation successful".to_vec()),
-                Err(e) => Response::Error(format!("Error: {}", e)),
+    };
+
+    let response = match request {
+        Request::Set(SetRequest { key, value }) => String::from_utf8(key)
+            .and_then(|key| Ok((key, String::from_utf8(value)?)))
+            .map_err(failure::Error::from)
+            .and_then(|(key, value)| engine.set(key, value))
+            .map(|()| Response::Ok),
+        Request::Get(GetRequest { key }) => String::from_utf8(key)
+            .map_err(failure::Error::from)
+            .and_then(|key| engine.get(key))
+            .map(|value| match value {
+                Some(value) => Response::Value(value.into_bytes()),
+                None => Response::NotFound,
+            }),
+        Request::Remove(RemoveRequest { key }) => String::from_utf8(key)
+            .map_err(failure::Error::from)
+            .and_then(|key| engine.remove(key))
+            .map(|()| Response::Ok),
+        Request::Scan(ScanRequest { start, limit }) => {
+            let limit = usize::try_from(limit.min(MAX_SCAN_LIMIT)).unwrap_or(usize::MAX);
+            match start {
+                Bound::Included(key) => String::from_utf8(key).map(Bound::Included),
+                Bound::Excluded(key) => String::from_utf8(key).map(Bound::Excluded),
+                Bound::Unbounded => Ok(Bound::Unbounded),
             }
+            .map_err(failure::Error::from)
+            .and_then(|start| engine.scan(start, limit))
+            .map(|pairs| {
+                Response::Pairs(
+                    pairs
+                        .into_iter()
+                        .map(|(key, value)| (key.into_bytes(), value.into_bytes()))
+                        .collect(),
+                )
+            })
         }
-        Err(e) => Response::Error(format!("Invalid request: {}", e)),
+        // `Session::admit` answers these before they get here
+        Request::Auth(_) => Ok(Response::Ok),
     };
-
-    // println!("Sending response: {:?}", response);
-
-    let response_buf = serialize(&response).unwrap();
-    if let Err(e) = stream.get_mut().write_all(&response_buf) {
-        eprintln!("Error writing to stream: {}", e);
-    }
+    response.unwrap_or_else(error_response)
 }
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// How long a server waits for requests in flight once asked to shut down,
+/// unless told otherwise.
+pub(crate) const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Asks a `KvsServer` or `AsyncKvsServer` to shut down from another thread
+/// or task.
+///
+/// The server stops accepting connections, lets requests already read
+/// finish within its drain timeout, flushes the engine, and then returns
+/// from `start` or `run`.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    sender: watch::Sender<bool>,
+    /// Where to connect to wake a listener blocked in `accept`.
+    wake: Option<SocketAddr>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(wake: Option<SocketAddr>) -> ShutdownHandle {
+        let (sender, _) = watch::channel(false);
+        ShutdownHandle {
+            inner: Arc::new(Inner { sender, wake }),
+        }
+    }
+
+    /// Starts shutting the server down, returning without waiting for it.
+    /// Later calls do nothing.
+    pub fn shutdown(&self) {
+        if self.inner.sender.send_replace(true) {
+            return;
+        }
+        if let Some(mut addr) = self.inner.wake {
+            // a listener on every address is reachable on loopback
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            // the accept loop sees the flag whether or not this gets in
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    /// Whether `shutdown` has been called.
+    pub fn is_shutdown(&self) -> bool {
+        *self.inner.sender.borrow()
+    }
+
+    /// Resolves once `shutdown` has been called.
+    pub(crate) async fn wait(&self) {
+        let mut receiver = self.inner.sender.subscribe();
+        // the sender lives as long as `self`
+        let _ = receiver.wait_for(|shutdown| *shutdown).await;
+    }
+}
+
+/// The connections a server is serving, so shutting down can wait for them
+/// and close the ones that don't finish.
+pub(crate) struct Connections {
+    /// Sockets by connection, `None` where the server can't close them from
+    /// outside.
+    live: Mutex<HashMap<u64, Option<TcpStream>>>,
+    next_id: AtomicU64,
+    drained: Condvar,
+}
+
+impl Connections {
+    pub(crate) fn new() -> Arc<Connections> {
+        Arc::new(Connections {
+            live: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            drained: Condvar::new(),
+        })
+    }
+
+    /// Counts a connection as live until the returned guard is dropped.
+    pub(crate) fn open(self: &Arc<Self>, stream: Option<TcpStream>) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.live.lock().unwrap().insert(id, stream);
+        ConnectionGuard {
+            connections: self.clone(),
+            id,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.live.lock().unwrap().len()
+    }
+
+    /// Stops reading from every connection, so each ends once it has
+    /// answered what it already read.
+    pub(crate) fn close_reads(&self) {
+        self.close(Shutdown::Read);
+    }
+
+    /// Cuts every connection off.
+    pub(crate) fn close_all(&self) {
+        self.close(Shutdown::Both);
+    }
+
+    fn close(&self, how: Shutdown) {
+        for stream in self.live.lock().unwrap().values().flatten() {
+            // the client may have hung up already
+            let _ = stream.shutdown(how);
+        }
+    }
+
+    /// Waits up to `timeout` for every connection to end, returning whether
+    /// they did.
+    pub(crate) fn wait(&self, timeout: Duration) -> bool {
+        let live = self.live.lock().unwrap();
+        let (live, _) = self
+            .drained
+            .wait_timeout_while(live, timeout, |live| !live.is_empty())
+            .unwrap();
+        live.is_empty()
+    }
+}
+
+pub(crate) struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut live = self.connections.live.lock().unwrap();
+        live.remove(&self.id);
+        if live.is_empty() {
+            self.connections.drained.notify_all();
+        }
+    }
+}
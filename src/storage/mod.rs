@@ -8,9 +8,12 @@ use std::sync::Mutex;
 use openraft::async_trait::async_trait;
 use openraft::storage::LogState;
 use openraft::storage::Snapshot;
+use openraft::AnyError;
 use openraft::BasicNode;
 use openraft::Entry;
 use openraft::EntryPayload;
+use openraft::ErrorSubject;
+use openraft::ErrorVerb;
 use openraft::LogId;
 use openraft::RaftLogReader;
 use openraft::RaftSnapshotBuilder;
@@ -25,8 +28,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use tokio::sync::RwLock;
 
-use crate::KvStore;
-use crate::KvsEngine;
+use crate::AsyncKvsEngine;
 use crate::NodeId;
 use crate::TypeConfig;
 
@@ -69,7 +71,7 @@ pub struct StoredSnapshot {
  * value as String, but you could set any type of value that has the serialization impl.
  */
 #[derive(Debug, Default, Clone)]
-pub struct StateMachine<K: KvsEngine + Sync> {
+pub struct StateMachine<K: AsyncKvsEngine> {
     pub last_applied_log: Option<LogId<NodeId>>,
 
     pub last_membership: StoredMembership<NodeId, BasicNode>,
@@ -78,7 +80,7 @@ pub struct StateMachine<K: KvsEngine + Sync> {
     pub data: K,
 }
 
-impl<K: KvsEngine + Sync> StateMachine<K> {
+impl<K: AsyncKvsEngine> StateMachine<K> {
     pub fn new(data: K) -> Self {
         Self {
             last_applied_log: None,
@@ -89,7 +91,7 @@ impl<K: KvsEngine + Sync> StateMachine<K> {
 }
 
 #[derive(Debug, Default)]
-pub struct Store<K: KvsEngine + Sync> {
+pub struct Store<K: AsyncKvsEngine> {
     last_purged_log_id: RwLock<Option<LogId<NodeId>>>,
 
     /// The Raft log.
@@ -106,7 +108,7 @@ pub struct Store<K: KvsEngine + Sync> {
     current_snapshot: RwLock<Option<StoredSnapshot>>,
 }
 
-impl<K: KvsEngine + Sync> Store<K> {
+impl<K: AsyncKvsEngine> Store<K> {
     pub fn new(data: K) -> Self {
         Self {
             last_purged_log_id: RwLock::new(None),
@@ -120,7 +122,7 @@ impl<K: KvsEngine + Sync> Store<K> {
 }
 
 #[async_trait]
-impl<K: KvsEngine + Sync> RaftLogReader<TypeConfig> for Arc<Store<K>> {
+impl<K: AsyncKvsEngine> RaftLogReader<TypeConfig> for Arc<Store<K>> {
     async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + Debug + Send + Sync>(
         &mut self,
         range: RB,
@@ -135,7 +137,7 @@ impl<K: KvsEngine + Sync> RaftLogReader<TypeConfig> for Arc<Store<K>> {
 }
 
 #[async_trait]
-impl<K: KvsEngine + Sync> RaftSnapshotBuilder<TypeConfig> for Arc<Store<K>> {
+impl<K: AsyncKvsEngine> RaftSnapshotBuilder<TypeConfig> for Arc<Store<K>> {
     #[tracing::instrument(level = "trace", skip(self))]
     async fn build_snapshot(&mut self) -> Result<Snapshot<TypeConfig>, StorageError<NodeId>> {
         Ok(Snapshot {
@@ -146,7 +148,7 @@ impl<K: KvsEngine + Sync> RaftSnapshotBuilder<TypeConfig> for Arc<Store<K>> {
 }
 
 #[async_trait]
-impl<K: KvsEngine + Sync> RaftStorage<TypeConfig> for Arc<Store<K>> {
+impl<K: AsyncKvsEngine> RaftStorage<TypeConfig> for Arc<Store<K>> {
     type LogReader = Self;
     type SnapshotBuilder = Self;
 
@@ -263,7 +265,13 @@ impl<K: KvsEngine + Sync> RaftStorage<TypeConfig> for Arc<Store<K>> {
                 EntryPayload::Blank => res.push(Response { value: None }),
                 EntryPayload::Normal(ref req) => match req {
                     Request::Set { key, value } => {
-                        sm.data.set(key.clone(), value.clone());
+                        sm.data.set(key.clone(), value.clone()).await.map_err(|e| {
+                            StorageIOError::new(
+                                ErrorSubject::StateMachine,
+                                ErrorVerb::Write,
+                                AnyError::error(e),
+                            )
+                        })?;
                         res.push(Response {
                             value: Some(value.clone()),
                         })
@@ -0,0 +1,102 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+use crate::Result;
+
+/// What a `KvsServer` or `AsyncKvsServer` needs to serve over TLS.
+#[derive(Clone)]
+pub struct ServerTls {
+    pub(crate) config: Arc<ServerConfig>,
+}
+
+impl ServerTls {
+    /// Serves the PEM certificate chain in `cert` with the PEM private key in
+    /// `key`. With `client_ca`, clients must present a certificate signed by
+    /// one of the PEM CA certificates in it.
+    pub fn from_pem_files(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<ServerTls> {
+        let provider = Arc::new(default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca {
+            Some(ca) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots(ca)?), provider)
+                        .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder.with_single_cert(certs(cert)?, private_key(key)?)?;
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// What a `KvsClient` needs to reach a server over TLS.
+#[derive(Clone)]
+pub struct ClientTls {
+    pub(crate) config: Arc<ClientConfig>,
+    pub(crate) server_name: ServerName<'static>,
+}
+
+impl ClientTls {
+    /// Trusts a server whose certificate is signed by one of the PEM CA
+    /// certificates in `ca` and names `server_name`, a DNS name or an IP
+    /// address. With `identity`, a PEM certificate chain and private key, the
+    /// client proves who it is to servers that ask.
+    pub fn from_pem_files(
+        ca: &Path,
+        server_name: &str,
+        identity: Option<(&Path, &Path)>,
+    ) -> Result<ClientTls> {
+        let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => builder.with_client_auth_cert(certs(cert)?, private_key(key)?)?,
+            None => builder.with_no_client_auth(),
+        };
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|_| failure::err_msg(format!("Invalid TLS server name {}", server_name)))?;
+
+        Ok(ClientTls {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(failure::err_msg(format!(
+            "No PEM certificates in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| failure::err_msg(format!("No PEM private key in {}", path.display())))
+}
+
+fn roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
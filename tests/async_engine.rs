@@ -0,0 +1,74 @@
+use kvs::ThreadPool;
+use kvs::{AsyncKvsEngine, BlockingEngine, KvStore, PooledEngine, Result, SharedQueueThreadPool};
+use tempfile::TempDir;
+use tokio::runtime::{Builder, Runtime};
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread()
+        .build()
+        .expect("unable to build tokio runtime")
+}
+
+async fn set_get_remove<E: AsyncKvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        engine.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    engine.set("key1".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(
+        engine.get("key1".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    engine.remove("key1".to_owned()).await?;
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+
+    Ok(())
+}
+
+#[test]
+fn blocking_engine_set_get_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = BlockingEngine::new(KvStore::open(temp_dir.path())?);
+
+    runtime().block_on(set_get_remove(engine))
+}
+
+#[test]
+fn pooled_engine_set_get_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::new(2)?;
+    let engine = PooledEngine::new(KvStore::open(temp_dir.path())?, pool);
+
+    runtime().block_on(set_get_remove(engine))
+}
+
+#[test]
+fn pooled_engine_concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::new(4)?;
+    let engine = PooledEngine::new(KvStore::open(temp_dir.path())?, pool);
+
+    runtime().block_on(async {
+        let mut handles = Vec::new();
+        for i in 0..100 {
+            let engine = engine.clone();
+            handles.push(tokio::spawn(async move {
+                engine.set(format!("key{}", i), format!("value{}", i)).await
+            }));
+        }
+        for handle in handles {
+            handle.await??;
+        }
+
+        for i in 0..100 {
+            assert_eq!(
+                engine.get(format!("key{}", i)).await?,
+                Some(format!("value{}", i))
+            );
+        }
+        Ok(())
+    })
+}
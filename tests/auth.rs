@@ -0,0 +1,242 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use kvs::common::*;
+use kvs::{
+    Acl, AsyncKvsServer, InMemEngine, KvsClient, KvsEngine, KvsError, KvsServer, Result,
+    SharedQueueThreadPool, ThreadPool,
+};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+use tokio::runtime::Builder;
+
+/// Hashes `password` with parameters cheap enough for unoptimized tests.
+fn hash(password: &str) -> Result<String> {
+    let salt = SaltString::encode_b64(b"sixteen byte salt")
+        .map_err(|e| failure::err_msg(e.to_string()))?;
+    let params = Params::new(1024, 1, 1, None).map_err(|e| failure::err_msg(e.to_string()))?;
+    let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| failure::err_msg(e.to_string()))?;
+    Ok(hash.to_string())
+}
+
+fn write_acl(dir: &Path) -> Result<Acl> {
+    let path = dir.join("acl.toml");
+    let acl = format!(
+        r#"
+[anonymous]
+read = ["public/"]
+
+[[tokens]]
+name = "backup"
+token = "let-me-in"
+read = [""]
+
+[[users]]
+name = "alice"
+password = "{}"
+read = ["alice/", "public/"]
+write = ["alice/"]
+"#,
+        hash("hunter2")?
+    );
+    fs::write(&path, acl)?;
+    Acl::from_file(&path)
+}
+
+/// Starts a thread-pool server on `port` and an async one on `port + 100`
+/// over one engine, and returns their addresses.
+fn start_servers<E: KvsEngine>(port: u16, engine: E, acl: Acl) -> Result<Vec<SocketAddr>> {
+    let logger = Logger::root(Discard, o!());
+    let addrs = vec![
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        SocketAddr::from(([127, 0, 0, 1], port + 100)),
+    ];
+
+    // each connection holds a thread
+    let pool = SharedQueueThreadPool::new(4)?;
+    let server = KvsServer::new(
+        addrs[0],
+        engine.clone(),
+        String::new(),
+        logger.clone(),
+        pool,
+    )
+    .with_acl(acl.clone());
+    thread::spawn(move || server.start());
+
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(2)
+        .max_blocking_threads(2)
+        .enable_io()
+        .build()?;
+    let server = AsyncKvsServer::new(addrs[1], engine, logger).with_acl(acl);
+    thread::spawn(move || runtime.block_on(server.run()));
+
+    thread::sleep(Duration::from_millis(200));
+    Ok(addrs)
+}
+
+fn client(addr: SocketAddr) -> KvsClient {
+    KvsClient::new(addr, Logger::root(Discard, o!()))
+}
+
+fn error_of<T: std::fmt::Debug>(result: Result<T>) -> KvsError {
+    *result
+        .unwrap_err()
+        .downcast_ref::<KvsError>()
+        .expect("a KvsError")
+}
+
+fn password(user: &str, password: &str) -> Credentials {
+    Credentials::Password {
+        user: user.to_owned(),
+        password: password.to_owned(),
+    }
+}
+
+#[test]
+fn grants_by_prefix() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+    for key in &["alice/1", "alice/2", "bob/1", "public/1"] {
+        engine.set(key.to_string(), "value".to_owned())?;
+    }
+    let addrs = start_servers(4070, engine, write_acl(temp_dir.path())?)?;
+
+    for addr in addrs {
+        // anonymous clients get what the ACL gives everyone
+        let anonymous = client(addr);
+        assert_eq!(
+            anonymous.get("public/1".to_owned())?,
+            Some("value".to_owned())
+        );
+        assert_eq!(
+            error_of(anonymous.get("alice/1".to_owned())),
+            KvsError::PermissionDenied
+        );
+        assert_eq!(
+            error_of(anonymous.set("public/2".to_owned(), "x".to_owned())),
+            KvsError::PermissionDenied
+        );
+
+        let alice = client(addr).with_credentials(password("alice", "hunter2"));
+        alice.set("alice/3".to_owned(), "value".to_owned())?;
+        alice.remove("alice/3".to_owned())?;
+        assert_eq!(alice.get("public/1".to_owned())?, Some("value".to_owned()));
+        assert_eq!(
+            error_of(alice.get("bob/1".to_owned())),
+            KvsError::PermissionDenied
+        );
+        assert_eq!(
+            error_of(alice.remove("public/1".to_owned())),
+            KvsError::PermissionDenied
+        );
+        // a scan skips ahead to what the client may read, and stops where
+        // that ends
+        let keys: Vec<_> = alice
+            .scan(Bound::Unbounded, 10)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, ["alice/1", "alice/2"]);
+        assert_eq!(
+            alice.scan(Bound::Excluded("alice/2".to_owned()), 10)?,
+            Vec::new()
+        );
+        assert_eq!(
+            alice.scan(Bound::Included("bob/".to_owned()), 10)?,
+            vec![("public/1".to_owned(), "value".to_owned())]
+        );
+
+        let backup = client(addr).with_credentials(Credentials::Token("let-me-in".to_owned()));
+        assert_eq!(backup.scan(Bound::Unbounded, 10)?.len(), 4);
+        assert_eq!(
+            error_of(backup.set("bob/1".to_owned(), "x".to_owned())),
+            KvsError::PermissionDenied
+        );
+
+        // nothing reached the engine
+        assert_eq!(backup.get("public/2".to_owned())?, None);
+        assert_eq!(backup.get("bob/1".to_owned())?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn bad_credentials() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+    engine.set("secret".to_owned(), "value".to_owned())?;
+    let addrs = start_servers(4071, engine, write_acl(temp_dir.path())?)?;
+
+    for addr in addrs {
+        let wrong = [
+            password("alice", "hunter3"),
+            password("mallory", "hunter2"),
+            Credentials::Token("let-me-in!".to_owned()),
+            Credentials::Token(String::new()),
+            Credentials::Token("x".repeat(MAX_CREDENTIAL_LEN + 1)),
+        ];
+        for credentials in wrong.iter() {
+            let client = client(addr).with_credentials(credentials.clone());
+            assert_eq!(
+                error_of(client.get("secret".to_owned())),
+                KvsError::Unauthenticated
+            );
+        }
+
+        // failing to authenticate drops what an earlier success granted
+        let responses = client(addr).pipeline(vec![
+            Request::Auth(Credentials::Token("let-me-in".to_owned())),
+            Request::Get(GetRequest {
+                key: b"secret".to_vec(),
+            }),
+            Request::Auth(password("alice", "wrong")),
+            Request::Get(GetRequest {
+                key: b"secret".to_vec(),
+            }),
+        ])?;
+        assert_eq!(
+            responses,
+            [
+                Response::Ok,
+                Response::Value(b"value".to_vec()),
+                Response::Denied(KvsError::Unauthenticated),
+                Response::Denied(KvsError::PermissionDenied),
+            ]
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn invalid_acl_files() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("acl.toml");
+    let invalid = [
+        "[[users]]\nname = \"alice\"\npassword = \"hunter2\"\n",
+        "[[tokens]]\nname = \"empty\"\ntoken = \"\"\n",
+        "[[tokens]]\nname = \"a\"\ntoken = \"x\"\n[[tokens]]\nname = \"a\"\ntoken = \"y\"\n",
+        // a typo mustn't quietly grant nothing
+        "[anonymous]\nreads = [\"\"]\n",
+    ];
+    for acl in invalid.iter() {
+        fs::write(&path, acl)?;
+        assert!(Acl::from_file(&path).is_err(), "{}", acl);
+    }
+
+    fs::write(&path, "")?;
+    Acl::from_file(&path)?;
+
+    Ok(())
+}
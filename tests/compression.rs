@@ -0,0 +1,160 @@
+use std::path::Path;
+
+use kvs::{EncryptionKey, KvStore, KvStoreOptions, KvsEngine, Result};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+fn open(path: &Path, compress_min_size: Option<usize>) -> Result<KvStore> {
+    let options = KvStoreOptions {
+        compress_min_size,
+        ..Default::default()
+    };
+    KvStore::open_with_options(path, options)
+}
+
+fn json_value(i: u32) -> String {
+    let items: Vec<String> = (0..20)
+        .map(|j| {
+            format!(
+                r#"{{"id":{},"name":"item","tags":["a","b","c"]}}"#,
+                i * 100 + j
+            )
+        })
+        .collect();
+    format!("[{}]", items.join(","))
+}
+
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+#[test]
+fn compresses_large_records_only() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = open(temp_dir.path(), Some(128))?;
+
+    for i in 0..10 {
+        store.set(format!("big{}", i), json_value(i))?;
+        store.set(format!("small{}", i), "tiny".to_owned())?;
+    }
+
+    let stats = store.stats();
+    assert_eq!(stats.keys, 20);
+    assert_eq!(stats.compressed_records, 10);
+    assert!(
+        stats.compression_ratio() > 3.0,
+        "{}",
+        stats.compression_ratio()
+    );
+
+    drop(store);
+    let store = open(temp_dir.path(), Some(128))?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("big{}", i))?, Some(json_value(i)));
+        assert_eq!(store.get(format!("small{}", i))?, Some("tiny".to_owned()));
+    }
+    assert_eq!(store.stats().compressed_records, 10);
+
+    Ok(())
+}
+
+#[test]
+fn compression_shrinks_segments() -> Result<()> {
+    let plain_dir = TempDir::new()?;
+    let compressed_dir = TempDir::new()?;
+    let plain = open(plain_dir.path(), None)?;
+    let compressed = open(compressed_dir.path(), Some(0))?;
+
+    for i in 0..10 {
+        plain.set(format!("key{}", i), json_value(i))?;
+        compressed.set(format!("key{}", i), json_value(i))?;
+    }
+
+    assert_eq!(plain.stats().compressed_records, 0);
+    assert_eq!(plain.stats().compression_ratio(), 1.0);
+    assert!(dir_size(compressed_dir.path())? * 3 < dir_size(plain_dir.path())?);
+
+    Ok(())
+}
+
+#[test]
+fn mixed_segments_stay_readable() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+
+    let store = open(temp_dir.path(), None)?;
+    store.set("plain".to_owned(), json_value(0))?;
+    drop(store);
+
+    let store = open(temp_dir.path(), Some(64))?;
+    store.set("compressed".to_owned(), json_value(1))?;
+    assert_eq!(store.stats().compressed_records, 1);
+    drop(store);
+
+    // turning compression off keeps compressed records readable, and the
+    // next compaction writes everything back uncompressed
+    let store = open(temp_dir.path(), None)?;
+    assert_eq!(store.get("plain".to_owned())?, Some(json_value(0)));
+    assert_eq!(store.get("compressed".to_owned())?, Some(json_value(1)));
+    store.compact()?;
+    assert_eq!(store.stats().compressed_records, 0);
+    assert_eq!(store.get("compressed".to_owned())?, Some(json_value(1)));
+    drop(store);
+
+    // and turning it on again compresses the lot at compaction
+    let store = open(temp_dir.path(), Some(64))?;
+    store.compact()?;
+    assert_eq!(store.stats().compressed_records, 2);
+    assert_eq!(store.get("plain".to_owned())?, Some(json_value(0)));
+
+    Ok(())
+}
+
+#[test]
+fn incompressible_values_are_stored_as_is() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = open(temp_dir.path(), Some(0))?;
+
+    // every char differs from its neighbours, LZ4 finds nothing to match
+    let value: String = (0..200u32)
+        .map(|i| char::from_u32(0x4e00 + i * 7).unwrap())
+        .collect();
+    store.set("noise".to_owned(), value.clone())?;
+
+    assert_eq!(store.stats().compressed_records, 0);
+    assert_eq!(store.get("noise".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+#[test]
+fn compression_with_encryption() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let key = EncryptionKey::generate();
+    let options = KvStoreOptions {
+        compress_min_size: Some(64),
+        encryption_key: Some(key),
+        ..Default::default()
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), json_value(i))?;
+    }
+    assert_eq!(store.stats().compressed_records, 10);
+    assert!(store.stats().compression_ratio() > 3.0);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(json_value(i)));
+    }
+
+    Ok(())
+}
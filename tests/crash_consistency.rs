@@ -0,0 +1,175 @@
+//! Crash-consistency tests for `KvStore` on top of the fault-injecting `MemFs`.
+//!
+//! A fixed workload is replayed once per filesystem operation, with a fault
+//! scheduled at that operation. Whatever happens, reopening the store must
+//! succeed and show every acknowledged write. Only the operation that was in
+//! flight when the fault hit may go either way.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use kvs::{KvStore, KvStoreOptions, KvsEngine, MemFs};
+
+const NUM_KEYS: usize = 7;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+    Compact,
+}
+
+fn workload() -> Vec<Op> {
+    let mut ops = Vec::new();
+    for i in 0..60 {
+        ops.push(Op::Set(
+            format!("key{}", i % NUM_KEYS),
+            format!("value{}", i),
+        ));
+        if i % 5 == 4 {
+            ops.push(Op::Remove(format!("key{}", (i * 3) % NUM_KEYS)));
+        }
+        if i % 20 == 19 {
+            ops.push(Op::Compact);
+        }
+    }
+    ops
+}
+
+fn open(fs: &MemFs) -> kvs::Result<KvStore> {
+    let options = KvStoreOptions {
+        max_segment_size: 64,
+        fs: Arc::new(fs.clone()),
+        ..Default::default()
+    };
+    KvStore::open_with_options(Path::new("/db"), options)
+}
+
+/// Runs `op` and records it in `acked` if the store acknowledged it.
+fn apply(store: &KvStore, op: &Op, acked: &mut BTreeMap<String, String>) -> kvs::Result<()> {
+    match op {
+        Op::Set(key, value) => {
+            store.set(key.clone(), value.clone())?;
+            acked.insert(key.clone(), value.clone());
+        }
+        Op::Remove(key) => {
+            // removing a missing key fails without touching the disk
+            if acked.contains_key(key) {
+                store.remove(key.clone())?;
+                acked.remove(key);
+            }
+        }
+        Op::Compact => store.compact()?,
+    }
+    Ok(())
+}
+
+/// Asserts the store holds `acked`, except that the key of `in_flight` may
+/// also reflect that operation.
+fn check(store: &KvStore, acked: &BTreeMap<String, String>, in_flight: Option<&Op>) {
+    for i in 0..NUM_KEYS {
+        let key = format!("key{}", i);
+        let value = store.get(key.clone()).expect("get after recovery");
+        let expected = acked.get(&key).cloned();
+
+        let alternative = match in_flight {
+            Some(Op::Set(k, v)) if *k == key => Some(Some(v.clone())),
+            Some(Op::Remove(k)) if *k == key => Some(None),
+            _ => None,
+        };
+
+        assert!(
+            value == expected || Some(&value) == alternative.as_ref(),
+            "{}: got {:?}, acknowledged {:?}, in flight {:?}",
+            key,
+            value,
+            expected,
+            in_flight
+        );
+    }
+}
+
+/// Brings the key of `op` in line with what the store shows, after `op` failed
+/// without a crash.
+fn settle(store: &KvStore, op: &Op, acked: &mut BTreeMap<String, String>) {
+    let key = match op {
+        Op::Set(key, _) | Op::Remove(key) => key,
+        Op::Compact => return,
+    };
+    match store.get(key.clone()).expect("get after failed operation") {
+        Some(value) => acked.insert(key.clone(), value),
+        None => acked.remove(key),
+    };
+}
+
+fn fault_free_ops() -> u64 {
+    let fs = MemFs::new();
+    let store = open(&fs).unwrap();
+    let mut acked = BTreeMap::new();
+    for op in workload() {
+        apply(&store, &op, &mut acked).unwrap();
+    }
+    fs.ops()
+}
+
+#[test]
+fn power_cut_at_every_operation() {
+    let total = fault_free_ops();
+
+    for n in 1..=total {
+        let fs = MemFs::new();
+        fs.power_cut_after(n);
+
+        let mut acked = BTreeMap::new();
+        let mut in_flight = None;
+        if let Ok(store) = open(&fs) {
+            for op in workload() {
+                if apply(&store, &op, &mut acked).is_err() {
+                    in_flight = Some(op);
+                    break;
+                }
+            }
+        }
+
+        fs.restart();
+        let store = open(&fs).unwrap_or_else(|e| panic!("reopen after cut at op {}: {}", n, e));
+        check(&store, &acked, in_flight.as_ref());
+
+        // the recovered store keeps working
+        store.set("key0".to_owned(), "after".to_owned()).unwrap();
+        assert_eq!(
+            store.get("key0".to_owned()).unwrap(),
+            Some("after".to_owned())
+        );
+    }
+}
+
+#[test]
+fn error_at_every_operation() {
+    let total = fault_free_ops();
+
+    for n in 1..=total {
+        let fs = MemFs::new();
+        fs.fail_after(n);
+
+        let store = match open(&fs) {
+            Ok(store) => store,
+            Err(_) => open(&fs).unwrap(),
+        };
+
+        let mut acked = BTreeMap::new();
+        for op in workload() {
+            if apply(&store, &op, &mut acked).is_err() {
+                settle(&store, &op, &mut acked);
+            }
+        }
+        check(&store, &acked, None);
+
+        // everything acknowledged is durable, even if the power goes now
+        drop(store);
+        fs.restart();
+        let store = open(&fs).unwrap_or_else(|e| panic!("reopen after error at op {}: {}", n, e));
+        check(&store, &acked, None);
+    }
+}
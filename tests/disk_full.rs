@@ -0,0 +1,93 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsError, MemFs, Result};
+
+fn open(fs: &MemFs) -> Result<KvStore> {
+    let options = KvStoreOptions {
+        fs: Arc::new(fs.clone()),
+        shards: 1,
+        ..Default::default()
+    };
+    KvStore::open_with_options("/db", options)
+}
+
+fn is_storage_full(e: &failure::Error) -> bool {
+    e.downcast_ref::<KvsError>() == Some(&KvsError::StorageFull)
+}
+
+/// Sets `key` to ever new values until the disk is full and returns the last
+/// value that made it.
+fn fill(store: &KvStore, key: &str) -> Result<String> {
+    let mut last = None;
+    for i in 0.. {
+        let value = format!("{:0100}", i);
+        match store.set(key.to_owned(), value.clone()) {
+            Ok(()) => last = Some(value),
+            Err(e) => {
+                assert!(is_storage_full(&e), "{}", e);
+                break;
+            }
+        }
+    }
+    Ok(last.unwrap())
+}
+
+#[test]
+fn full_disk_degrades_to_read_only() -> Result<()> {
+    let fs = MemFs::new();
+    let store = open(&fs)?;
+    store.set("kept".to_owned(), "value".to_owned())?;
+    fs.set_capacity(Some(8 * 1024));
+
+    let last = fill(&store, "key")?;
+    assert!(store.is_storage_full());
+
+    // refused without touching the disk, and nothing is lost
+    let e = store
+        .set("other".to_owned(), "value".to_owned())
+        .unwrap_err();
+    assert!(is_storage_full(&e));
+    let e = store.remove("kept".to_owned()).unwrap_err();
+    assert!(is_storage_full(&e));
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some(last.clone()));
+
+    // once there is room again the next probe lets writes through
+    fs.set_capacity(None);
+    thread::sleep(Duration::from_millis(1100));
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert!(!store.is_storage_full());
+    store.remove("kept".to_owned())?;
+    drop(store);
+
+    let store = open(&fs)?;
+    assert_eq!(store.get("key".to_owned())?, Some(last));
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("kept".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn compaction_makes_room() -> Result<()> {
+    let fs = MemFs::new();
+    let store = open(&fs)?;
+    fs.set_capacity(Some(16 * 1024));
+
+    let last = fill(&store, "key")?;
+    assert!(store.is_storage_full());
+
+    // a little room is enough to compact the overwritten values away, after
+    // which writes resume without waiting for a probe
+    fs.set_capacity(Some(17 * 1024));
+    store.compact()?;
+    assert!(!store.is_storage_full());
+    assert_eq!(store.get("key".to_owned())?, Some(last));
+    for i in 0..20 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,180 @@
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use assert_cmd::prelude::*;
+use kvs::dump::{export, import};
+use kvs::{
+    InMemEngine, KvStore, KvsClient, KvsEngine, KvsServer, Result, SharedQueueThreadPool,
+    SledKvsEngine, ThreadPool,
+};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+fn all<E: KvsEngine>(engine: &E) -> Result<Vec<(String, String)>> {
+    engine.scan(Bound::Unbounded, usize::MAX)
+}
+
+fn fill<E: KvsEngine>(engine: &E) -> Result<()> {
+    for i in 0..50 {
+        engine.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    engine.set("line\nbreak".to_owned(), "tab\there".to_owned())?;
+    engine.set("quote\"d".to_owned(), "ünïcödé".to_owned())?;
+    engine.remove("key13".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let source = KvStore::open(temp_dir.path())?;
+    fill(&source)?;
+
+    let mut dump = Vec::new();
+    assert_eq!(export(&source, &mut dump, 7)?, 51);
+
+    let text = String::from_utf8(dump.clone())?;
+    assert_eq!(text.lines().count(), 51);
+    assert!(text.contains(r#"{"key":"key00","value":"value0"}"#));
+    assert!(text.contains(r#"{"key":"quote\"d","value":"ünïcödé"}"#));
+    // control characters go through base64
+    assert!(
+        text.contains(r#"{"key":{"base64":"bGluZQpicmVhaw=="},"value":{"base64":"dGFiCWhlcmU="}}"#)
+    );
+
+    let dest = InMemEngine::open(temp_dir.path().to_path_buf());
+    assert_eq!(import(&dest, Cursor::new(dump), 7)?, 51);
+    assert_eq!(all(&dest)?, all(&source)?);
+
+    Ok(())
+}
+
+#[test]
+fn import_rejects_malformed_line() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+
+    let dump =
+        "{\"key\":\"a\",\"value\":\"1\"}\n\n{\"key\":\"b\",\"value\":\"2\"}\n{\"key\":\"c\"}\n";
+    let err = import(&engine, Cursor::new(dump), 1).unwrap_err();
+    assert!(err.to_string().contains("line 4"), "{}", err);
+
+    // batches before the bad line are in, nothing after it
+    assert_eq!(
+        all(&engine)?,
+        vec![
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "2".to_owned())
+        ]
+    );
+
+    let dump = "{\"key\":{\"base64\":\"not base64!\"},\"value\":\"1\"}\n";
+    assert!(import(&engine, Cursor::new(dump), 10).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn export_import_over_tcp() -> Result<()> {
+    let logger = Logger::root(Discard, o!());
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4010".parse()?;
+
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let server = KvsServer::new(
+        addr,
+        engine.clone(),
+        temp_dir.path().display().to_string(),
+        logger.clone(),
+        pool,
+    );
+    thread::spawn(move || server.start());
+    thread::sleep(Duration::from_millis(200));
+
+    let client = KvsClient::new(addr, logger);
+    fill(&client)?;
+    assert_eq!(all(&client)?, all(&engine)?);
+
+    let mut dump = Vec::new();
+    assert_eq!(export(&client, &mut dump, 4)?, 51);
+
+    let restored = InMemEngine::open(temp_dir.path().to_path_buf());
+    import(&restored, Cursor::new(&dump), 4)?;
+    assert_eq!(all(&restored)?, all(&engine)?);
+
+    // and back in through the server
+    for (key, _) in all(&engine)? {
+        client.remove(key)?;
+    }
+    assert!(all(&engine)?.is_empty());
+    assert_eq!(import(&client, Cursor::new(&dump), 4)?, 51);
+    assert_eq!(all(&engine)?, all(&restored)?);
+
+    Ok(())
+}
+
+#[test]
+fn cli_export_import_offline() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let source = KvStore::open(temp_dir.path().join("kvs"))?;
+    fill(&source)?;
+    let expected = all(&source)?;
+    drop(source);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "export",
+            "--engine",
+            "kvs",
+            "--dir",
+            "kvs",
+            "--output",
+            "dump.jsonl",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "import",
+            "--engine",
+            "sled",
+            "--dir",
+            "sled",
+            "--input",
+            "dump.jsonl",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let dest = SledKvsEngine::open(temp_dir.path().join("sled"));
+    assert_eq!(all(&dest)?, expected);
+
+    // a mistyped directory is an error rather than an empty store
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "export",
+            "--engine",
+            "kvs",
+            "--dir",
+            "kvs2",
+            "--output",
+            "empty.jsonl",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert!(!temp_dir.path().join("kvs2").exists());
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::fs;
+use std::path::Path;
+
+use kvs::{EncryptionKey, KvStore, KvStoreOptions, KvsEngine, Result};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+const SECRET: &str = "secret-value";
+
+fn open(path: &Path, key: Option<&EncryptionKey>, old: &[&EncryptionKey]) -> Result<KvStore> {
+    let options = KvStoreOptions {
+        encryption_key: key.cloned(),
+        old_encryption_keys: old.iter().map(|&key| key.clone()).collect(),
+        ..Default::default()
+    };
+    KvStore::open_with_options(path, options)
+}
+
+/// Whether `needle` appears in any file under `dir`.
+fn on_disk(dir: &Path, needle: &str) -> Result<bool> {
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let data = fs::read(entry.path())?;
+        if data
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn fill(store: &KvStore) -> Result<()> {
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("{}{}", SECRET, i))?;
+    }
+    store.remove("key3".to_owned())?;
+    Ok(())
+}
+
+fn check(store: &KvStore) -> Result<()> {
+    for i in 0..20 {
+        let expected = if i == 3 {
+            None
+        } else {
+            Some(format!("{}{}", SECRET, i))
+        };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn records_are_encrypted_on_disk() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let key = EncryptionKey::generate();
+
+    let store = open(temp_dir.path(), Some(&key), &[])?;
+    fill(&store)?;
+    check(&store)?;
+    assert!(!on_disk(temp_dir.path(), SECRET)?);
+    assert!(!on_disk(temp_dir.path(), "key1")?);
+
+    store.compact()?;
+    check(&store)?;
+    assert!(!on_disk(temp_dir.path(), SECRET)?);
+
+    drop(store);
+    let store = open(temp_dir.path(), Some(&key), &[])?;
+    check(&store)?;
+
+    Ok(())
+}
+
+#[test]
+fn wrong_or_missing_key_is_a_clear_error() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let key = EncryptionKey::generate();
+    let store = open(temp_dir.path(), Some(&key), &[])?;
+    fill(&store)?;
+    drop(store);
+
+    let err = open(temp_dir.path(), None, &[]).unwrap_err();
+    assert!(err.to_string().contains("no encryption key"), "{}", err);
+
+    let wrong = EncryptionKey::generate();
+    let err = open(temp_dir.path(), Some(&wrong), &[]).unwrap_err();
+    assert!(
+        err.to_string().contains(&format!("{:016x}", key.id())),
+        "{}",
+        err
+    );
+
+    // failed opens must not have taken the ciphertext for a torn write
+    let store = open(temp_dir.path(), Some(&key), &[])?;
+    check(&store)?;
+
+    Ok(())
+}
+
+#[test]
+fn plaintext_store_is_encrypted_by_compaction() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = open(temp_dir.path(), None, &[])?;
+    fill(&store)?;
+    drop(store);
+    assert!(on_disk(temp_dir.path(), SECRET)?);
+
+    let key = EncryptionKey::generate();
+    let store = open(temp_dir.path(), Some(&key), &[])?;
+    check(&store)?;
+    store.compact()?;
+    assert!(!on_disk(temp_dir.path(), SECRET)?);
+    drop(store);
+
+    let store = open(temp_dir.path(), Some(&key), &[])?;
+    check(&store)?;
+
+    Ok(())
+}
+
+#[test]
+fn key_rotation_at_compaction() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let old_key = EncryptionKey::generate();
+    let store = open(temp_dir.path(), Some(&old_key), &[])?;
+    fill(&store)?;
+    drop(store);
+
+    let new_key = EncryptionKey::generate();
+    let store = open(temp_dir.path(), Some(&new_key), &[&old_key])?;
+    check(&store)?;
+    store.set("key20".to_owned(), "fresh".to_owned())?;
+
+    // before compaction the old key is still needed
+    assert!(open(temp_dir.path(), Some(&new_key), &[]).is_err());
+
+    store.compact()?;
+    drop(store);
+
+    let store = open(temp_dir.path(), Some(&new_key), &[])?;
+    check(&store)?;
+    assert_eq!(store.get("key20".to_owned())?, Some("fresh".to_owned()));
+    assert!(open(temp_dir.path(), Some(&old_key), &[]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn key_from_file() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let raw = temp_dir.path().join("raw.key");
+    let text = temp_dir.path().join("text.key");
+    fs::write(&raw, [7u8; 32])?;
+    fs::write(&text, "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=\n")?;
+
+    let from_raw = EncryptionKey::from_file(&raw)?;
+    let from_text = EncryptionKey::from_file(&text)?;
+    assert_eq!(from_raw.id(), from_text.id());
+    assert_eq!(from_raw.id(), EncryptionKey::new([7; 32]).id());
+
+    fs::write(&text, "c2hvcnQ=")?;
+    assert!(EncryptionKey::from_file(&text).is_err());
+
+    Ok(())
+}
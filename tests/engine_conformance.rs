@@ -0,0 +1,317 @@
+//! Behaviour every `KvsEngine` must share.
+//!
+//! Each check is a generic function over the engine type. The `conformance!` macro
+//! instantiates all of them for one engine, so a new engine only needs a single
+//! macro invocation at the bottom of this file.
+
+use std::ops::Bound;
+use std::panic;
+use std::path::Path;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+use kvs::layers::{EngineMetrics, MetricsLayer, ReadCacheLayer, SizeLimitLayer};
+use kvs::{EngineBuilder, InMemEngine, KvStore, KvsEngine, KvsError, Result, SledKvsEngine};
+use tempfile::TempDir;
+
+/// Opens an engine rooted at the given directory.
+type Open<E> = fn(&Path) -> Result<E>;
+
+fn temp_dir() -> TempDir {
+    TempDir::new().expect("unable to create temporary working directory")
+}
+
+/// sled releases its directory lock from a background thread a little after
+/// the last handle is dropped, so a quick reopen has to wait for it.
+fn open_sled(path: &Path) -> Result<SledKvsEngine> {
+    for _ in 0..50 {
+        if let Ok(engine) = panic::catch_unwind(|| SledKvsEngine::open(path.to_path_buf())) {
+            return Ok(engine);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(SledKvsEngine::open(path.to_path_buf()))
+}
+
+// Should get previously stored value
+fn get_stored_value<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should overwrite existent value
+fn overwrite_value<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key
+fn get_non_existent_value<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+fn remove_non_existent_key<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+
+    let err = engine.remove("key1".to_owned()).unwrap_err();
+    assert_eq!(err.downcast_ref::<KvsError>(), Some(&KvsError::KeyNotFound));
+
+    Ok(())
+}
+
+fn remove_key<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    // a removed key is gone, removing it again must fail
+    assert!(engine.remove("key1".to_owned()).is_err());
+
+    // and it can be written again
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+fn empty_key_and_value<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+
+    engine.set("".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "".to_owned())?;
+
+    assert_eq!(engine.get("".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key1".to_owned())?, Some("".to_owned()));
+
+    Ok(())
+}
+
+fn large_value<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+
+    let key = "k".repeat(4 * 1024);
+    let value: String = (0..1024 * 1024)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+
+    engine.set(key.clone(), value.clone())?;
+    engine.set("small".to_owned(), "value".to_owned())?;
+
+    assert_eq!(engine.get(key)?, Some(value));
+    assert_eq!(engine.get("small".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+fn scan<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+
+    assert!(engine.scan(Bound::Unbounded, 10)?.is_empty());
+
+    for i in (0..10).rev() {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.set("key3".to_owned(), "value33".to_owned())?;
+    engine.remove("key5".to_owned())?;
+
+    let pair = |i: u32, value: &str| (format!("key{}", i), value.to_owned());
+
+    // in key order, with overwrites and removals applied
+    assert_eq!(
+        engine.scan(Bound::Unbounded, 4)?,
+        vec![
+            pair(0, "value0"),
+            pair(1, "value1"),
+            pair(2, "value2"),
+            pair(3, "value33"),
+        ]
+    );
+    assert_eq!(
+        engine.scan(Bound::Excluded("key3".to_owned()), 3)?,
+        vec![pair(4, "value4"), pair(6, "value6"), pair(7, "value7")]
+    );
+    assert_eq!(
+        engine.scan(Bound::Included("key8".to_owned()), 10)?,
+        vec![pair(8, "value8"), pair(9, "value9")]
+    );
+    assert!(engine
+        .scan(Bound::Excluded("key9".to_owned()), 10)?
+        .is_empty());
+
+    Ok(())
+}
+
+fn concurrent_set<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+
+    let barrier = Arc::new(Barrier::new(101));
+    for i in 0..100 {
+        let engine = engine.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            engine
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..100 {
+        assert_eq!(
+            engine.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    Ok(())
+}
+
+fn concurrent_get<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..20 {
+        let engine = engine.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    engine.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// Only run for engines that persist data across reopen
+fn reopen_persists<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    engine.set("key3".to_owned(), "value4".to_owned())?;
+    engine.remove("key3".to_owned())?;
+
+    drop(engine);
+    let engine = open(temp_dir.path())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    assert!(engine.remove("key3".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Only run for engines that keep nothing across reopen
+fn reopen_is_empty<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    drop(engine);
+    let engine = open(temp_dir.path())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+macro_rules! conformance {
+    ($name:ident, $open:expr, persistent) => {
+        conformance!(@tests $name, $open, reopen_persists);
+    };
+    ($name:ident, $open:expr, volatile) => {
+        conformance!(@tests $name, $open, reopen_is_empty);
+    };
+    (@tests $name:ident, $open:expr, $reopen:ident) => {
+        mod $name {
+            use super::*;
+
+            conformance!(@test $open;
+                get_stored_value,
+                overwrite_value,
+                get_non_existent_value,
+                remove_non_existent_key,
+                remove_key,
+                empty_key_and_value,
+                large_value,
+                scan,
+                concurrent_set,
+                concurrent_get,
+                $reopen
+            );
+        }
+    };
+    (@test $open:expr; $($check:ident),+ $(,)?) => {
+        $(
+            #[test]
+            fn $check() -> Result<()> {
+                super::$check($open)
+            }
+        )+
+    };
+}
+
+conformance!(kv_store, |path| KvStore::open(path), persistent);
+conformance!(sled_kvs, open_sled, persistent);
+conformance!(
+    inmem,
+    |path| Ok(InMemEngine::open(path.to_path_buf())),
+    volatile
+);
+conformance!(
+    layered,
+    |path| Ok(EngineBuilder::new(KvStore::open(path)?)
+        .layer(ReadCacheLayer::new(16))
+        .layer(SizeLimitLayer::new(Some(1 << 16), Some(1 << 24)))
+        .layer(MetricsLayer::new(EngineMetrics::new()))
+        .build()),
+    persistent
+);
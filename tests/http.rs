@@ -0,0 +1,166 @@
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use kvs::layers::SizeLimitLayer;
+use kvs::{EngineBuilder, HttpGateway, InMemEngine, KvsEngine, Result};
+use reqwest::{header, Client, StatusCode};
+use serde_json::{json, Value};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+fn start_gateway<E: KvsEngine>(addr: SocketAddr, engine: E, limits: SizeLimitLayer) {
+    let gateway = HttpGateway::new(addr, engine, Logger::root(Discard, o!())).with_limits(limits);
+    thread::spawn(move || actix_web::rt::System::new().block_on(gateway.run()));
+    thread::sleep(Duration::from_millis(500));
+}
+
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+#[test]
+fn get_put_delete() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4050".parse()?;
+    let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+    start_gateway(addr, engine.clone(), SizeLimitLayer::default());
+    let url = |key: &str| format!("http://{}/kv/{}", addr, key);
+
+    block_on(async {
+        let client = Client::new();
+
+        let response = client.get(url("key1")).send().await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            response.json::<Value>().await?,
+            json!({ "error": "Key not found" })
+        );
+
+        let response = client.put(url("key1")).body("value1").send().await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        let response = client.get(url("key1")).send().await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(response.text().await?, "value1");
+
+        // keys may hold slashes and escapes
+        client
+            .put(url("dir/with%20space"))
+            .body("nested")
+            .send()
+            .await?;
+        assert_eq!(
+            engine.get("dir/with space".to_owned())?,
+            Some("nested".to_owned())
+        );
+
+        let response = client.delete(url("key1")).send().await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = client.delete(url("key1")).send().await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = client
+            .put(url("binary"))
+            .body(vec![0xff, 0xfe])
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn list_by_prefix() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4051".parse()?;
+    let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+    for i in 0..25 {
+        engine.set(format!("user/{:02}", i), i.to_string())?;
+    }
+    engine.set("users".to_owned(), "x".to_owned())?;
+    engine.set("a".to_owned(), "x".to_owned())?;
+    start_gateway(addr, engine, SizeLimitLayer::default());
+
+    block_on(async {
+        let client = Client::new();
+        let list = |query: String| {
+            let request = client.get(format!("http://{}/kv?{}", addr, query));
+            async move { Ok::<_, failure::Error>(request.send().await?.json::<Value>().await?) }
+        };
+
+        let page = list("prefix=user/&limit=10".to_owned()).await?;
+        assert_eq!(page["items"].as_array().unwrap().len(), 10);
+        assert_eq!(page["items"][0], json!({ "key": "user/00", "value": "0" }));
+        assert_eq!(page["next"], "user/09");
+
+        // paging all the way through finds every key once
+        let mut keys = Vec::new();
+        let mut after = String::new();
+        loop {
+            let page = list(format!("prefix=user/&limit=7&after={}", after)).await?;
+            for item in page["items"].as_array().unwrap() {
+                keys.push(item["key"].as_str().unwrap().to_owned());
+            }
+            match page["next"].as_str() {
+                Some(next) => after = next.to_owned(),
+                None => break,
+            }
+        }
+        let expected: Vec<_> = (0..25).map(|i| format!("user/{:02}", i)).collect();
+        assert_eq!(keys, expected);
+
+        let page = list("prefix=nope".to_owned()).await?;
+        assert_eq!(page, json!({ "items": [], "next": null }));
+        let page = list(String::new()).await?;
+        assert_eq!(page["items"].as_array().unwrap().len(), 27);
+
+        let response = client
+            .get(format!("http://{}/kv?limit=0", addr))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn size_limits_are_payload_too_large() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4052".parse()?;
+    let limits = SizeLimitLayer::new(Some(8), Some(1024));
+    let engine = EngineBuilder::new(InMemEngine::open(temp_dir.path().to_path_buf()))
+        .layer(limits)
+        .build();
+    start_gateway(addr, engine, limits);
+
+    block_on(async {
+        let client = Client::new();
+        let url = |key: &str| format!("http://{}/kv/{}", addr, key);
+
+        let response = client.put(url("key")).body("v".repeat(2048)).send().await?;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let response = client.put(url("much-too-long")).body("v").send().await?;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            response.json::<Value>().await?,
+            json!({ "error": "Key of 13 bytes exceeds the limit of 8" })
+        );
+        let response = client.put(url("key")).body("v").send().await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        Ok(())
+    })
+}
@@ -1,4 +1,7 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Bound;
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -127,6 +130,133 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Keys overwritten and removed in later segments must end up the same after
+// the segments are read in parallel on reopen.
+#[test]
+fn reopen_across_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_size: 512,
+        shards: 1,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let mut expected = BTreeMap::new();
+    for i in 0..40 {
+        let key = format!("key{}", i % 15);
+        if i % 7 == 6 {
+            if store.remove(key.clone()).is_ok() {
+                expected.remove(&key);
+            }
+        } else {
+            store.set(key.clone(), format!("value{}", i))?;
+            expected.insert(key, format!("value{}", i));
+        }
+    }
+    let segments = WalkDir::new(temp_dir.path()).into_iter().count() - 1;
+    assert!(segments > 2, "only {} segments", segments);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let stored = store.scan(Bound::Unbounded, usize::MAX)?;
+    assert_eq!(stored, expected.into_iter().collect::<Vec<_>>());
+
+    Ok(())
+}
+
+// Keys are spread over shard directories, and the shard count a store was
+// created with sticks.
+#[test]
+fn sharded_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        shards: 3,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    assert!(temp_dir.path().join("MANIFEST").is_file());
+    for i in 0..3 {
+        let shard = temp_dir.path().join(format!("shard-{}", i));
+        assert!(WalkDir::new(shard).into_iter().count() > 1);
+    }
+
+    let options = KvStoreOptions {
+        shards: 5,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(!temp_dir.path().join("shard-3").exists());
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.scan(Bound::Unbounded, 10)?.len(), 10);
+    assert_eq!(store.stats().keys, 100);
+
+    Ok(())
+}
+
+// A store created before sharding keeps its segments in place.
+#[test]
+fn unsharded_store_stays_unsharded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        shards: 1,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert!(!temp_dir.path().join("MANIFEST").exists());
+    assert!(!temp_dir.path().join("shard-0").exists());
+
+    Ok(())
+}
+
+// Segments written before tombstones were null mark them with "rm".
+#[test]
+fn reads_baseline_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut segment = Vec::new();
+    for (key, value) in &[("a", "1"), ("b", "2"), ("a", "rm"), ("c", "3")] {
+        let record = format!(r#"{{"key":"{}","value":"{}"}}"#, key, value);
+        segment.extend_from_slice(&(record.len() as u64).to_be_bytes());
+        segment.extend_from_slice(record.as_bytes());
+    }
+    fs::write(temp_dir.path().join("0_kv_0.dat"), segment)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(store.get("b".to_owned())?, Some("2".to_owned()));
+    assert!(store.remove("a".to_owned()).is_err());
+    // a value that happens to be the old tombstone is kept now
+    store.set("d".to_owned(), "rm".to_owned())?;
+    store.remove("c".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    let pairs = store.scan(Bound::Unbounded, 10)?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("b".to_owned(), "2".to_owned()),
+            ("d".to_owned(), "rm".to_owned())
+        ]
+    );
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
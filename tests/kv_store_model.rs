@@ -0,0 +1,102 @@
+//! Model-based property tests for `KvStore`.
+//!
+//! Random operation sequences run against both the store and a `BTreeMap`, and
+//! every observable result must agree. Tiny segments keep rotation and compaction
+//! busy, and proptest shrinks any failure to a minimal sequence.
+
+use std::collections::BTreeMap;
+
+use kvs::{KvStore, KvStoreOptions, KvsEngine};
+use proptest::prelude::*;
+use tempfile::TempDir;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+    Get(String),
+    Reopen,
+    Compact,
+}
+
+fn key() -> impl Strategy<Value = String> {
+    // a small key space so operations keep hitting the same keys
+    (0..8u8).prop_map(|i| format!("key{}", i))
+}
+
+fn value() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[a-z0-9]{0,12}",
+        // the old on-disk tombstone, which must now be an ordinary value
+        Just("rm".to_owned()),
+        "[ -~]{64,256}",
+    ]
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        6 => (key(), value()).prop_map(|(k, v)| Op::Set(k, v)),
+        3 => key().prop_map(Op::Remove),
+        3 => key().prop_map(Op::Get),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Compact),
+    ]
+}
+
+fn check_all(store: &KvStore, model: &BTreeMap<String, String>) -> Result<(), TestCaseError> {
+    for i in 0..8 {
+        let key = format!("key{}", i);
+        prop_assert_eq!(store.get(key.clone()).unwrap(), model.get(&key).cloned());
+    }
+    Ok(())
+}
+
+fn run(max_segment_size: u64, ops: Vec<Op>) -> Result<(), TestCaseError> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_size,
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap();
+    let mut model = BTreeMap::new();
+
+    for op in ops {
+        match op {
+            Op::Set(key, value) => {
+                store.set(key.clone(), value.clone()).unwrap();
+                model.insert(key, value);
+            }
+            Op::Remove(key) => {
+                let removed = store.remove(key.clone()).is_ok();
+                prop_assert_eq!(removed, model.remove(&key).is_some());
+            }
+            Op::Get(key) => {
+                prop_assert_eq!(store.get(key.clone()).unwrap(), model.get(&key).cloned());
+            }
+            Op::Reopen => {
+                drop(store);
+                store = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap();
+                check_all(&store, &model)?;
+            }
+            Op::Compact => {
+                store.compact().unwrap();
+                check_all(&store, &model)?;
+            }
+        }
+    }
+
+    check_all(&store, &model)?;
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    check_all(&store, &model)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn matches_model(max_segment_size in 16..512u64, ops in prop::collection::vec(op(), 1..80)) {
+        run(max_segment_size, ops)?;
+    }
+}
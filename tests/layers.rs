@@ -0,0 +1,173 @@
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use kvs::layers::{
+    EngineMetrics, MetricsLayer, OpStats, ReadCacheLayer, SizeLimitLayer, TracingLayer,
+};
+use kvs::{EngineBuilder, InMemEngine, KvsEngine, KvsError, Result};
+use slog::{o, Drain, Logger};
+use tempfile::TempDir;
+
+fn inmem(temp_dir: &TempDir) -> InMemEngine {
+    InMemEngine::open(temp_dir.path().to_path_buf())
+}
+
+#[test]
+fn metrics_count_calls() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let metrics = EngineMetrics::new();
+    let engine = EngineBuilder::new(inmem(&temp_dir))
+        .layer(MetricsLayer::new(metrics.clone()))
+        .build();
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.get("key1".to_owned())?;
+    engine.get("missing".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    assert!(engine.remove("key2".to_owned()).is_err());
+    engine.scan(Bound::Unbounded, 10)?;
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.sets.calls, 2);
+    assert_eq!(snapshot.gets.calls, 2);
+    assert_eq!(snapshot.get_hits, 1);
+    assert_eq!(snapshot.removes.calls, 2);
+    assert_eq!(snapshot.removes.errors, 1);
+    assert_eq!(snapshot.scans.calls, 1);
+    assert_eq!(snapshot.sets.errors, 0);
+
+    Ok(())
+}
+
+#[test]
+fn read_cache_serves_repeated_gets() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    // counts the gets that reach the engine under the cache
+    let inner = EngineMetrics::new();
+    let engine = EngineBuilder::new(inmem(&temp_dir))
+        .layer(MetricsLayer::new(inner.clone()))
+        .layer(ReadCacheLayer::new(2))
+        .build();
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    for _ in 0..5 {
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(engine.get("missing".to_owned())?, None);
+    }
+    assert_eq!(inner.snapshot().gets.calls, 2);
+
+    // writes invalidate their key
+    engine.set("key1".to_owned(), "other".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("other".to_owned()));
+    engine.set("missing".to_owned(), "found".to_owned())?;
+    assert_eq!(engine.get("missing".to_owned())?, Some("found".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(inner.snapshot().gets.calls, 5);
+
+    // a third key evicts the least recently used
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    engine.get("key3".to_owned())?;
+    engine.get("key1".to_owned())?;
+    engine.get("missing".to_owned())?;
+    assert_eq!(inner.snapshot().gets.calls, 7);
+
+    // scans bypass the cache
+    assert_eq!(engine.scan(Bound::Unbounded, 10)?.len(), 2);
+    assert_eq!(inner.snapshot().scans.calls, 1);
+
+    Ok(())
+}
+
+#[test]
+fn size_limits_refuse_large_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = EngineBuilder::new(inmem(&temp_dir))
+        .layer(SizeLimitLayer::new(Some(8), Some(16)))
+        .build();
+
+    engine.set("key".to_owned(), "v".repeat(16))?;
+
+    let err = engine.set("k".repeat(9), "value".to_owned()).unwrap_err();
+    match err.downcast_ref::<KvsError>() {
+        Some(KvsError::KeyTooLarge { size: 9, limit: 8 }) => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+    let err = engine.set("key".to_owned(), "v".repeat(17)).unwrap_err();
+    match err.downcast_ref::<KvsError>() {
+        Some(KvsError::ValueTooLarge {
+            size: 17,
+            limit: 16,
+        }) => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+    assert!(engine.get("k".repeat(9)).is_err());
+    assert!(engine.remove("k".repeat(9)).is_err());
+
+    assert_eq!(engine.get("key".to_owned())?, Some("v".repeat(16)));
+
+    Ok(())
+}
+
+#[test]
+fn mean_time_survives_huge_call_counts() {
+    let stats = OpStats {
+        calls: 1 << 32,
+        errors: 0,
+        total_time: Duration::from_secs(1 << 32),
+    };
+    assert_eq!(stats.mean_time(), Duration::from_secs(1));
+    let stats = OpStats {
+        calls: 3 << 32,
+        ..stats
+    };
+    assert_eq!(stats.mean_time(), Duration::from_nanos(333_333_333));
+}
+
+/// Keeps the messages of every record logged through it.
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<String>>>);
+
+impl Drain for Capture {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        _: &slog::OwnedKVList,
+    ) -> std::result::Result<(), slog::Never> {
+        self.0.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
+#[test]
+fn layers_apply_in_order() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let capture = Capture::default();
+    let outer = EngineMetrics::new();
+    let engine = EngineBuilder::new(inmem(&temp_dir))
+        .layer(SizeLimitLayer::new(Some(8), None))
+        .layer(Some(MetricsLayer::new(outer.clone())))
+        .layer(None::<ReadCacheLayer>)
+        .layer(TracingLayer::new(Logger::root(capture.clone(), o!())))
+        .build();
+
+    engine.set("key".to_owned(), "value".to_owned())?;
+    engine.get("key".to_owned())?;
+    assert!(engine.set("k".repeat(9), "value".to_owned()).is_err());
+
+    // the limit sits under the metrics and the tracing, so both see the refusal
+    let snapshot = outer.snapshot();
+    assert_eq!(snapshot.sets.calls, 2);
+    assert_eq!(snapshot.sets.errors, 1);
+    assert_eq!(
+        *capture.0.lock().unwrap(),
+        vec!["set".to_owned(), "get".to_owned(), "set failed".to_owned()]
+    );
+
+    Ok(())
+}
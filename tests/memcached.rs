@@ -0,0 +1,250 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use kvs::layers::SizeLimitLayer;
+use kvs::{InMemEngine, KvsEngine, MemcachedServer, Result};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+use tokio::runtime::Builder;
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Result<Client> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    /// Sends `request` and checks the server answers with exactly `reply`.
+    fn expect(&mut self, request: &str, reply: &str) -> Result<()> {
+        self.writer.write_all(request.as_bytes())?;
+        let mut buf = vec![0; reply.len()];
+        self.reader.read_exact(&mut buf)?;
+        assert_eq!(String::from_utf8(buf)?, reply, "reply to {:?}", request);
+        Ok(())
+    }
+
+    /// The cas unique `gets` finds for `key`.
+    fn cas_unique(&mut self, key: &str) -> Result<u64> {
+        self.writer
+            .write_all(format!("gets {}\r\n", key).as_bytes())?;
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        assert_eq!(words[..2], ["VALUE", key]);
+        let unique = words[4].parse()?;
+        let mut rest = vec![0; words[3].parse::<usize>()? + 2];
+        self.reader.read_exact(&mut rest)?;
+        line.clear();
+        self.reader.read_line(&mut line)?;
+        assert_eq!(line, "END\r\n");
+        Ok(unique)
+    }
+}
+
+fn start_server<E: KvsEngine>(addr: SocketAddr, engine: E, limits: SizeLimitLayer) -> Result<()> {
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(2)
+        .max_blocking_threads(2)
+        .enable_io()
+        .build()?;
+    let server =
+        MemcachedServer::new(addr, engine, Logger::root(Discard, o!())).with_limits(limits);
+    thread::spawn(move || runtime.block_on(server.run()));
+    thread::sleep(Duration::from_millis(200));
+    Ok(())
+}
+
+#[test]
+fn storage_commands() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4040".parse()?;
+    let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+    start_server(addr, engine.clone(), SizeLimitLayer::default())?;
+    let mut client = Client::connect(addr)?;
+
+    client.expect("set key1 0 0 6\r\nvalue1\r\n", "STORED\r\n")?;
+    client.expect("get key1\r\n", "VALUE key1 0 6\r\nvalue1\r\nEND\r\n")?;
+    // plain items are stored as they are
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.expect("get nope\r\n", "END\r\n")?;
+
+    client.expect("add key1 0 0 1\r\nx\r\n", "NOT_STORED\r\n")?;
+    client.expect("add key2 7 0 4\r\n\r\n\0\x01\r\n", "STORED\r\n")?;
+    client.expect("replace key3 0 0 1\r\nx\r\n", "NOT_STORED\r\n")?;
+    client.expect("replace key2 42 0 2\r\nab\r\n", "STORED\r\n")?;
+    client.expect(
+        "get key1 nope key2\r\n",
+        "VALUE key1 0 6\r\nvalue1\r\nVALUE key2 42 2\r\nab\r\nEND\r\n",
+    )?;
+
+    client.expect("delete key1\r\n", "DELETED\r\n")?;
+    client.expect("delete key1\r\n", "NOT_FOUND\r\n")?;
+
+    // noreply is silent, and pipelined commands are answered in order
+    client.expect(
+        "set key3 0 0 1 noreply\r\nx\r\nset key4 0 0 1\r\ny\r\nget key3 key4\r\n",
+        "STORED\r\nVALUE key3 0 1\r\nx\r\nVALUE key4 0 1\r\ny\r\nEND\r\n",
+    )?;
+
+    client.expect("bogus\r\n", "ERROR\r\n")?;
+    client.expect(
+        "set key5 x 0 1\r\n",
+        "CLIENT_ERROR bad command line format\r\n",
+    )?;
+    client.expect(
+        &format!("get {}\r\n", "k".repeat(251)),
+        "CLIENT_ERROR bad command line format\r\n",
+    )?;
+    client.expect(
+        "version\r\n",
+        &format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")),
+    )?;
+
+    // a data block that isn't the length promised loses the connection
+    client.expect(
+        "set key5 0 0 1\r\nxyz\r\n",
+        "CLIENT_ERROR bad data chunk\r\n",
+    )?;
+    assert_eq!(client.reader.read(&mut [0; 1])?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn cas_and_arithmetic() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4041".parse()?;
+    start_server(
+        addr,
+        InMemEngine::open(temp_dir.path().to_path_buf()),
+        SizeLimitLayer::default(),
+    )?;
+    let mut client = Client::connect(addr)?;
+
+    client.expect("cas key 0 0 1 1\r\nx\r\n", "NOT_FOUND\r\n")?;
+    client.expect("set key 3 0 1\r\na\r\n", "STORED\r\n")?;
+    let unique = client.cas_unique("key")?;
+    client.expect(&format!("cas key 3 0 1 {}\r\nb\r\n", unique), "STORED\r\n")?;
+    // someone else got there first
+    client.expect(&format!("cas key 3 0 1 {}\r\nc\r\n", unique), "EXISTS\r\n")?;
+    assert_ne!(client.cas_unique("key")?, unique);
+    client.expect("get key\r\n", "VALUE key 3 1\r\nb\r\nEND\r\n")?;
+
+    client.expect("incr counter 1\r\n", "NOT_FOUND\r\n")?;
+    client.expect("set counter 5 0 2\r\n10\r\n", "STORED\r\n")?;
+    client.expect("incr counter 32\r\n", "42\r\n")?;
+    client.expect("decr counter 50\r\n", "0\r\n")?;
+    client.expect(
+        "incr counter 18446744073709551615\r\n",
+        "18446744073709551615\r\n",
+    )?;
+    client.expect("incr counter 2\r\n", "1\r\n")?;
+    // flags survive arithmetic
+    client.expect("get counter\r\n", "VALUE counter 5 1\r\n1\r\nEND\r\n")?;
+    client.expect(
+        "incr key 1\r\n",
+        "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n",
+    )?;
+    client.expect(
+        "incr counter -1\r\n",
+        "CLIENT_ERROR invalid numeric delta argument\r\n",
+    )?;
+
+    // racing increments from many connections all count
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = Client::connect(addr)?;
+                for _ in 0..50 {
+                    client.writer.write_all(b"incr counter 1\r\n")?;
+                    let mut line = String::new();
+                    client.reader.read_line(&mut line)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    client.expect("get counter\r\n", "VALUE counter 5 3\r\n201\r\nEND\r\n")?;
+
+    Ok(())
+}
+
+#[test]
+fn expiry_and_limits() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4042".parse()?;
+    start_server(
+        addr,
+        InMemEngine::open(temp_dir.path().to_path_buf()),
+        SizeLimitLayer::new(Some(16), Some(64)),
+    )?;
+    let mut client = Client::connect(addr)?;
+
+    client.expect("set gone 0 -1 1\r\nx\r\n", "STORED\r\n")?;
+    client.expect("get gone\r\n", "END\r\n")?;
+    // an absolute time long past
+    client.expect("set gone 0 2592001 1\r\nx\r\n", "STORED\r\n")?;
+    client.expect("get gone\r\n", "END\r\n")?;
+    client.expect("add gone 0 0 1\r\ny\r\n", "STORED\r\n")?;
+    client.expect("get gone\r\n", "VALUE gone 0 1\r\ny\r\nEND\r\n")?;
+
+    client.expect("set soon 0 1 1\r\nx\r\n", "STORED\r\n")?;
+    client.expect("set later 0 3600 1\r\nx\r\n", "STORED\r\n")?;
+    thread::sleep(Duration::from_millis(2100));
+    client.expect("get soon later\r\n", "VALUE later 0 1\r\nx\r\nEND\r\n")?;
+    client.expect("delete soon\r\n", "NOT_FOUND\r\n")?;
+
+    // the oversized data is skipped, and the connection carries on
+    client.expect(
+        &format!("set big 0 0 65\r\n{}\r\n", "v".repeat(65)),
+        "SERVER_ERROR object too large for cache\r\n",
+    )?;
+    client.expect(
+        &format!("set {} 0 0 1\r\nx\r\n", "k".repeat(17)),
+        "CLIENT_ERROR key too long\r\n",
+    )?;
+    client.expect("get big\r\n", "END\r\n")?;
+
+    Ok(())
+}
+
+#[test]
+fn default_item_size() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4043".parse()?;
+    start_server(
+        addr,
+        InMemEngine::open(temp_dir.path().to_path_buf()),
+        SizeLimitLayer::default(),
+    )?;
+    let mut client = Client::connect(addr)?;
+
+    // without a value limit, blocks over a megabyte are skipped
+    let big = 1024 * 1024 + 1;
+    client.expect(
+        &format!("set big 0 0 {}\r\n{}\r\n", big, "v".repeat(big)),
+        "SERVER_ERROR object too large for cache\r\n",
+    )?;
+    client.expect("get big\r\n", "END\r\n")?;
+
+    // a block that can't end closes the connection
+    client.expect(
+        &format!("set huge 0 0 {}\r\n", u64::MAX),
+        "SERVER_ERROR object too large for cache\r\n",
+    )?;
+    assert_eq!(client.reader.read(&mut [0; 1])?, 0);
+
+    Ok(())
+}
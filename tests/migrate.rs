@@ -0,0 +1,280 @@
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+
+use assert_cmd::prelude::*;
+use kvs::{EncryptionKey, KvStore, KvStoreOptions, KvsEngine, Migration, Result, SledKvsEngine};
+use predicates::str::contains;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn fill<E: KvsEngine>(engine: &E, n: u32) -> Result<()> {
+    for i in 0..n {
+        engine.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    // removed keys must not be carried over
+    engine.remove("key0007".to_owned())?;
+    Ok(())
+}
+
+fn all<E: KvsEngine>(engine: &E) -> Result<Vec<(String, String)>> {
+    engine.scan(Bound::Unbounded, usize::MAX)
+}
+
+#[test]
+fn migrate_kvs_to_sled() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let source = KvStore::open(temp_dir.path().join("kvs"))?;
+    let dest = SledKvsEngine::open(temp_dir.path().join("sled"));
+    let checkpoint = temp_dir.path().join("checkpoint");
+    fill(&source, 250)?;
+
+    let summary = Migration::new(source.clone(), dest.clone(), checkpoint.clone())?
+        .batch_size(32)
+        .run()?;
+
+    assert_eq!(summary.keys, 249);
+    assert_eq!(all(&dest)?, all(&source)?);
+    assert!(!checkpoint.exists());
+
+    Ok(())
+}
+
+#[test]
+fn migrate_resumes_from_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let source = SledKvsEngine::open(temp_dir.path().join("sled"));
+    let checkpoint = temp_dir.path().join("checkpoint");
+    fill(&source, 100)?;
+
+    let dest = KvStore::open(temp_dir.path().join("kvs"))?;
+    let mut migration = Migration::new(source.clone(), dest, checkpoint.clone())?.batch_size(30);
+    assert!(migration.step()?);
+    assert!(migration.step()?);
+    assert_eq!(migration.copied(), 60);
+    assert!(migration.finish().is_err());
+
+    // interrupted: pick up where the checkpoint says
+    let dest = KvStore::open(temp_dir.path().join("kvs"))?;
+    let migration = Migration::new(source.clone(), dest.clone(), checkpoint.clone())?;
+    assert_eq!(migration.copied(), 60);
+    let summary = migration.run()?;
+
+    assert_eq!(summary.keys, 99);
+    assert_eq!(all(&dest)?, all(&source)?);
+    assert!(!checkpoint.exists());
+
+    Ok(())
+}
+
+#[test]
+fn migrate_refuses_non_empty_destination() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let source = KvStore::open(temp_dir.path().join("source"))?;
+    let dest = KvStore::open(temp_dir.path().join("dest"))?;
+    fill(&source, 10)?;
+    dest.set("key".to_owned(), "value".to_owned())?;
+
+    assert!(Migration::new(source, dest, temp_dir.path().join("checkpoint")).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn migrate_detects_diverging_destination() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let source = KvStore::open(temp_dir.path().join("source"))?;
+    let dest = KvStore::open(temp_dir.path().join("dest"))?;
+    fill(&source, 10)?;
+
+    let mut migration = Migration::new(source, dest.clone(), temp_dir.path().join("checkpoint"))?;
+    while migration.step()? {}
+    dest.set("extra".to_owned(), "value".to_owned())?;
+
+    assert!(migration.finish().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let source = KvStore::open(temp_dir.path().join("kvs"))?;
+    fill(&source, 20)?;
+    let expected = all(&source)?;
+    drop(source);
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args([
+            "--from",
+            "kvs",
+            "--from-dir",
+            "kvs",
+            "--to",
+            "sled",
+            "--to-dir",
+            "sled",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let dest = SledKvsEngine::open(temp_dir.path().join("sled"));
+    assert_eq!(all(&dest)?, expected);
+    assert!(!temp_dir.path().join("sled.migrate").exists());
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args([
+            "--from",
+            "inmem",
+            "--from-dir",
+            "a",
+            "--to",
+            "kvs",
+            "--to-dir",
+            "b",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("inmem"));
+
+    Ok(())
+}
+
+#[test]
+fn cli_migrate_default_checkpoints() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let source = KvStore::open(temp_dir.path().join("kvs"))?;
+    fill(&source, 20)?;
+    let expected = all(&source)?;
+    drop(source);
+
+    // a stale checkpoint of another destination is left alone
+    fs::write(temp_dir.path().join("data.migrate"), "not a checkpoint")?;
+    let migrate = |to_dir: &str, current_dir: &Path| {
+        Command::cargo_bin("kvs-migrate")
+            .unwrap()
+            .args([
+                "--from",
+                "kvs",
+                "--from-dir",
+                temp_dir.path().join("kvs").to_str().unwrap(),
+                "--to",
+                "sled",
+                "--to-dir",
+                to_dir,
+            ])
+            .current_dir(current_dir)
+            .assert()
+            .success();
+    };
+    migrate("data.v2", temp_dir.path());
+    assert_eq!(
+        all(&SledKvsEngine::open(temp_dir.path().join("data.v2")))?,
+        expected
+    );
+
+    let dest = temp_dir.path().join("dest");
+    fs::create_dir(&dest)?;
+    migrate(".", &dest);
+    assert_eq!(all(&SledKvsEngine::open(dest))?, expected);
+
+    Ok(())
+}
+
+#[test]
+fn cli_migrate_between_keys() -> Result<()> {
+    let key = "AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA=";
+    let temp_dir = TempDir::new()?;
+    let source = KvStore::open(temp_dir.path().join("plain"))?;
+    fill(&source, 20)?;
+    let expected = all(&source)?;
+    drop(source);
+    let segments = |dir: &str| -> Result<Vec<_>> {
+        let mut files = Vec::new();
+        for entry in walkdir::WalkDir::new(temp_dir.path().join(dir)) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                files.push((entry.path().to_owned(), std::fs::read(entry.path())?));
+            }
+        }
+        Ok(files)
+    };
+    let before = segments("plain")?;
+
+    // a key set for both sides no longer leaks into the plaintext source
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args([
+            "--from",
+            "kvs",
+            "--from-dir",
+            "plain",
+            "--to",
+            "kvs",
+            "--to-dir",
+            "sealed",
+            "--dest-key-env",
+            "DEST_KEY",
+        ])
+        .env("KVS_ENCRYPTION_KEY", key)
+        .env("DEST_KEY", key)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(segments("plain")?, before);
+
+    assert!(KvStore::open(temp_dir.path().join("sealed")).is_err());
+    let options = KvStoreOptions {
+        encryption_key: Some(EncryptionKey::from_base64(key)?),
+        ..Default::default()
+    };
+    let dest = KvStore::open_with_options(temp_dir.path().join("sealed"), options)?;
+    assert_eq!(all(&dest)?, expected);
+    drop(dest);
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args([
+            "--from",
+            "kvs",
+            "--from-dir",
+            "sealed",
+            "--source-key-env",
+            "SOURCE_KEY",
+            "--to",
+            "sled",
+            "--to-dir",
+            "sled",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("SOURCE_KEY"));
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args([
+            "--from",
+            "kvs",
+            "--from-dir",
+            "sealed",
+            "--source-key-env",
+            "SOURCE_KEY",
+            "--to",
+            "sled",
+            "--to-dir",
+            "sled",
+        ])
+        .env("SOURCE_KEY", key)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let dest = SledKvsEngine::open(temp_dir.path().join("sled"));
+    assert_eq!(all(&dest)?, expected);
+
+    Ok(())
+}
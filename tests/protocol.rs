@@ -0,0 +1,566 @@
+use std::io::{self, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::ops::Bound;
+use std::thread;
+use std::time::Duration;
+
+use bincode::serialize;
+use kvs::common::*;
+use kvs::layers::SizeLimitLayer;
+use kvs::{
+    AsyncKvsServer, EngineBuilder, InMemEngine, KvsClient, KvsEngine, KvsError, KvsServer, Result,
+    SharedQueueThreadPool, ThreadPool,
+};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+use tokio::runtime::Builder;
+
+fn requests() -> Vec<Request> {
+    vec![
+        Request::Get(GetRequest {
+            key: b"key\n".to_vec(),
+        }),
+        Request::Set(SetRequest {
+            key: vec![10; 10],
+            value: b"value\nwith newlines\n".to_vec(),
+        }),
+        Request::Remove(RemoveRequest { key: Vec::new() }),
+        Request::Scan(ScanRequest {
+            start: Bound::Unbounded,
+            limit: 10,
+        }),
+        Request::Scan(ScanRequest {
+            start: Bound::Included(b"a".to_vec()),
+            limit: u64::MAX,
+        }),
+        Request::Scan(ScanRequest {
+            start: Bound::Excluded(b"b".to_vec()),
+            limit: 0,
+        }),
+    ]
+}
+
+#[test]
+fn read_request_matches_bincode() -> Result<()> {
+    let mut wire = Vec::new();
+    for request in requests() {
+        wire.extend(serialize(&request)?);
+    }
+
+    let mut reader = Cursor::new(wire);
+    for request in requests() {
+        assert_eq!(read_request(&mut reader, None, None)?, request);
+    }
+    assert!(read_request(&mut reader, None, None).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn read_request_skips_oversized_fields() -> Result<()> {
+    let big = Request::Set(SetRequest {
+        key: b"key".to_vec(),
+        value: vec![b'v'; 1 << 20],
+    });
+    let small = Request::Get(GetRequest {
+        key: b"key".to_vec(),
+    });
+    let mut wire = serialize(&big)?;
+    wire.extend(serialize(&small)?);
+
+    let mut reader = Cursor::new(wire);
+    let err = read_request(&mut reader, Some(16), Some(1024)).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<KvsError>(),
+        Some(&KvsError::ValueTooLarge {
+            size: 1 << 20,
+            limit: 1024
+        })
+    );
+    // the next request is read from where the oversized one ended
+    assert_eq!(read_request(&mut reader, Some(16), Some(1024))?, small);
+
+    let err = read_request(&mut Cursor::new(serialize(&big)?), Some(2), None).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<KvsError>(),
+        Some(&KvsError::KeyTooLarge { size: 3, limit: 2 })
+    );
+
+    // without limits, fields are still capped rather than buffered whole
+    let mut header = 1u32.to_le_bytes().to_vec();
+    header.extend(serialize(&b"key".to_vec())?);
+    header.extend((MAX_FIELD_LEN as u64 + 1).to_le_bytes());
+    let value = io::repeat(b'v').take(MAX_FIELD_LEN as u64 + 1);
+    let err = read_request(&mut Cursor::new(header).chain(value), None, None).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<KvsError>(),
+        Some(&KvsError::ValueTooLarge {
+            size: MAX_FIELD_LEN + 1,
+            limit: MAX_FIELD_LEN
+        })
+    );
+
+    // a length claiming more than is sent isn't an oversized field
+    let mut truncated = serialize(&big)?;
+    truncated.truncate(100);
+    let err = read_request(&mut Cursor::new(truncated), None, None).unwrap_err();
+    assert!(err.downcast_ref::<KvsError>().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn frames_round_trip() -> Result<()> {
+    let mut wire = Vec::new();
+    for (id, body) in requests().into_iter().enumerate() {
+        write_frame(
+            &mut wire,
+            &Envelope {
+                id: id as u64,
+                body,
+            },
+        )?;
+    }
+
+    let mut reader = Cursor::new(wire);
+    for (id, body) in requests().into_iter().enumerate() {
+        let envelope: Envelope<Request> = read_frame(&mut reader)?.unwrap();
+        assert_eq!(
+            envelope,
+            Envelope {
+                id: id as u64,
+                body
+            }
+        );
+    }
+    assert_eq!(read_frame::<_, Envelope<Request>>(&mut reader)?, None);
+
+    // a stream ending inside a frame isn't a clean end
+    let mut wire = Vec::new();
+    write_frame(&mut wire, &requests()[1])?;
+    wire.pop();
+    assert!(read_frame::<_, Request>(&mut Cursor::new(wire)).is_err());
+
+    // nor is a frame too long to read
+    let wire = (MAX_FRAME_LEN + 1).to_le_bytes();
+    assert!(read_frame::<_, Request>(&mut Cursor::new(wire)).is_err());
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Server {
+    ThreadPool,
+    Async,
+}
+
+/// Both servers, each with its own address from `port` on.
+fn servers(port: u16) -> Vec<(Server, SocketAddr)> {
+    vec![
+        (Server::ThreadPool, SocketAddr::from(([127, 0, 0, 1], port))),
+        (
+            Server::Async,
+            SocketAddr::from(([127, 0, 0, 1], port + 100)),
+        ),
+    ]
+}
+
+fn start_server<E: KvsEngine>(
+    server: Server,
+    addr: SocketAddr,
+    engine: E,
+    limits: SizeLimitLayer,
+) -> Result<()> {
+    let logger = Logger::root(Discard, o!());
+    match server {
+        Server::ThreadPool => {
+            let pool = SharedQueueThreadPool::new(2)?;
+            let server = KvsServer::new(addr, engine, String::new(), logger, pool);
+            let server = server.with_limits(limits);
+            thread::spawn(move || server.start());
+        }
+        Server::Async => {
+            let runtime = Builder::new_multi_thread()
+                .worker_threads(2)
+                .max_blocking_threads(2)
+                .enable_io()
+                .build()?;
+            let server = AsyncKvsServer::new(addr, engine, logger).with_limits(limits);
+            thread::spawn(move || runtime.block_on(server.run()));
+        }
+    }
+    thread::sleep(Duration::from_millis(200));
+    Ok(())
+}
+
+#[test]
+fn pipelined_requests_share_a_connection() -> Result<()> {
+    for (server, addr) in servers(4012) {
+        let temp_dir = TempDir::new()?;
+        let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+        start_server(server, addr, engine, SizeLimitLayer::default())?;
+
+        let client = KvsClient::new(addr, Logger::root(Discard, o!()));
+        let mut requests = Vec::new();
+        for i in 0..100 {
+            requests.push(Request::Set(SetRequest {
+                key: format!("key{}", i).into_bytes(),
+                value: vec![b'\n'; i],
+            }));
+            requests.push(Request::Get(GetRequest {
+                key: format!("key{}", i).into_bytes(),
+            }));
+        }
+        let responses = client.pipeline(requests)?;
+        assert_eq!(responses.len(), 200);
+        for (i, pair) in responses.chunks(2).enumerate() {
+            match &pair[1] {
+                Response::Value(value) => assert_eq!(value, &vec![b'\n'; i]),
+                other => panic!("unexpected response {:?}", other),
+            }
+        }
+
+        // clones take turns on the one connection
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let client = client.clone();
+                thread::spawn(move || -> Result<()> {
+                    for i in 0..10 {
+                        let key = format!("key{}", t * 10 + i);
+                        client.set(key.clone(), t.to_string())?;
+                        assert_eq!(client.get(key)?, Some(t.to_string()));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+    }
+
+    Ok(())
+}
+
+// far more than the socket buffers hold, both ways at once
+#[test]
+fn huge_pipelines_dont_deadlock() -> Result<()> {
+    for (server, addr) in servers(4016) {
+        let temp_dir = TempDir::new()?;
+        let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+        start_server(server, addr, engine, SizeLimitLayer::default())?;
+
+        let client = KvsClient::new(addr, Logger::root(Discard, o!()));
+        let requests = (0..100_000)
+            .map(|i| {
+                Request::Set(SetRequest {
+                    key: format!("key{}", i).into_bytes(),
+                    value: vec![b'v'; 64],
+                })
+            })
+            .collect();
+        let responses = client.pipeline(requests)?;
+        assert_eq!(responses.len(), 100_000);
+        assert!(responses.iter().all(|response| *response == Response::Ok));
+
+        // responses as large as the requests
+        let mut requests = Vec::new();
+        for i in 0..10_000 {
+            requests.push(Request::Set(SetRequest {
+                key: b"key".to_vec(),
+                value: vec![b'v'; 1024 + i % 2],
+            }));
+            requests.push(Request::Get(GetRequest {
+                key: b"key".to_vec(),
+            }));
+        }
+        let responses = client.pipeline(requests)?;
+        assert_eq!(responses.len(), 20_000);
+        for (i, pair) in responses.chunks(2).enumerate() {
+            assert_eq!(pair[1], Response::Value(vec![b'v'; 1024 + i % 2]));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn client_sees_size_limit_errors() -> Result<()> {
+    let logger = Logger::root(Discard, o!());
+    for (server, addr) in servers(4011) {
+        let temp_dir = TempDir::new()?;
+
+        // the engine is stricter than the wire, so both places get a turn
+        let engine = EngineBuilder::new(InMemEngine::open(temp_dir.path().to_path_buf()))
+            .layer(SizeLimitLayer::new(Some(8), Some(1024)))
+            .build();
+        start_server(
+            server,
+            addr,
+            engine,
+            SizeLimitLayer::new(Some(64), Some(1 << 16)),
+        )?;
+
+        let client = KvsClient::new(addr, logger.clone());
+        client.set("key".to_owned(), "value".to_owned())?;
+
+        let cases = vec![
+            (
+                client.set("key".to_owned(), "v".repeat(1 << 20)),
+                KvsError::ValueTooLarge {
+                    size: 1 << 20,
+                    limit: 1 << 16,
+                },
+            ),
+            (
+                client.set("key".to_owned(), "v".repeat(2048)),
+                KvsError::ValueTooLarge {
+                    size: 2048,
+                    limit: 1024,
+                },
+            ),
+            (
+                client.set("k".repeat(100), "value".to_owned()),
+                KvsError::KeyTooLarge {
+                    size: 100,
+                    limit: 64,
+                },
+            ),
+            (
+                client.remove("k".repeat(10)),
+                KvsError::KeyTooLarge { size: 10, limit: 8 },
+            ),
+        ];
+        for (result, expected) in cases {
+            let err = result.unwrap_err();
+            assert_eq!(err.downcast_ref::<KvsError>(), Some(&expected));
+        }
+        assert!(client
+            .get("k".repeat(100))
+            .unwrap_err()
+            .downcast_ref::<KvsError>()
+            .is_some());
+
+        assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn value_limit_alone_is_enforced() -> Result<()> {
+    let logger = Logger::root(Discard, o!());
+    for (server, addr) in servers(4017) {
+        let temp_dir = TempDir::new()?;
+        let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+        start_server(server, addr, engine, SizeLimitLayer::new(None, Some(1024)))?;
+
+        let client = KvsClient::new(addr, logger.clone());
+        let err = client
+            .set("key".to_owned(), "v".repeat(1 << 20))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<KvsError>(),
+            Some(&KvsError::ValueTooLarge {
+                size: 1 << 20,
+                limit: 1024
+            }),
+            "{:?}",
+            server
+        );
+
+        // keys are only held to the default cap
+        client.set("k".repeat(4096), "value".to_owned())?;
+        assert_eq!(client.get("k".repeat(4096))?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn hello_negotiates_a_shared_version() {
+    let server = Hello {
+        min_version: 2,
+        max_version: 4,
+        features: features::SCAN,
+        ..Hello::current()
+    };
+    let client = |min_version, max_version| Hello {
+        min_version,
+        max_version,
+        ..Hello::current()
+    };
+
+    assert_eq!(
+        server.negotiate(&client(1, 3)),
+        Welcome::Accepted {
+            version: 3,
+            features: features::SCAN
+        }
+    );
+    assert_eq!(
+        server.negotiate(&client(3, 9)),
+        Welcome::Accepted {
+            version: 4,
+            features: features::SCAN
+        }
+    );
+    let rejected = Welcome::Rejected(KvsError::IncompatibleProtocol {
+        min_version: 2,
+        max_version: 4,
+    });
+    assert_eq!(server.negotiate(&client(1, 1)), rejected);
+    assert_eq!(server.negotiate(&client(5, 6)), rejected);
+    let stranger = Hello {
+        magic: *b"HTTP",
+        ..client(1, 9)
+    };
+    assert_eq!(server.negotiate(&stranger), rejected);
+}
+
+#[test]
+fn server_turns_away_incompatible_clients() -> Result<()> {
+    for (server, addr) in servers(4013) {
+        let temp_dir = TempDir::new()?;
+        let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+        start_server(server, addr, engine, SizeLimitLayer::default())?;
+        let rejected = Welcome::Rejected(KvsError::IncompatibleProtocol {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        });
+
+        let future = Hello {
+            min_version: PROTOCOL_VERSION + 1,
+            max_version: PROTOCOL_VERSION + 1,
+            ..Hello::current()
+        };
+        let mut stream = TcpStream::connect(addr)?;
+        write_frame(&mut stream, &future)?;
+        assert_eq!(
+            read_frame::<_, Welcome>(&mut stream)?,
+            Some(rejected.clone())
+        );
+        assert_eq!(read_frame::<_, Welcome>(&mut stream)?, None);
+
+        // a client going straight to requests
+        let mut stream = TcpStream::connect(addr)?;
+        let request = Envelope {
+            id: 0,
+            body: requests().remove(0),
+        };
+        write_frame(&mut stream, &request)?;
+        stream.flush()?;
+        assert_eq!(read_frame::<_, Welcome>(&mut stream)?, Some(rejected));
+
+        // while this build's client gets in
+        let client = KvsClient::new(addr, Logger::root(Discard, o!()));
+        client.set("key".to_owned(), "value".to_owned())?;
+        assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn responses_are_typed() -> Result<()> {
+    for (server, addr) in servers(4014) {
+        let temp_dir = TempDir::new()?;
+        let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+        start_server(server, addr, engine, SizeLimitLayer::default())?;
+
+        let client = KvsClient::new(addr, Logger::root(Discard, o!()));
+        let responses = client.pipeline(vec![
+            Request::Set(SetRequest {
+                key: b"key".to_vec(),
+                value: b"Key not found".to_vec(),
+            }),
+            Request::Get(GetRequest {
+                key: b"key".to_vec(),
+            }),
+            Request::Get(GetRequest {
+                key: b"missing".to_vec(),
+            }),
+            Request::Remove(RemoveRequest {
+                key: b"missing".to_vec(),
+            }),
+            Request::Get(GetRequest {
+                key: vec![0xff, 0xfe],
+            }),
+        ])?;
+        assert_eq!(responses[0], Response::Ok);
+        assert_eq!(responses[1], Response::Value(b"Key not found".to_vec()));
+        assert_eq!(responses[2], Response::NotFound);
+        assert_eq!(responses[3], Response::NotFound);
+        match &responses[4] {
+            Response::InvalidRequest(_) => {}
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        // which the client maps back to what an engine returns
+        assert_eq!(
+            client.get("key".to_owned())?,
+            Some("Key not found".to_owned())
+        );
+        assert_eq!(client.get("missing".to_owned())?, None);
+        let err = client.remove("missing".to_owned()).unwrap_err();
+        assert_eq!(err.downcast_ref::<KvsError>(), Some(&KvsError::KeyNotFound));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn scans_are_capped() -> Result<()> {
+    for (server, addr) in servers(4015) {
+        let temp_dir = TempDir::new()?;
+        let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+        for i in 0..MAX_SCAN_LIMIT + 500 {
+            engine.set(format!("key{:05}", i), "value".to_owned())?;
+        }
+        start_server(server, addr, engine, SizeLimitLayer::default())?;
+
+        let client = KvsClient::new(addr, Logger::root(Discard, o!()));
+        let responses = client.pipeline(vec![Request::Scan(ScanRequest {
+            start: Bound::Unbounded,
+            limit: u64::MAX,
+        })])?;
+        match &responses[0] {
+            Response::Pairs(pairs) => assert_eq!(pairs.len() as u64, MAX_SCAN_LIMIT),
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        // the client pages through past the cap
+        let pairs = client.scan(Bound::Unbounded, usize::MAX)?;
+        assert_eq!(pairs.len() as u64, MAX_SCAN_LIMIT + 500);
+        assert_eq!(pairs[1000].0, "key01000");
+        let pairs = client.scan(Bound::Excluded("key00100".to_owned()), 1200)?;
+        assert_eq!(pairs.len(), 1200);
+        assert_eq!(pairs[0].0, "key00101");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn idle_connections_dont_hold_engine_threads() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4020".parse()?;
+    let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+    start_server(Server::Async, addr, engine, SizeLimitLayer::default())?;
+
+    // far more open connections than the 2 blocking threads
+    let idle = (0..1000)
+        .map(|_| {
+            let mut stream = TcpStream::connect(addr)?;
+            write_frame(&mut stream, &Hello::current())?;
+            Ok(stream)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let client = KvsClient::new(addr, Logger::root(Discard, o!()));
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    drop(idle);
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+/// Every path under `dir` with its size.
+fn listing(dir: &Path) -> Result<BTreeMap<PathBuf, u64>> {
+    let mut files = BTreeMap::new();
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        files.insert(entry.path().to_path_buf(), entry.metadata()?.len());
+    }
+    Ok(files)
+}
+
+fn options(shards: usize) -> KvStoreOptions {
+    KvStoreOptions {
+        max_segment_size: 256,
+        shards,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn read_only_store_never_writes() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let before = listing(temp_dir.path())?;
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key7".to_owned())?, Some("value7".to_owned()));
+    assert_eq!(reader.scan(Bound::Unbounded, 100)?.len(), 50);
+    assert!(reader.set("key7".to_owned(), "other".to_owned()).is_err());
+    assert!(reader.remove("key7".to_owned()).is_err());
+    assert!(reader.compact().is_err());
+    assert_eq!(reader.get("key7".to_owned())?, Some("value7".to_owned()));
+    drop(reader);
+
+    assert_eq!(listing(temp_dir.path())?, before);
+
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+
+    Ok(())
+}
+
+#[test]
+fn refresh_follows_the_writer() -> Result<()> {
+    for &shards in &[1, 3] {
+        let temp_dir = TempDir::new()?;
+        let writer = KvStore::open_with_options(temp_dir.path(), options(shards))?;
+        writer.set("first".to_owned(), "1".to_owned())?;
+
+        let reader = KvStore::open_read_only_with_options(temp_dir.path(), options(shards))?;
+        assert_eq!(reader.get("first".to_owned())?, Some("1".to_owned()));
+
+        // appends to the active segment
+        writer.set("second".to_owned(), "2".to_owned())?;
+        writer.remove("first".to_owned())?;
+        assert_eq!(reader.get("second".to_owned())?, None);
+        reader.refresh()?;
+        assert_eq!(reader.get("second".to_owned())?, Some("2".to_owned()));
+        assert_eq!(reader.get("first".to_owned())?, None);
+
+        // rotations and compactions
+        for i in 0..200 {
+            writer.set(format!("key{}", i % 20), format!("value{}", i))?;
+        }
+        reader.refresh()?;
+        assert_eq!(
+            reader.scan(Bound::Unbounded, usize::MAX)?,
+            writer.scan(Bound::Unbounded, usize::MAX)?
+        );
+
+        // reads that hit a compacted-away segment refresh by themselves
+        writer.compact()?;
+        assert_eq!(reader.get("key3".to_owned())?, Some("value183".to_owned()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,379 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::layers::SizeLimitLayer;
+use kvs::{InMemEngine, KvsEngine, RespServer, Result};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+use tokio::runtime::Builder;
+
+/// A reply as the tests see it.
+#[derive(Debug, PartialEq)]
+enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    /// RESP2's `$-1`.
+    Nil,
+    /// RESP3's `_`.
+    Null,
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+fn bulk(value: &str) -> Value {
+    Value::Bulk(value.as_bytes().to_vec())
+}
+
+fn ok() -> Value {
+    Value::Simple("OK".to_owned())
+}
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Result<Client> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    fn send(&mut self, args: &[&str]) -> Result<()> {
+        let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            buf.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+        }
+        self.writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn call(&mut self, args: &[&str]) -> Result<Value> {
+        self.send(args)?;
+        self.read()
+    }
+
+    fn read(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let line = line.trim_end_matches("\r\n");
+        let (kind, rest) = line.split_at(1);
+        Ok(match kind {
+            "+" => Value::Simple(rest.to_owned()),
+            "-" => Value::Error(rest.to_owned()),
+            ":" => Value::Integer(rest.parse()?),
+            "_" => Value::Null,
+            "$" if rest == "-1" => Value::Nil,
+            "$" => {
+                let mut value = vec![0; rest.parse::<usize>()? + 2];
+                self.reader.read_exact(&mut value)?;
+                value.truncate(value.len() - 2);
+                Value::Bulk(value)
+            }
+            "*" => Value::Array(
+                (0..rest.parse::<usize>()?)
+                    .map(|_| self.read())
+                    .collect::<Result<_>>()?,
+            ),
+            "%" => Value::Map(
+                (0..rest.parse::<usize>()?)
+                    .map(|_| Ok((self.read()?, self.read()?)))
+                    .collect::<Result<_>>()?,
+            ),
+            _ => panic!("unexpected reply {:?}", line),
+        })
+    }
+}
+
+fn start_server<E: KvsEngine>(addr: SocketAddr, engine: E, limits: SizeLimitLayer) -> Result<()> {
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(2)
+        .max_blocking_threads(2)
+        .enable_io()
+        .build()?;
+    let server = RespServer::new(addr, engine, Logger::root(Discard, o!())).with_limits(limits);
+    thread::spawn(move || runtime.block_on(server.run()));
+    thread::sleep(Duration::from_millis(200));
+    Ok(())
+}
+
+#[test]
+fn string_commands() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4030".parse()?;
+    let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+    start_server(addr, engine.clone(), SizeLimitLayer::default())?;
+    let mut client = Client::connect(addr)?;
+
+    assert_eq!(client.call(&["PING"])?, Value::Simple("PONG".to_owned()));
+    assert_eq!(client.call(&["set", "key1", "value1"])?, ok());
+    assert_eq!(client.call(&["GET", "key1"])?, bulk("value1"));
+    assert_eq!(client.call(&["GET", "key2"])?, Value::Nil);
+    // the engine behind the server sees the same keys
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert_eq!(client.call(&["MSET", "key2", "value2", "key3", ""])?, ok());
+    assert_eq!(
+        client.call(&["MGET", "key1", "nope", "key3"])?,
+        Value::Array(vec![bulk("value1"), Value::Nil, bulk("")])
+    );
+    assert_eq!(
+        client.call(&["EXISTS", "key1", "key2", "nope", "key1"])?,
+        Value::Integer(3)
+    );
+    assert_eq!(
+        client.call(&["DEL", "key1", "nope", "key2"])?,
+        Value::Integer(2)
+    );
+    assert_eq!(client.call(&["EXISTS", "key1"])?, Value::Integer(0));
+
+    assert_eq!(client.call(&["SET", "key3", "x", "NX"])?, Value::Nil);
+    assert_eq!(client.call(&["SET", "key4", "x", "XX"])?, Value::Nil);
+    assert_eq!(client.call(&["SET", "key4", "x", "NX"])?, ok());
+    assert_eq!(client.call(&["SET", "key4", "y", "GET"])?, bulk("x"));
+    assert_eq!(client.call(&["GET", "key4"])?, bulk("y"));
+
+    // keys don't expire
+    assert_eq!(client.call(&["TTL", "key4"])?, Value::Integer(-1));
+    assert_eq!(client.call(&["TTL", "nope"])?, Value::Integer(-2));
+    assert!(matches!(
+        client.call(&["EXPIRE", "key4", "10"])?,
+        Value::Error(_)
+    ));
+    assert!(matches!(
+        client.call(&["SET", "key4", "z", "EX", "10"])?,
+        Value::Error(_)
+    ));
+    assert_eq!(client.call(&["GET", "key4"])?, bulk("y"));
+
+    assert_eq!(
+        client.call(&["GET"])?,
+        Value::Error("ERR wrong number of arguments for 'get' command".to_owned())
+    );
+    assert_eq!(
+        client.call(&["FLUSHALL"])?,
+        Value::Error("ERR unknown command 'flushall'".to_owned())
+    );
+    match client.call(&["INFO", "clients"])? {
+        Value::Bulk(info) => {
+            assert_eq!(info, b"# Clients\r\nconnected_clients:1\r\n".to_vec())
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn inline_and_pipelined_commands() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4031".parse()?;
+    start_server(
+        addr,
+        InMemEngine::open(temp_dir.path().to_path_buf()),
+        SizeLimitLayer::default(),
+    )?;
+    let mut client = Client::connect(addr)?;
+
+    // what redis-benchmark's PING_INLINE and telnet send
+    client
+        .writer
+        .write_all(b"PING\r\n\r\nSET key value\nGET  key\r\n")?;
+    for i in 0..100 {
+        client.send(&["SET", &format!("key{}", i), &i.to_string()])?;
+        client.send(&["GET", &format!("key{}", i)])?;
+    }
+    assert_eq!(client.read()?, Value::Simple("PONG".to_owned()));
+    assert_eq!(client.read()?, ok());
+    assert_eq!(client.read()?, bulk("value"));
+    for i in 0..100 {
+        assert_eq!(client.read()?, ok());
+        assert_eq!(client.read()?, bulk(&i.to_string()));
+    }
+
+    // a broken request is answered, then the connection is closed
+    client.writer.write_all(b"*1\r\n$x\r\n")?;
+    assert_eq!(
+        client.read()?,
+        Value::Error("ERR Protocol error: invalid bulk length".to_owned())
+    );
+    assert_eq!(client.reader.read(&mut [0; 1])?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn hello_switches_to_resp3() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4032".parse()?;
+    start_server(
+        addr,
+        InMemEngine::open(temp_dir.path().to_path_buf()),
+        SizeLimitLayer::default(),
+    )?;
+    let mut client = Client::connect(addr)?;
+
+    assert_eq!(client.call(&["GET", "nope"])?, Value::Nil);
+    match client.call(&["HELLO", "3"])? {
+        Value::Map(fields) => {
+            assert!(fields.contains(&(bulk("server"), bulk("kvrs"))));
+            assert!(fields.contains(&(bulk("proto"), Value::Integer(3))));
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert_eq!(client.call(&["GET", "nope"])?, Value::Null);
+    assert_eq!(
+        client.call(&["MGET", "nope"])?,
+        Value::Array(vec![Value::Null])
+    );
+
+    assert!(matches!(
+        client.call(&["HELLO", "4"])?,
+        Value::Error(e) if e.starts_with("NOPROTO")
+    ));
+    // and back, with the map flattened
+    match client.call(&["HELLO", "2"])? {
+        Value::Array(fields) => assert_eq!(fields.len(), 14),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert_eq!(client.call(&["GET", "nope"])?, Value::Nil);
+
+    assert_eq!(client.call(&["QUIT"])?, ok());
+    assert_eq!(client.reader.read(&mut [0; 1])?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn scan_visits_every_key() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4033".parse()?;
+    let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+    for i in 0..100 {
+        engine.set(format!("key{}", i), i.to_string())?;
+    }
+    engine.set("other".to_owned(), "value".to_owned())?;
+    start_server(addr, engine, SizeLimitLayer::default())?;
+    let mut client = Client::connect(addr)?;
+
+    let mut scan = |pattern: &str, count: &str| -> Result<BTreeSet<String>> {
+        let mut keys = BTreeSet::new();
+        let mut cursor = "0".to_owned();
+        loop {
+            let reply = client.call(&["SCAN", &cursor, "MATCH", pattern, "COUNT", count])?;
+            match reply {
+                Value::Array(mut reply) if reply.len() == 2 => {
+                    if let Value::Array(found) = reply.pop().unwrap() {
+                        for key in found {
+                            match key {
+                                Value::Bulk(key) => assert!(keys.insert(String::from_utf8(key)?)),
+                                other => panic!("unexpected key {:?}", other),
+                            }
+                        }
+                    }
+                    match reply.pop().unwrap() {
+                        Value::Bulk(next) => cursor = String::from_utf8(next)?,
+                        other => panic!("unexpected cursor {:?}", other),
+                    }
+                }
+                other => panic!("unexpected reply {:?}", other),
+            }
+            if cursor == "0" {
+                return Ok(keys);
+            }
+        }
+    };
+
+    let all = scan("*", "7")?;
+    assert_eq!(all.len(), 101);
+    let expected: BTreeSet<_> = (10..20)
+        .map(|i| format!("key{}", i))
+        .chain(Some("key1".to_owned()))
+        .collect();
+    assert_eq!(scan("key1*", "3")?, expected);
+    assert_eq!(
+        scan("key[2-3]?", "1000")?,
+        (20..40).map(|i| format!("key{}", i)).collect()
+    );
+    assert_eq!(
+        scan("*[^0-9]", "1000")?,
+        vec!["other".to_owned()].into_iter().collect()
+    );
+
+    assert_eq!(
+        client.call(&["SCAN", "12345"])?,
+        Value::Error("ERR invalid cursor".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn oversized_arguments_are_skipped() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4034".parse()?;
+    start_server(
+        addr,
+        InMemEngine::open(temp_dir.path().to_path_buf()),
+        SizeLimitLayer::new(Some(16), Some(64)),
+    )?;
+    let mut client = Client::connect(addr)?;
+
+    // the oversized argument is skipped, and the connection carries on
+    let big = "v".repeat(65);
+    assert_eq!(
+        client.call(&["SET", "key", &big])?,
+        Value::Error("ERR argument of 65 bytes exceeds the limit of 64".to_owned())
+    );
+    assert_eq!(client.call(&["GET", "key"])?, Value::Nil);
+    assert_eq!(client.call(&["SET", "key", &"v".repeat(64)])?, ok());
+
+    Ok(())
+}
+
+#[test]
+fn many_star_patterns_scan_quickly() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr: SocketAddr = "127.0.0.1:4035".parse()?;
+    let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+    for i in 0..10 {
+        engine.set(format!("{}{}", "a".repeat(200), i), "value".to_owned())?;
+    }
+    start_server(addr, engine, SizeLimitLayer::default())?;
+    let mut client = Client::connect(addr)?;
+
+    // backtracking into every star would take years on these keys
+    let started = Instant::now();
+    let reply = client.call(&[
+        "SCAN",
+        "0",
+        "MATCH",
+        "*a*a*a*a*a*a*a*a*a*a*a*a*b",
+        "COUNT",
+        "100",
+    ])?;
+    assert_eq!(
+        reply,
+        Value::Array(vec![bulk("0"), Value::Array(Vec::new())])
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        client.call(&["SCAN", "0", "MATCH", "*a*a*a*7", "COUNT", "100"])?,
+        Value::Array(vec![
+            bulk("0"),
+            Value::Array(vec![Value::Bulk(
+                format!("{}7", "a".repeat(200)).into_bytes()
+            )])
+        ])
+    );
+
+    Ok(())
+}
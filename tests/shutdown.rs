@@ -0,0 +1,201 @@
+use std::net::{SocketAddr, TcpStream};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::common::*;
+use kvs::{
+    AsyncKvsServer, InMemEngine, KvsClient, KvsEngine, KvsServer, Result, SharedQueueThreadPool,
+    ShutdownHandle, ThreadPool,
+};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+use tokio::runtime::Builder;
+
+/// Takes `delay` over every set, and notes when it's flushed.
+#[derive(Clone)]
+struct SlowEngine {
+    inner: InMemEngine,
+    delay: Duration,
+    flushed: Arc<AtomicBool>,
+}
+
+impl SlowEngine {
+    fn new(dir: &TempDir, delay: Duration) -> SlowEngine {
+        SlowEngine {
+            inner: InMemEngine::open(dir.path().to_path_buf()),
+            delay,
+            flushed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether it was flushed since the last time this was asked.
+    fn flushed(&self) -> bool {
+        self.flushed.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl KvsEngine for SlowEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        thread::sleep(self.delay);
+        self.inner.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.inner.remove(key)
+    }
+
+    fn scan(&self, start: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.inner.scan(start, limit)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.flushed.store(true, Ordering::SeqCst);
+        self.inner.flush()
+    }
+}
+
+/// Starts a thread-pool server on `port` and an async one on `port + 100`,
+/// returning for each its address, its shutdown handle, and where it reports
+/// how serving ended once it has let go of its listener.
+fn start_servers(
+    port: u16,
+    engine: SlowEngine,
+    drain_timeout: Duration,
+) -> Result<Vec<(SocketAddr, ShutdownHandle, Receiver<Result<()>>)>> {
+    let logger = Logger::root(Discard, o!());
+    let addrs = [
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        SocketAddr::from(([127, 0, 0, 1], port + 100)),
+    ];
+
+    let pool = SharedQueueThreadPool::new(4)?;
+    let server = KvsServer::new(
+        addrs[0],
+        engine.clone(),
+        String::new(),
+        logger.clone(),
+        pool,
+    )
+    .with_drain_timeout(drain_timeout);
+    let handle = server.shutdown_handle();
+    let (sender, stopped) = mpsc::channel();
+    thread::spawn(move || {
+        let result = server.start();
+        drop(server);
+        sender.send(result).unwrap();
+    });
+    let mut servers = vec![(addrs[0], handle, stopped)];
+
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(2)
+        .max_blocking_threads(2)
+        .enable_io()
+        .build()?;
+    let server = AsyncKvsServer::new(addrs[1], engine, logger).with_drain_timeout(drain_timeout);
+    let handle = server.shutdown_handle();
+    let (sender, stopped) = mpsc::channel();
+    thread::spawn(move || {
+        let result = runtime.block_on(server.run());
+        sender.send(result).unwrap();
+    });
+    servers.push((addrs[1], handle, stopped));
+
+    thread::sleep(Duration::from_millis(200));
+    Ok(servers)
+}
+
+fn client(addr: SocketAddr) -> KvsClient {
+    KvsClient::new(addr, Logger::root(Discard, o!()))
+}
+
+#[test]
+fn shutdown_drains_requests_in_flight() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = SlowEngine::new(&temp_dir, Duration::from_millis(500));
+
+    let servers = start_servers(4080, engine.clone(), Duration::from_secs(30))?;
+    for (addr, handle, stopped) in servers {
+        // an idle connection mustn't hold shutting down up
+        let idle = client(addr);
+        assert_eq!(idle.get("key".to_owned())?, None);
+
+        let busy = thread::spawn(move || {
+            client(addr).pipeline(vec![
+                Request::Set(SetRequest {
+                    key: b"key".to_vec(),
+                    value: b"value".to_vec(),
+                }),
+                Request::Get(GetRequest {
+                    key: b"key".to_vec(),
+                }),
+            ])
+        });
+        thread::sleep(Duration::from_millis(100));
+        let started = Instant::now();
+        handle.shutdown();
+        handle.shutdown();
+        assert!(handle.is_shutdown());
+
+        // both the request being answered and the one queued behind it
+        // get their responses
+        assert_eq!(
+            busy.join().unwrap()?,
+            [Response::Ok, Response::Value(b"value".to_vec())]
+        );
+        stopped.recv_timeout(Duration::from_secs(5))??;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(engine.flushed());
+        engine.remove("key".to_owned())?;
+
+        assert!(TcpStream::connect(addr).is_err());
+        assert!(idle.get("key".to_owned()).is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn shutdown_gives_up_after_the_drain_timeout() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = SlowEngine::new(&temp_dir, Duration::from_secs(3));
+
+    let servers = start_servers(4082, engine.clone(), Duration::from_millis(200))?;
+    for (addr, handle, stopped) in servers {
+        let busy = client(addr);
+        thread::spawn(move || busy.set("key".to_owned(), "value".to_owned()));
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        handle.shutdown();
+        stopped.recv_timeout(Duration::from_secs(2))??;
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(engine.flushed());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn shutdown_before_serving() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = SlowEngine::new(&temp_dir, Duration::from_millis(0));
+    let logger = Logger::root(Discard, o!());
+    let addr = SocketAddr::from(([127, 0, 0, 1], 4183));
+
+    // the async server hasn't bound its address yet
+    let server = AsyncKvsServer::new(addr, engine.clone(), logger);
+    server.shutdown_handle().shutdown();
+    let runtime = Builder::new_current_thread().enable_io().build()?;
+    runtime.block_on(server.run())?;
+    assert!(engine.flushed());
+
+    Ok(())
+}
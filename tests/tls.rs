@@ -0,0 +1,189 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use kvs::{
+    AsyncKvsServer, ClientTls, InMemEngine, KvsClient, KvsEngine, KvsServer, Result, ServerTls,
+    SharedQueueThreadPool, ThreadPool,
+};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+use tokio::runtime::Builder;
+
+/// A throwaway CA whose certificates and keys are written to `dir`.
+struct Ca {
+    dir: PathBuf,
+    name: String,
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new(dir: &Path, name: &str) -> Result<Ca> {
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(Vec::<String>::new())?;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key)?;
+        fs::write(dir.join(format!("{}-ca.pem", name)), cert.pem())?;
+        Ok(Ca {
+            dir: dir.to_path_buf(),
+            name: name.to_owned(),
+            cert,
+            key,
+        })
+    }
+
+    fn pem(&self) -> PathBuf {
+        self.dir.join(format!("{}-ca.pem", self.name))
+    }
+
+    /// Issues a certificate for localhost, returning where its PEM
+    /// certificate and key went.
+    fn issue(&self, name: &str) -> Result<(PathBuf, PathBuf)> {
+        let key = KeyPair::generate()?;
+        let params = CertificateParams::new(vec!["localhost".to_owned(), "127.0.0.1".to_owned()])?;
+        let cert = params.signed_by(&key, &self.cert, &self.key)?;
+        let cert_path = self.dir.join(format!("{}.pem", name));
+        let key_path = self.dir.join(format!("{}.key", name));
+        fs::write(&cert_path, cert.pem())?;
+        fs::write(&key_path, key.serialize_pem())?;
+        Ok((cert_path, key_path))
+    }
+}
+
+/// Starts a thread-pool server on `port` and an async one on `port + 100`,
+/// both serving `tls`, and returns their addresses.
+fn start_servers(port: u16, tls: ServerTls) -> Result<Vec<SocketAddr>> {
+    let temp_dir = TempDir::new()?;
+    let engine = InMemEngine::open(temp_dir.path().to_path_buf());
+    let logger = Logger::root(Discard, o!());
+    let addrs = vec![
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        SocketAddr::from(([127, 0, 0, 1], port + 100)),
+    ];
+
+    let pool = SharedQueueThreadPool::new(2)?;
+    let server = KvsServer::new(
+        addrs[0],
+        engine.clone(),
+        String::new(),
+        logger.clone(),
+        pool,
+    )
+    .with_tls(tls.clone());
+    thread::spawn(move || server.start());
+
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(2)
+        .max_blocking_threads(2)
+        .enable_io()
+        .build()?;
+    let server = AsyncKvsServer::new(addrs[1], engine, logger).with_tls(tls);
+    thread::spawn(move || runtime.block_on(server.run()));
+
+    thread::sleep(Duration::from_millis(200));
+    Ok(addrs)
+}
+
+fn client(addr: SocketAddr, tls: ClientTls) -> KvsClient {
+    KvsClient::new(addr, Logger::root(Discard, o!())).with_tls(tls)
+}
+
+#[test]
+fn client_server_over_tls() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let ca = Ca::new(temp_dir.path(), "root")?;
+    let other_ca = Ca::new(temp_dir.path(), "other")?;
+    let (cert, key) = ca.issue("server")?;
+    let addrs = start_servers(4060, ServerTls::from_pem_files(&cert, &key, None)?)?;
+
+    for addr in addrs {
+        let tls = ClientTls::from_pem_files(&ca.pem(), "localhost", None)?;
+        let kvs = client(addr, tls);
+        kvs.set("key1".to_owned(), "value1".to_owned())?;
+        kvs.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(kvs.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(kvs.scan(Bound::Unbounded, 10)?.len(), 2);
+        kvs.remove("key2".to_owned())?;
+        assert_eq!(kvs.get("key2".to_owned())?, None);
+
+        // the certificate names the IP too
+        let tls = ClientTls::from_pem_files(&ca.pem(), "127.0.0.1", None)?;
+        assert_eq!(
+            client(addr, tls).get("key1".to_owned())?,
+            Some("value1".to_owned())
+        );
+
+        // a plaintext client gets nowhere
+        let plain = KvsClient::new(addr, Logger::root(Discard, o!()));
+        assert!(plain.get("key1".to_owned()).is_err());
+
+        // nor does one that doesn't trust the server's CA
+        let tls = ClientTls::from_pem_files(&other_ca.pem(), "localhost", None)?;
+        assert!(client(addr, tls).get("key1".to_owned()).is_err());
+
+        // nor one expecting another name
+        let tls = ClientTls::from_pem_files(&ca.pem(), "kvs.example.com", None)?;
+        assert!(client(addr, tls).get("key1".to_owned()).is_err());
+
+        // the servers carry on after failed handshakes
+        assert_eq!(kvs.get("key1".to_owned())?, Some("value1".to_owned()));
+        kvs.remove("key1".to_owned())?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn client_certificates() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let ca = Ca::new(temp_dir.path(), "root")?;
+    let other_ca = Ca::new(temp_dir.path(), "other")?;
+    let (cert, key) = ca.issue("server")?;
+    let (client_cert, client_key) = ca.issue("client")?;
+    let (stranger_cert, stranger_key) = other_ca.issue("stranger")?;
+    let addrs = start_servers(
+        4061,
+        ServerTls::from_pem_files(&cert, &key, Some(&ca.pem()))?,
+    )?;
+
+    for addr in addrs {
+        let tls = ClientTls::from_pem_files(&ca.pem(), "localhost", None)?;
+        assert!(client(addr, tls).get("key".to_owned()).is_err());
+
+        let identity = Some((stranger_cert.as_path(), stranger_key.as_path()));
+        let tls = ClientTls::from_pem_files(&ca.pem(), "localhost", identity)?;
+        assert!(client(addr, tls).get("key".to_owned()).is_err());
+
+        let identity = Some((client_cert.as_path(), client_key.as_path()));
+        let tls = ClientTls::from_pem_files(&ca.pem(), "localhost", identity)?;
+        let kvs = client(addr, tls);
+        kvs.set("key".to_owned(), "value".to_owned())?;
+        assert_eq!(kvs.get("key".to_owned())?, Some("value".to_owned()));
+        kvs.remove("key".to_owned())?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn bad_pem_files_are_errors() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let ca = Ca::new(temp_dir.path(), "root")?;
+    let (cert, key) = ca.issue("server")?;
+    let empty = temp_dir.path().join("empty.pem");
+    fs::write(&empty, "")?;
+    let missing = temp_dir.path().join("missing.pem");
+
+    assert!(ServerTls::from_pem_files(&empty, &key, None).is_err());
+    assert!(ServerTls::from_pem_files(&cert, &empty, None).is_err());
+    assert!(ServerTls::from_pem_files(&cert, &key, Some(&missing)).is_err());
+    assert!(ClientTls::from_pem_files(&empty, "localhost", None).is_err());
+    assert!(ClientTls::from_pem_files(&ca.pem(), "not a name!", None).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+#![cfg(all(target_os = "linux", feature = "io-uring"))]
+
+use std::ops::Bound;
+use std::sync::Arc;
+use std::thread;
+
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, UringFs};
+use tempfile::TempDir;
+
+fn open(temp_dir: &TempDir, fs: &UringFs) -> Result<KvStore> {
+    let options = KvStoreOptions {
+        fs: Arc::new(fs.clone()),
+        max_segment_size: 256,
+        ..Default::default()
+    };
+    KvStore::open_with_options(temp_dir.path(), options)
+}
+
+#[test]
+fn store_on_io_uring() -> Result<()> {
+    let fs = UringFs::new(32)?;
+    let temp_dir = TempDir::new()?;
+    let store = open(&temp_dir, &fs)?;
+
+    // enough writes to rotate and compact segments
+    for iter in 0..20 {
+        for i in 0..20 {
+            store.set(format!("key{}", i), format!("value{}_{}", i, iter))?;
+        }
+    }
+    store.remove("key7".to_owned())?;
+    drop(store);
+
+    let store = open(&temp_dir, &fs)?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3_19".to_owned()));
+    assert_eq!(store.get("key7".to_owned())?, None);
+    assert_eq!(store.scan(Bound::Unbounded, usize::MAX)?.len(), 19);
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_writers_share_the_ring() -> Result<()> {
+    let fs = UringFs::new(8)?;
+    let temp_dir = TempDir::new()?;
+    let store = open(&temp_dir, &fs)?;
+
+    let handles: Vec<_> = (0..16)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    store.set(format!("key{}_{}", t, i), i.to_string()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    drop(store);
+
+    let store = open(&temp_dir, &fs)?;
+    for t in 0..16 {
+        for i in 0..50 {
+            assert_eq!(store.get(format!("key{}_{}", t, i))?, Some(i.to_string()));
+        }
+    }
+
+    Ok(())
+}